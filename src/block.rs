@@ -0,0 +1,238 @@
+//! Blocks are the unit in which SSTables are read, compressed and cached.
+//!
+//! Every block is compressed on its own with the codec configured for the
+//! level of its table, and is written followed by a trailer:
+//!
+//! +---...---+---------------+----------+
+//! | Data    | Codec Id (1B) | CRC (4B) |
+//! +---...---+---------------+----------+
+//! Data = Contents of the block, compressed by the codec
+//! Codec Id = Identifier of the codec that compressed the Data, 0 if it is
+//!            stored as is
+//! CRC = CRC-32C of the Data and Codec Id
+//!
+//! The checksum is verified whenever a block is read, unless the read opts
+//! out of it, so corrupted data is never served.
+//!
+//! Since every block records its codec, changing the compression options only
+//! affects the tables written afterwards, and tables written with different
+//! settings stay readable.
+//!
+//! A table written with a compression dictionary stores it in a meta block,
+//! which is not compressed. All the data blocks of the table are compressed
+//! with the dictionary, so it must be read before them.
+
+#![allow(dead_code)]
+
+use std::io;
+
+use crate::{
+    checksum,
+    compression::{Codec, CompressionOptions, CompressionType},
+};
+
+/// Size of the trailer at the end of every block
+pub const BLOCK_TRAILER_SIZE: usize = 1 + 4;
+
+/// Compresses the contents of a block with `codec`, and the dictionary of
+/// its table if it has one, and appends its trailer
+///
+/// Data that doesn't get smaller is stored as is, so reading it doesn't pay
+/// for a decompression that saves nothing.
+pub fn encode_block(codec: &dyn Codec, data: &[u8], dict: Option<&[u8]>) -> Vec<u8> {
+    let compressed = match dict {
+        Some(dict) => codec.compress_with_dict(data, dict),
+        None => codec.compress(data),
+    };
+    let compressed = Some(compressed).filter(|compressed| compressed.len() < data.len());
+
+    let mut block = Vec::with_capacity(data.len() + BLOCK_TRAILER_SIZE);
+    match compressed {
+        Some(compressed) => {
+            block.extend_from_slice(&compressed);
+            block.push(codec.id());
+        }
+        None => {
+            block.extend_from_slice(data);
+            block.push(CompressionType::None as u8);
+        }
+    }
+    let crc = checksum::crc32c(&block);
+    block.extend_from_slice(&crc.to_le_bytes());
+    block
+}
+
+/// Checks that a block read from a table is not corrupted
+pub fn verify_block(block: &[u8]) -> io::Result<()> {
+    let Some(crc_offset) = block.len().checked_sub(4).filter(|&offset| offset > 0) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "block is too short",
+        ));
+    };
+    let crc = u32::from_le_bytes(block[crc_offset..].try_into().unwrap());
+    if checksum::crc32c(&block[..crc_offset]) != crc {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "block checksum mismatch",
+        ));
+    }
+    Ok(())
+}
+
+/// Decompresses a block read from a table, with the codec recorded in its
+/// trailer and the dictionary of the table if it has one
+///
+/// The checksum of the block is verified first if `verify_checksum` is set.
+/// Fails if the codec is neither built-in nor in `options`, or if the block
+/// is corrupted.
+pub fn decode_block(
+    block: &[u8],
+    options: &CompressionOptions,
+    dict: Option<&[u8]>,
+    verify_checksum: bool,
+) -> io::Result<Vec<u8>> {
+    if verify_checksum {
+        verify_block(block)?;
+    }
+    let Some((&codec_id, data)) = block
+        .len()
+        .checked_sub(4)
+        .and_then(|crc_offset| block[..crc_offset].split_last())
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "block is too short",
+        ));
+    };
+    let codec = options.codec(codec_id).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("block compressed with unknown codec {}", codec_id),
+        )
+    })?;
+    let decompressed = match dict {
+        Some(dict) => codec.decompress_with_dict(data, dict),
+        None => codec.decompress(data),
+    };
+    decompressed.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("corrupted block compressed with {}", codec.name()),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{CodecId, FIRST_CUSTOM_CODEC_ID};
+    use std::sync::Arc;
+
+    /// Stores runs of repeated bytes as (length, byte) pairs
+    struct RunLengthCodec;
+
+    impl Codec for RunLengthCodec {
+        fn id(&self) -> CodecId {
+            FIRST_CUSTOM_CODEC_ID
+        }
+
+        fn name(&self) -> &str {
+            "rle"
+        }
+
+        fn compress(&self, data: &[u8]) -> Vec<u8> {
+            let mut compressed = Vec::new();
+            for chunk in data.chunk_by(|a, b| a == b) {
+                for run in chunk.chunks(u8::MAX as usize) {
+                    compressed.extend_from_slice(&[run.len() as u8, run[0]]);
+                }
+            }
+            compressed
+        }
+
+        fn decompress(&self, data: &[u8]) -> Option<Vec<u8>> {
+            if !data.len().is_multiple_of(2) {
+                return None;
+            }
+            let runs = data.chunks(2);
+            Some(runs.flat_map(|run| vec![run[1]; run[0] as usize]).collect())
+        }
+    }
+
+    #[test]
+    fn test_block_codec_per_level() {
+        let data = br#"{"fruit": "Lime", "smoothie": true}"#.repeat(20);
+        let options = CompressionOptions {
+            bottommost: Some(Arc::new(RunLengthCodec)),
+            ..CompressionOptions::default()
+        };
+
+        let l0_block = encode_block(options.codec_for_level(0, false), &data, None);
+        assert_eq!(l0_block.len(), data.len() + BLOCK_TRAILER_SIZE);
+        let l2_block = encode_block(options.codec_for_level(2, false), &data, None);
+        assert!(l2_block.len() < data.len());
+        let zeros = vec![0; 1000];
+        let bottommost_block = encode_block(options.codec_for_level(2, true), &zeros, None);
+        assert_eq!(bottommost_block.len(), 8 + BLOCK_TRAILER_SIZE);
+
+        // Blocks are readable whatever the options they were written with
+        let options = CompressionOptions {
+            codecs: vec![Arc::new(RunLengthCodec)],
+            ..CompressionOptions::default()
+        };
+        assert_eq!(decode_block(&l0_block, &options, None, true).unwrap(), data);
+        assert_eq!(decode_block(&l2_block, &options, None, true).unwrap(), data);
+        assert_eq!(
+            decode_block(&bottommost_block, &options, None, true).unwrap(),
+            zeros
+        );
+
+        let err = decode_block(
+            &bottommost_block,
+            &CompressionOptions::default(),
+            None,
+            true,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_block_incompressible() {
+        // Runs of single bytes double in size with run-length encoding
+        let data = b"Lime Smoothie".to_vec();
+        let block = encode_block(&RunLengthCodec, &data, None);
+        assert_eq!(
+            block[block.len() - BLOCK_TRAILER_SIZE],
+            CompressionType::None as u8
+        );
+        assert_eq!(
+            decode_block(&block, &CompressionOptions::default(), None, true).unwrap(),
+            data
+        );
+
+        assert!(decode_block(&[], &CompressionOptions::default(), None, true).is_err());
+    }
+
+    #[test]
+    fn test_block_checksum() {
+        let data = br#"{"fruit": "Lime", "smoothie": true}"#.repeat(20);
+        let options = CompressionOptions::default();
+        let mut block = encode_block(&CompressionType::Lz4, &data, None);
+        verify_block(&block).unwrap();
+
+        // A flipped bit is caught by the checksum, unless the read skips it
+        block[10] ^= 0x10;
+        let err = verify_block(&block).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(decode_block(&block, &options, None, true).is_err());
+        assert!(decode_block(&block, &options, None, false).is_ok_and(|decoded| decoded != data));
+
+        // Corrupted data that is not checksummed may still fail to decompress
+        let mut block = encode_block(&CompressionType::Lz4, &data, None);
+        let len = block.len();
+        block.remove(len - BLOCK_TRAILER_SIZE - 1);
+        assert!(decode_block(&block, &options, None, false).is_err());
+    }
+}
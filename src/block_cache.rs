@@ -0,0 +1,562 @@
+//! Cache of SSTable blocks kept in memory, so reads of hot blocks don't hit
+//! the disk.
+//!
+//! Blocks are cached decompressed, whether they hold data, an index or a
+//! filter, and the cache evicts blocks once their size goes over its capacity,
+//! following one of two policies:
+//!
+//! - LRU evicts the least recently used blocks. A single large scan reads many
+//!   blocks only once, and can evict the whole working set.
+//! - CLOCK keeps the blocks in a ring with a reference bit set on every read.
+//!   The hand evicting blocks gives referenced ones a second chance, so blocks
+//!   read only once by a scan are evicted before the ones read again.
+//!
+//! Scans can also skip inserting the blocks they read altogether.
+//!
+//! The cache is split in shards, each with its own lock and a part of the
+//! capacity, so concurrent readers rarely contend. It can be shared by several
+//! databases through an `Arc`, making the capacity a limit on all of them
+//! together. Every database gets its own id from the cache, which is part of
+//! the key of its blocks, since file numbers are only unique within a
+//! database.
+//!
+//! The index and filter blocks of L0 tables are read by almost every lookup,
+//! so they can be pinned: pinned blocks count towards the usage of the cache
+//! but are never evicted, until their table is erased from the cache.
+
+#![allow(dead_code)]
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, VecDeque},
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+/// Default capacity of the cache (8 MiB)
+pub const DEFAULT_CACHE_CAPACITY: usize = 8 * 1024 * 1024;
+
+/// Default number of bits of the hash of a key used to pick its shard
+pub const DEFAULT_NUM_SHARD_BITS: u32 = 4;
+
+/// Identifies a block: the table holding it and its offset in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    /// Id of the database the table belongs to, given by
+    /// [`BlockCache::new_id`]
+    pub cache_id: u64,
+    pub file_number: u64,
+    pub offset: u64,
+}
+
+/// What a cached block holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    Data,
+    Index,
+    Filter,
+}
+
+/// How a cache picks the blocks to evict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Least recently used first
+    #[default]
+    Lru,
+    /// Second chance for the blocks read since the hand last passed them,
+    /// which resists scans
+    Clock,
+}
+
+/// Options of a [`BlockCache`]
+#[derive(Debug, Clone)]
+pub struct BlockCacheOptions {
+    /// Total bytes of blocks the cache holds before evicting
+    pub capacity: usize,
+    /// How blocks are picked for eviction
+    pub policy: EvictionPolicy,
+    /// The cache is split in `2^num_shard_bits` shards
+    pub num_shard_bits: u32,
+    /// Whether to pin the index and filter blocks of L0 tables
+    pub pin_l0_index_and_filter_blocks: bool,
+}
+
+impl Default for BlockCacheOptions {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CACHE_CAPACITY,
+            policy: EvictionPolicy::default(),
+            num_shard_bits: DEFAULT_NUM_SHARD_BITS,
+            pin_l0_index_and_filter_blocks: false,
+        }
+    }
+}
+
+/// Counters of the use of a cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Lookups that found the block
+    pub hits: u64,
+    /// Lookups that didn't find the block
+    pub misses: u64,
+    /// Bytes of all the cached blocks
+    pub usage: usize,
+    /// Bytes of the pinned blocks
+    pub pinned_usage: usize,
+}
+
+struct CacheEntry {
+    block: Arc<[u8]>,
+    kind: BlockKind,
+    pinned: bool,
+    /// With LRU, last time the entry was used, its position in the LRU list
+    tick: u64,
+    /// Whether the entry was read since the CLOCK hand last passed it
+    referenced: bool,
+}
+
+/// Order in which the entries that are not pinned are evicted
+enum EvictionOrder {
+    /// Keys by last use, least recently used first
+    Lru(BTreeMap<u64, CacheKey>),
+    /// Ring of keys in the order the hand visits them
+    Clock(VecDeque<CacheKey>),
+}
+
+/// A part of the cache with its own eviction order
+struct CacheShard {
+    capacity: usize,
+    entries: HashMap<CacheKey, CacheEntry>,
+    order: EvictionOrder,
+    next_tick: u64,
+    usage: usize,
+    pinned_usage: usize,
+}
+
+impl CacheShard {
+    fn new(capacity: usize, policy: EvictionPolicy) -> Self {
+        let order = match policy {
+            EvictionPolicy::Lru => EvictionOrder::Lru(BTreeMap::new()),
+            EvictionPolicy::Clock => EvictionOrder::Clock(VecDeque::new()),
+        };
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order,
+            next_tick: 0,
+            usage: 0,
+            pinned_usage: 0,
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<Arc<[u8]>> {
+        let entry = self.entries.get_mut(key)?;
+        match &mut self.order {
+            EvictionOrder::Lru(lru) if !entry.pinned => {
+                lru.remove(&entry.tick);
+                lru.insert(self.next_tick, *key);
+                entry.tick = self.next_tick;
+                self.next_tick += 1;
+            }
+            EvictionOrder::Lru(_) => {}
+            EvictionOrder::Clock(_) => entry.referenced = true,
+        }
+        Some(entry.block.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, block: Arc<[u8]>, kind: BlockKind, pinned: bool) {
+        self.remove(&key);
+
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.usage += block.len();
+        if pinned {
+            self.pinned_usage += block.len();
+        } else {
+            match &mut self.order {
+                EvictionOrder::Lru(lru) => {
+                    lru.insert(tick, key);
+                }
+                EvictionOrder::Clock(ring) => ring.push_back(key),
+            }
+        }
+        let entry = CacheEntry {
+            block,
+            kind,
+            pinned,
+            tick,
+            referenced: false,
+        };
+        self.entries.insert(key, entry);
+
+        while self.usage > self.capacity {
+            let Some(key) = self.next_victim() else {
+                break;
+            };
+            let entry = self.entries.remove(&key).unwrap();
+            self.usage -= entry.block.len();
+        }
+    }
+
+    /// Picks the next entry to evict, removing it from the eviction order
+    fn next_victim(&mut self) -> Option<CacheKey> {
+        match &mut self.order {
+            EvictionOrder::Lru(lru) => lru.pop_first().map(|(_, key)| key),
+            EvictionOrder::Clock(ring) => loop {
+                let key = ring.pop_front()?;
+                let entry = self
+                    .entries
+                    .get_mut(&key)
+                    .expect("ring slot of a removed entry");
+                if !entry.referenced {
+                    return Some(key);
+                }
+                entry.referenced = false;
+                ring.push_back(key);
+            },
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        let Some(entry) = self.entries.remove(key) else {
+            return;
+        };
+        self.usage -= entry.block.len();
+        if entry.pinned {
+            self.pinned_usage -= entry.block.len();
+            return;
+        }
+        match &mut self.order {
+            EvictionOrder::Lru(lru) => {
+                lru.remove(&entry.tick);
+            }
+            EvictionOrder::Clock(ring) => ring.retain(|slot| slot != key),
+        }
+    }
+}
+
+/// A sharded cache of decompressed blocks
+pub struct BlockCache {
+    shards: Vec<Mutex<CacheShard>>,
+    capacity: usize,
+    policy: EvictionPolicy,
+    pin_l0_index_and_filter_blocks: bool,
+    next_id: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.capacity)
+            .field("policy", &self.policy)
+            .field("num_shards", &self.shards.len())
+            .field(
+                "pin_l0_index_and_filter_blocks",
+                &self.pin_l0_index_and_filter_blocks,
+            )
+            .finish()
+    }
+}
+
+impl BlockCache {
+    pub fn new(options: BlockCacheOptions) -> Self {
+        let num_shards = 1 << options.num_shard_bits;
+        let shards = (0..num_shards)
+            .map(|_| {
+                let capacity = options.capacity.div_ceil(num_shards);
+                Mutex::new(CacheShard::new(capacity, options.policy))
+            })
+            .collect();
+
+        Self {
+            shards,
+            capacity: options.capacity,
+            policy: options.policy,
+            pin_l0_index_and_filter_blocks: options.pin_l0_index_and_filter_blocks,
+            next_id: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Gives a new id to a database using the cache, to tell its blocks apart
+    /// from the ones of the other databases sharing it
+    pub fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Gets a cached block, marking it as the most recently used
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<[u8]>> {
+        let block = self.shard(key).get(key);
+        let counter = match block {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        block
+    }
+
+    /// Caches a block read from a table of `level`, replacing the block
+    /// cached with the same key if any
+    ///
+    /// Index and filter blocks of L0 tables are pinned if the cache is
+    /// configured to. Inserting may evict the least recently used blocks to
+    /// make room for the new one.
+    pub fn insert(&self, key: CacheKey, block: Arc<[u8]>, kind: BlockKind, level: usize) {
+        let pinned = self.pin_l0_index_and_filter_blocks && level == 0 && kind != BlockKind::Data;
+        self.shard(&key).insert(key, block, kind, pinned);
+    }
+
+    /// Gets a cached block, loading it with `load` on a miss
+    ///
+    /// The loaded block is only inserted in the cache if `fill_cache` is set,
+    /// so bulk scans can read blocks without evicting the working set.
+    pub fn get_or_load(
+        &self,
+        key: CacheKey,
+        kind: BlockKind,
+        level: usize,
+        fill_cache: bool,
+        load: impl FnOnce() -> io::Result<Arc<[u8]>>,
+    ) -> io::Result<Arc<[u8]>> {
+        if let Some(block) = self.get(&key) {
+            return Ok(block);
+        }
+
+        let block = load()?;
+        if fill_cache {
+            self.insert(key, block.clone(), kind, level);
+        }
+        Ok(block)
+    }
+
+    /// Removes a block from the cache, even if it is pinned
+    pub fn erase(&self, key: &CacheKey) {
+        self.shard(key).remove(key);
+    }
+
+    /// Removes all the blocks of a table from the cache, even the pinned ones,
+    /// once the table is deleted
+    pub fn erase_file(&self, cache_id: u64, file_number: u64) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().expect("block cache lock poisoned");
+            let keys: Vec<CacheKey> = shard
+                .entries
+                .keys()
+                .filter(|key| key.cache_id == cache_id && key.file_number == file_number)
+                .copied()
+                .collect();
+            for key in keys {
+                shard.remove(&key);
+            }
+        }
+    }
+
+    /// Counters of the use of the cache since it was created
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        };
+        for shard in &self.shards {
+            let shard = shard.lock().expect("block cache lock poisoned");
+            stats.usage += shard.usage;
+            stats.pinned_usage += shard.pinned_usage;
+        }
+        stats
+    }
+
+    fn shard(&self, key: &CacheKey) -> MutexGuard<'_, CacheShard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let idx = hasher.finish() as usize % self.shards.len();
+        self.shards[idx].lock().expect("block cache lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn key(offset: u64) -> CacheKey {
+        CacheKey {
+            cache_id: 0,
+            file_number: 1,
+            offset,
+        }
+    }
+
+    fn block(len: usize) -> Arc<[u8]> {
+        vec![0; len].into()
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = BlockCache::new(BlockCacheOptions {
+            capacity: 300,
+            num_shard_bits: 0,
+            ..BlockCacheOptions::default()
+        });
+        cache.insert(key(0), block(100), BlockKind::Data, 1);
+        cache.insert(key(1), block(100), BlockKind::Data, 1);
+        cache.insert(key(2), block(100), BlockKind::Data, 1);
+
+        // Reading the first block makes the second one the least recently used
+        assert!(cache.get(&key(0)).is_some());
+        cache.insert(key(3), block(100), BlockKind::Data, 1);
+        assert!(cache.get(&key(1)).is_none());
+        assert!(cache.get(&key(0)).is_some());
+        assert!(cache.get(&key(2)).is_some());
+
+        // Replacing a block updates the usage
+        cache.insert(key(2), block(50), BlockKind::Data, 1);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 1,
+                usage: 250,
+                pinned_usage: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_clock_resists_scans() {
+        let options = BlockCacheOptions {
+            capacity: 400,
+            num_shard_bits: 0,
+            ..BlockCacheOptions::default()
+        };
+        for (policy, kept) in [(EvictionPolicy::Lru, false), (EvictionPolicy::Clock, true)] {
+            let cache = BlockCache::new(BlockCacheOptions {
+                policy,
+                ..options.clone()
+            });
+            cache.insert(key(0), block(100), BlockKind::Data, 1);
+            cache.insert(key(1), block(100), BlockKind::Data, 1);
+            assert!(cache.get(&key(0)).is_some());
+            assert!(cache.get(&key(1)).is_some());
+
+            // A scan reading every block once, while the working set keeps
+            // being read
+            for offset in 10..20 {
+                cache.insert(key(offset), block(100), BlockKind::Data, 1);
+                if offset % 3 == 0 {
+                    cache.get(&key(0));
+                    cache.get(&key(1));
+                }
+            }
+            assert_eq!(cache.get(&key(0)).is_some(), kept);
+            assert_eq!(cache.stats().usage, 400);
+        }
+    }
+
+    #[test]
+    fn test_clock_remove() {
+        let cache = BlockCache::new(BlockCacheOptions {
+            capacity: 300,
+            num_shard_bits: 0,
+            policy: EvictionPolicy::Clock,
+            ..BlockCacheOptions::default()
+        });
+        let ring_len = || match &cache.shards[0].lock().unwrap().order {
+            EvictionOrder::Clock(ring) => ring.len(),
+            EvictionOrder::Lru(_) => unreachable!(),
+        };
+
+        // Removed and replaced blocks leave no slot behind in the ring
+        for _ in 0..10 {
+            cache.insert(key(0), block(100), BlockKind::Data, 1);
+            cache.insert(key(1), block(100), BlockKind::Data, 1);
+            cache.erase(&key(1));
+        }
+        assert_eq!(ring_len(), 1);
+
+        cache.insert(key(1), block(100), BlockKind::Data, 1);
+        cache.insert(key(2), block(100), BlockKind::Data, 1);
+        assert!(cache.get(&key(0)).is_some());
+        cache.insert(key(3), block(100), BlockKind::Data, 1);
+        assert!(cache.get(&key(1)).is_none());
+        assert!(cache.get(&key(0)).is_some());
+        assert_eq!(ring_len(), 3);
+    }
+
+    #[test]
+    fn test_get_or_load() {
+        let cache = BlockCache::new(BlockCacheOptions::default());
+        let load = || Ok(block(100));
+
+        cache
+            .get_or_load(key(0), BlockKind::Data, 1, false, load)
+            .unwrap();
+        assert!(cache.get(&key(0)).is_none());
+        cache
+            .get_or_load(key(0), BlockKind::Data, 1, true, load)
+            .unwrap();
+        cache
+            .get_or_load(key(0), BlockKind::Data, 1, true, || unreachable!())
+            .unwrap();
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().misses, 3);
+    }
+
+    #[test]
+    fn test_pinned_blocks() {
+        let cache = BlockCache::new(BlockCacheOptions {
+            capacity: 200,
+            num_shard_bits: 0,
+            pin_l0_index_and_filter_blocks: true,
+            ..BlockCacheOptions::default()
+        });
+        cache.insert(key(0), block(100), BlockKind::Index, 0);
+        cache.insert(key(1), block(100), BlockKind::Filter, 0);
+        cache.insert(key(2), block(100), BlockKind::Index, 1);
+        cache.insert(key(3), block(100), BlockKind::Data, 0);
+
+        // Only the pinned blocks are left
+        assert!(cache.get(&key(0)).is_some());
+        assert!(cache.get(&key(1)).is_some());
+        assert!(cache.get(&key(2)).is_none());
+        assert!(cache.get(&key(3)).is_none());
+        assert_eq!(cache.stats().pinned_usage, 200);
+
+        cache.erase_file(1, 1);
+        assert_eq!(cache.stats().usage, 200);
+        cache.erase_file(0, 1);
+        assert_eq!(cache.stats().usage, 0);
+    }
+
+    #[test]
+    fn test_shared_cache() {
+        let cache = Arc::new(BlockCache::new(BlockCacheOptions::default()));
+        thread::scope(|s| {
+            for file_number in 0..4 {
+                let cache = cache.clone();
+                s.spawn(move || {
+                    for offset in 0..100 {
+                        let key = CacheKey {
+                            cache_id: 0,
+                            file_number,
+                            offset,
+                        };
+                        cache.insert(key, block(10), BlockKind::Data, 1);
+                        assert!(cache.get(&key).is_some());
+                    }
+                });
+            }
+        });
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 400);
+        assert_eq!(stats.usage, 4000);
+    }
+}
@@ -0,0 +1,71 @@
+//! Column families split the database into independent keyspaces.
+//!
+//! Every column family has its own MemTable and options, but all of them share
+//! the WAL of the database, so a write batch touching several families is
+//! still applied atomically. The families that exist are recorded in the
+//! manifest, and the `default` family always exists.
+
+#![allow(dead_code)]
+
+use std::{fmt, sync::Arc};
+
+use crate::{compaction_filter::CompactionFilter, merge_operator::MergeOperator};
+
+/// Identifier of a column family, which is never reused once dropped
+pub type ColumnFamilyId = u32;
+
+/// Identifier of the column family that always exists
+pub const DEFAULT_COLUMN_FAMILY: ColumnFamilyId = 0;
+
+/// Name of the column family that always exists
+pub const DEFAULT_COLUMN_FAMILY_NAME: &str = "default";
+
+/// Options of a column family
+#[derive(Clone, Default)]
+pub struct ColumnFamilyOptions {
+    /// Operator combining the operands merged into the keys of the family.
+    /// Merging fails if none is set
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Filter deciding what happens to the values of the family when they
+    /// are compacted. Values are kept as they are without one
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+}
+
+impl fmt::Debug for ColumnFamilyOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ColumnFamilyOptions")
+            .field(
+                "merge_operator",
+                &self.merge_operator.as_ref().map(|op| op.name()),
+            )
+            .field(
+                "compaction_filter",
+                &self.compaction_filter.as_ref().map(|filter| filter.name()),
+            )
+            .finish()
+    }
+}
+
+/// Handle to a column family of a database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnFamily {
+    id: ColumnFamilyId,
+    name: String,
+}
+
+impl ColumnFamily {
+    pub(crate) fn new(id: ColumnFamilyId, name: &str) -> Self {
+        Self {
+            id,
+            name: name.to_owned(),
+        }
+    }
+
+    pub fn id(&self) -> ColumnFamilyId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
@@ -0,0 +1,522 @@
+//! Compaction merges sorted runs of entries into a new one, keeping only the
+//! data that is still visible.
+//!
+//! The database compacts the tables of a column family into a table of the
+//! bottommost level, compressed with the codec configured for it. This module
+//! holds the logic compaction applies to the entries it merges, which decides
+//! what gets written to the output for every key:
+//!
+//! - Only the newest version of every key is kept.
+//! - Merge operands are collapsed: combined with the value below them into a
+//!   new value, or with each other when the value is not part of the
+//!   compaction.
+//! - Versions hidden by a newer range tombstone are dropped, and the range
+//!   tombstone acts as a Tombstone for the operands on top of them.
+//! - Values that expired by the time of the compaction are turned into
+//!   Tombstones, so they keep hiding older versions.
+//! - The compaction filter, if any, decides whether to keep, remove or
+//!   change every value left.
+//! - Tombstones and range tombstones are dropped when compacting into the
+//!   bottommost level, since there is no older data left for them to hide.
+//!
+//! When dictionaries are enabled, the values of the output are also sampled to
+//! train the compression dictionary of the table they are written to.
+
+#![allow(dead_code)]
+
+use crate::{
+    compaction_filter::{CompactionFilter, CompactionFilterContext, FilterDecision},
+    comparator::{BytewiseComparator, Comparator},
+    compression::CompressionOptions,
+    dictionary::DictionarySampler,
+    memtable::{MemTableEntry, RangeTombstone},
+    merge_operator::MergeOperator,
+};
+
+/// Settings of a compaction
+#[derive(Default)]
+pub struct Compaction<'a> {
+    /// Level the output is written to
+    pub level: usize,
+    /// Whether the output is the bottommost level, so no older data of the
+    /// keys exists outside of the compaction
+    pub bottommost: bool,
+    /// Operator combining merge operands. Operands are kept as they are
+    /// without one
+    pub merge_operator: Option<&'a dyn MergeOperator>,
+    /// Range Tombstones of the runs being compacted
+    pub range_tombstones: &'a [RangeTombstone],
+    /// Time of the compaction in microseconds, values that expired by then
+    /// are removed
+    pub now: u128,
+    /// Filter invoked for every value written to the output
+    pub filter: Option<&'a dyn CompactionFilter>,
+    /// Comparator the keys are sorted with. Keys are ordered bytewise without
+    /// one
+    pub comparator: Option<&'a dyn Comparator>,
+}
+
+impl Compaction<'_> {
+    /// Compacts entries sorted by key, and then from newest to oldest,
+    /// returning the entries to write to the output sorted by key
+    pub fn compact(&self, entries: impl IntoIterator<Item = MemTableEntry>) -> Vec<MemTableEntry> {
+        self.compact_with_filtered_keys(entries).0
+    }
+
+    /// Compacts entries like [`Compaction::compact`], also returning the keys
+    /// whose value the filter removed or changed
+    pub fn compact_with_filtered_keys(
+        &self,
+        entries: impl IntoIterator<Item = MemTableEntry>,
+    ) -> (Vec<MemTableEntry>, Vec<Vec<u8>>) {
+        let comparator = self.comparator.unwrap_or(&BytewiseComparator);
+        let mut output = Vec::new();
+        let mut versions: Vec<MemTableEntry> = Vec::new();
+        for entry in entries {
+            if versions
+                .first()
+                .is_some_and(|v| comparator.compare(&v.key, &entry.key).is_ne())
+            {
+                self.compact_key(std::mem::take(&mut versions), &mut output);
+            }
+            versions.push(entry);
+        }
+        if !versions.is_empty() {
+            self.compact_key(versions, &mut output);
+        }
+
+        let mut filtered_keys = Vec::new();
+        let output = match self.filter {
+            Some(filter) => output
+                .into_iter()
+                .filter_map(|entry| self.apply_filter(filter, entry, &mut filtered_keys))
+                .collect(),
+            None => output,
+        };
+        (output, filtered_keys)
+    }
+
+    /// Applies the decision of the compaction filter to an output entry,
+    /// returning `None` if it is dropped, and adding its key to
+    /// `filtered_keys` if its value is removed or changed
+    fn apply_filter(
+        &self,
+        filter: &dyn CompactionFilter,
+        mut entry: MemTableEntry,
+        filtered_keys: &mut Vec<Vec<u8>>,
+    ) -> Option<MemTableEntry> {
+        let Some(value) = entry.value.as_deref() else {
+            return Some(entry);
+        };
+        if !entry.operands.is_empty() {
+            return Some(entry);
+        }
+
+        let context = CompactionFilterContext {
+            level: self.level,
+            bottommost: self.bottommost,
+        };
+        let decision = filter.filter(&context, &entry.key, value, entry.timestamp);
+        if decision != FilterDecision::Keep {
+            filtered_keys.push(entry.key.clone());
+        }
+        match decision {
+            FilterDecision::Keep => {}
+            // Older versions may still exist below the output, so a removed
+            // key needs a Tombstone unless the output is the bottommost level
+            FilterDecision::Remove if self.bottommost => return None,
+            FilterDecision::Remove => {
+                entry.value = None;
+                entry.deleted = true;
+                entry.expires_at = None;
+            }
+            FilterDecision::ChangeValue(value) => entry.value = Some(value),
+        }
+        Some(entry)
+    }
+
+    /// Range Tombstones to write to the output, none in the bottommost level
+    pub fn output_range_tombstones(&self) -> &[RangeTombstone] {
+        if self.bottommost {
+            &[]
+        } else {
+            self.range_tombstones
+        }
+    }
+
+    /// Compacts the versions of a key, newest first, into `output`
+    fn compact_key(&self, mut versions: Vec<MemTableEntry>, output: &mut Vec<MemTableEntry>) {
+        let comparator = self.comparator.unwrap_or(&BytewiseComparator);
+        let covered = versions.iter().position(|version| {
+            self.range_tombstones
+                .iter()
+                .any(|tombstone| tombstone.covers(&version.key, version.seq, comparator))
+        });
+        if let Some(idx) = covered {
+            versions.truncate(idx);
+        }
+        for version in versions.iter_mut().filter(|v| v.is_expired(self.now)) {
+            version.value = None;
+            version.deleted = true;
+            version.expires_at = None;
+        }
+        let Some(newest) = versions.first() else {
+            return;
+        };
+        if newest.operands.is_empty() {
+            if !(newest.deleted && self.bottommost) {
+                output.push(versions.swap_remove(0));
+            }
+            return;
+        }
+
+        let Some(operator) = self.merge_operator else {
+            output.extend(versions);
+            return;
+        };
+
+        // Gather the operands down to the first version with a value or a
+        // Tombstone, the base they apply to
+        let mut operands: Vec<&[u8]> = Vec::new();
+        let mut base = None;
+        let mut expires_at = None;
+        for version in &versions {
+            for operand in version.operands.iter().rev() {
+                operands.push(operand);
+            }
+            if version.value.is_some() || version.deleted {
+                base = Some(version.value.as_deref());
+                expires_at = version.expires_at;
+                break;
+            }
+        }
+        if base.is_none() && covered.is_some() {
+            base = Some(None);
+        }
+        operands.reverse();
+
+        let key = newest.key.clone();
+        let (value, operands) = match base {
+            // The operands outlive a value that expires, so they are kept apart
+            Some(base) if expires_at.is_some() => (
+                base.map(<[u8]>::to_vec),
+                partial_merge(operator, &key, &operands),
+            ),
+            Some(base) => (Some(operator.full_merge(&key, base, &operands)), Vec::new()),
+            None if self.bottommost => {
+                (Some(operator.full_merge(&key, None, &operands)), Vec::new())
+            }
+            None => (None, partial_merge(operator, &key, &operands)),
+        };
+        output.push(MemTableEntry {
+            key,
+            value,
+            timestamp: newest.timestamp,
+            expires_at,
+            seq: newest.seq,
+            deleted: false,
+            operands,
+        });
+    }
+}
+
+/// Trains the compression dictionary of the table holding the `output` of a
+/// compaction from a sample of its values
+///
+/// Returns `None` if dictionaries are disabled or the values have nothing in
+/// common.
+pub fn train_dictionary(output: &[MemTableEntry], options: &CompressionOptions) -> Option<Vec<u8>> {
+    if options.max_dict_bytes == 0 {
+        return None;
+    }
+    let mut sampler = DictionarySampler::new(options.max_train_bytes);
+    for value in output.iter().filter_map(|entry| entry.value.as_deref()) {
+        sampler.add(value);
+    }
+    sampler.train(options.max_dict_bytes)
+}
+
+/// Combines consecutive operands of a key while the operator allows it
+fn partial_merge(operator: &dyn MergeOperator, key: &[u8], operands: &[&[u8]]) -> Vec<Vec<u8>> {
+    let mut merged: Vec<Vec<u8>> = Vec::new();
+    for operand in operands {
+        let combined = merged
+            .last()
+            .and_then(|last| operator.partial_merge(key, last, operand));
+        match combined {
+            Some(combined) => *merged.last_mut().unwrap() = combined,
+            None => merged.push(operand.to_vec()),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block,
+        compression::CompressionType,
+        merge_operator::{AppendOperator, U64AddOperator},
+    };
+
+    fn entry(key: &[u8], value: Option<&[u8]>, operands: &[&[u8]], seq: u64) -> MemTableEntry {
+        MemTableEntry {
+            key: key.to_vec(),
+            value: value.map(|v| v.to_vec()),
+            timestamp: seq as u128,
+            expires_at: None,
+            seq,
+            deleted: value.is_none() && operands.is_empty(),
+            operands: operands.iter().map(|o| o.to_vec()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_compact_keeps_newest_version() {
+        let entries = vec![
+            entry(b"Apple", Some(b"Apple Pie"), &[], 3),
+            entry(b"Apple", Some(b"Apple Smoothie"), &[], 1),
+            entry(b"Lime", None, &[], 4),
+            entry(b"Lime", Some(b"Lime Smoothie"), &[], 2),
+        ];
+
+        let output = Compaction::default().compact(entries);
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[0].value.as_deref(), Some(b"Apple Pie".as_slice()));
+        assert!(output[1].deleted);
+
+        // Tombstones have nothing left to hide in the bottommost level
+        let compaction = Compaction {
+            bottommost: true,
+            ..Compaction::default()
+        };
+        let output = compaction.compact(vec![entry(b"Lime", None, &[], 4)]);
+        assert!(output.is_empty());
+    }
+
+    /// Orders keys ignoring ASCII case, so "lime" and "LIME" are the same key
+    struct CaseInsensitiveComparator;
+
+    impl Comparator for CaseInsensitiveComparator {
+        fn name(&self) -> &str {
+            "test.CaseInsensitiveComparator"
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }
+    }
+
+    #[test]
+    fn test_compact_groups_versions_with_comparator() {
+        let compaction = Compaction {
+            comparator: Some(&CaseInsensitiveComparator),
+            ..Compaction::default()
+        };
+        let entries = vec![
+            entry(b"Apple", Some(b"Apple Pie"), &[], 1),
+            entry(b"lime", Some(b"Lime Milkshake"), &[], 3),
+            entry(b"LIME", Some(b"Lime Smoothie"), &[], 2),
+        ];
+        let output = compaction.compact(entries);
+        assert_eq!(output.len(), 2);
+        assert_eq!(
+            output[1].value.as_deref(),
+            Some(b"Lime Milkshake".as_slice())
+        );
+    }
+
+    #[test]
+    fn test_compact_collapses_merge_operands() {
+        let operator = AppendOperator::with_delimiter(b",");
+        let compaction = Compaction {
+            merge_operator: Some(&operator),
+            ..Compaction::default()
+        };
+
+        let entries = vec![
+            entry(b"Apple", None, &[b"c", b"d"], 5),
+            entry(b"Apple", None, &[b"b"], 3),
+            entry(b"Apple", Some(b"a"), &[], 2),
+            entry(b"Apple", Some(b"old"), &[], 1),
+            entry(b"Lime", None, &[b"x"], 4),
+            entry(b"Lime", None, &[b"w"], 3),
+        ];
+        let output = compaction.compact(entries);
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[0].value.as_deref(), Some(b"a,b,c,d".as_slice()));
+        assert!(output[0].operands.is_empty());
+        assert_eq!(output[0].seq, 5);
+        // Without a base the operands are only combined with each other
+        assert_eq!(output[1].value, None);
+        assert_eq!(output[1].operands, vec![b"w,x".to_vec()]);
+
+        let operator = U64AddOperator;
+        let compaction = Compaction {
+            bottommost: true,
+            merge_operator: Some(&operator),
+            ..Compaction::default()
+        };
+        let one = 1u64.to_le_bytes();
+        let output = compaction.compact(vec![entry(b"counter", None, &[&one, &one], 2)]);
+        assert_eq!(
+            output[0].value.as_deref(),
+            Some(2u64.to_le_bytes().as_slice())
+        );
+    }
+
+    #[test]
+    fn test_compact_drops_range_deleted_versions() {
+        let operator = AppendOperator::with_delimiter(b",");
+        let range_tombstones = [RangeTombstone {
+            start: b"Apple".to_vec(),
+            end: b"Orange".to_vec(),
+            timestamp: 3,
+            seq: 3,
+        }];
+        let compaction = Compaction {
+            merge_operator: Some(&operator),
+            range_tombstones: &range_tombstones,
+            ..Compaction::default()
+        };
+
+        let entries = vec![
+            entry(b"Apple", None, &[b"b"], 4),
+            entry(b"Apple", Some(b"a"), &[], 1),
+            entry(b"Lime", Some(b"Lime Smoothie"), &[], 2),
+            entry(b"Orange", Some(b"Orange Smoothie"), &[], 1),
+        ];
+        let output = compaction.compact(entries);
+        assert_eq!(output.len(), 2);
+        // The range delete is the base of the newer operands
+        assert_eq!(output[0].value.as_deref(), Some(b"b".as_slice()));
+        assert_eq!(output[1].key, b"Orange");
+        assert_eq!(compaction.output_range_tombstones().len(), 1);
+
+        let compaction = Compaction {
+            bottommost: true,
+            ..compaction
+        };
+        assert!(compaction.output_range_tombstones().is_empty());
+    }
+
+    #[test]
+    fn test_compact_removes_expired_values() {
+        let mut expiring = entry(b"Apple", Some(b"Apple Pie"), &[], 3);
+        expiring.expires_at = Some(10);
+        let entries = vec![
+            expiring,
+            entry(b"Apple", Some(b"Apple Smoothie"), &[], 1),
+            entry(b"Lime", Some(b"Lime Smoothie"), &[], 2),
+        ];
+        let compaction = Compaction {
+            now: 10,
+            ..Compaction::default()
+        };
+
+        // The expired value still hides the older one
+        let output = compaction.compact(entries);
+        assert_eq!(output.len(), 2);
+        assert!(output[0].deleted);
+        assert_eq!(output[0].seq, 3);
+
+        let compaction = Compaction {
+            bottommost: true,
+            ..compaction
+        };
+        let output = compaction.compact(output);
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0].key, b"Lime");
+    }
+
+    /// Removes the keys of deleted accounts and redacts passwords
+    struct AccountFilter;
+
+    impl CompactionFilter for AccountFilter {
+        fn name(&self) -> &str {
+            "AccountFilter"
+        }
+
+        fn filter(
+            &self,
+            _context: &CompactionFilterContext,
+            key: &[u8],
+            value: &[u8],
+            _timestamp: u128,
+        ) -> FilterDecision {
+            if key.starts_with(b"deleted/") {
+                FilterDecision::Remove
+            } else if key.ends_with(b"/password") && value != b"***" {
+                FilterDecision::ChangeValue(b"***".to_vec())
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_applies_filter() {
+        let compaction = Compaction {
+            level: 1,
+            filter: Some(&AccountFilter),
+            ..Compaction::default()
+        };
+        let entries = vec![
+            entry(b"alice/password", Some(b"hunter2"), &[], 2),
+            entry(b"deleted/bob", Some(b"Bob"), &[], 3),
+            entry(b"deleted/bob", Some(b"Bobby"), &[], 1),
+            entry(b"deleted/carol", None, &[], 4),
+        ];
+
+        let (output, filtered_keys) = compaction.compact_with_filtered_keys(entries);
+        assert_eq!(output.len(), 3);
+        assert_eq!(output[0].value.as_deref(), Some(b"***".as_slice()));
+        assert_eq!(
+            filtered_keys,
+            vec![b"alice/password".to_vec(), b"deleted/bob".to_vec()]
+        );
+        // The removed key keeps hiding its older version
+        assert!(output[1].deleted);
+        assert_eq!(output[1].seq, 3);
+        assert!(output[2].deleted);
+
+        let compaction = Compaction {
+            bottommost: true,
+            ..compaction
+        };
+        let output = compaction.compact(vec![entry(b"deleted/bob", Some(b"Bob"), &[], 3)]);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_compact_trains_dictionary() {
+        let entries: Vec<MemTableEntry> = (0..200u64)
+            .map(|id| {
+                let key = format!("user/{:03}", id);
+                let value = format!(r#"{{"id": {}, "fruit": "Lime", "smoothie": true}}"#, id);
+                entry(key.as_bytes(), Some(value.as_bytes()), &[], id + 1)
+            })
+            .collect();
+        let compaction = Compaction::default();
+        let output = compaction.compact(entries);
+
+        let options = CompressionOptions::default();
+        assert_eq!(train_dictionary(&output, &options), None);
+
+        let options = CompressionOptions {
+            max_dict_bytes: 256,
+            ..options
+        };
+        let dict = train_dictionary(&output, &options).unwrap();
+        assert!(dict.len() <= 256);
+
+        let value = output[7].value.as_deref().unwrap();
+        let block = block::encode_block(&CompressionType::Lz4, value, Some(&dict));
+        assert!(block.len() < value.len());
+        assert_eq!(
+            block::decode_block(&block, &options, Some(&dict), true).unwrap(),
+            value
+        );
+    }
+}
@@ -0,0 +1,49 @@
+//! Compaction filters drop or rewrite values while they are compacted.
+//!
+//! A filter sees every value compaction writes to its output, and decides
+//! with its own rules whether to keep it, remove it or change it. This lets
+//! applications garbage collect data, like the keys of deleted accounts,
+//! without deleting every key themselves.
+//!
+//! Every column family can have its own filter, set in its options.
+
+#![allow(dead_code)]
+
+/// Information about the compaction a filter is invoked from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompactionFilterContext {
+    /// Level the output of the compaction is written to
+    pub level: usize,
+    /// Whether the output is the bottommost level
+    pub bottommost: bool,
+}
+
+/// What to do with a value seen by a compaction filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    /// Writes the value to the output as it is
+    Keep,
+    /// Removes the key, as if it had been deleted
+    Remove,
+    /// Writes the given value to the output instead
+    ChangeValue(Vec<u8>),
+}
+
+/// Decides what happens to the values written by a compaction
+///
+/// Filters are only invoked for values. Tombstones and merge operands that
+/// could not be combined with a value are written as they are.
+pub trait CompactionFilter: Send + Sync {
+    /// Name of the filter
+    fn name(&self) -> &str;
+
+    /// Decides what to do with the value of a key, written at `timestamp`
+    /// microseconds
+    fn filter(
+        &self,
+        context: &CompactionFilterContext,
+        key: &[u8],
+        value: &[u8],
+        timestamp: u128,
+    ) -> FilterDecision;
+}
@@ -0,0 +1,69 @@
+//! Comparators define the order of the keys of the database.
+//!
+//! Keys are ordered bytewise unless the database is opened with another
+//! comparator. The order is used wherever keys are sorted or compared: in the
+//! MemTables, the index of the tables, the iterators and the bounds of range
+//! deletes.
+//!
+//! Data sorted with one order can't be read with another, so the name of the
+//! comparator is recorded in the manifest when the database is created, and
+//! opening it with a comparator of a different name fails.
+
+#![allow(dead_code)]
+
+use std::cmp::Ordering;
+
+/// Defines a total order of keys
+pub trait Comparator: Send + Sync {
+    /// Name of the comparator, recorded in the manifest. Comparators that
+    /// order keys differently must have different names
+    fn name(&self) -> &str;
+
+    /// Orders two keys
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Orders keys lexicographically by their bytes, the default order
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "irondb.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Orders keys in the reverse of the bytewise order, so iterators return the
+/// largest keys first
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReverseBytewiseComparator;
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &str {
+        "irondb.ReverseBytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comparators() {
+        let mut keys: Vec<&[u8]> = vec![b"Lime", b"Apple", b"Apple Pie", b"Orange"];
+        keys.sort_by(|a, b| BytewiseComparator.compare(a, b));
+        assert_eq!(keys, vec![&b"Apple"[..], b"Apple Pie", b"Lime", b"Orange"]);
+
+        keys.sort_by(|a, b| ReverseBytewiseComparator.compare(a, b));
+        assert_eq!(keys, vec![&b"Orange"[..], b"Lime", b"Apple Pie", b"Apple"]);
+        assert_ne!(BytewiseComparator.name(), ReverseBytewiseComparator.name());
+    }
+}
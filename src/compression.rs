@@ -0,0 +1,289 @@
+//! Compression of the data written to disk.
+//!
+//! The WAL and SSTable blocks are compressed with a [`Codec`], either one of
+//! the built-in algorithms of [`CompressionType`] or one defined by the
+//! application. Every codec has an id that is persisted with the data it
+//! compressed, so the data can be decompressed after the configuration
+//! changes.
+
+#![allow(dead_code)]
+
+use std::{fmt, sync::Arc};
+
+/// Identifier of a codec, persisted with the data it compressed
+pub type CodecId = u8;
+
+/// First id available to codecs defined by applications. Lower ids are
+/// reserved for the built-in codecs
+pub const FIRST_CUSTOM_CODEC_ID: CodecId = 128;
+
+/// Default maximum size of the values sampled to train a dictionary (1 MiB)
+pub const DEFAULT_MAX_TRAIN_BYTES: usize = 1024 * 1024;
+
+/// An algorithm compressing blocks of data
+pub trait Codec: Send + Sync {
+    /// Identifier of the codec, which must never change once data was
+    /// compressed with it
+    fn id(&self) -> CodecId;
+
+    /// Name of the codec
+    fn name(&self) -> &str;
+
+    /// Compresses `data`
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Decompresses `data` that was compressed by this codec
+    ///
+    /// Returns `None` if the data is not valid for this codec
+    fn decompress(&self, data: &[u8]) -> Option<Vec<u8>>;
+
+    /// Compresses `data` with a dictionary of content similar to it, shared
+    /// by many blocks. Codecs that don't support dictionaries ignore it
+    fn compress_with_dict(&self, data: &[u8], dict: &[u8]) -> Vec<u8> {
+        let _ = dict;
+        self.compress(data)
+    }
+
+    /// Decompresses `data` that was compressed by this codec with `dict`
+    fn decompress_with_dict(&self, data: &[u8], dict: &[u8]) -> Option<Vec<u8>> {
+        let _ = dict;
+        self.decompress(data)
+    }
+}
+
+/// Compression algorithm applied to a piece of data
+///
+/// The discriminant is persisted on disk, so existing values must never change
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompressionType {
+    /// Data is stored as is
+    #[default]
+    None = 0,
+    /// LZ4 block compression, very fast with a moderate compression ratio
+    Lz4 = 1,
+}
+
+impl CompressionType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(CompressionType::None),
+            1 => Some(CompressionType::Lz4),
+            _ => None,
+        }
+    }
+
+    /// Compresses `data`
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            CompressionType::None => data.to_vec(),
+            CompressionType::Lz4 => lz4_flex::block::compress_prepend_size(data),
+        }
+    }
+
+    /// Decompresses `data` that was compressed with the same algorithm
+    ///
+    /// Returns `None` if the data is not valid for this algorithm
+    pub fn decompress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            CompressionType::None => Some(data.to_vec()),
+            CompressionType::Lz4 => lz4_flex::block::decompress_size_prepended(data).ok(),
+        }
+    }
+
+    /// Compresses `data`, finding matches in `dict` as if it preceded the data
+    pub fn compress_with_dict(self, data: &[u8], dict: &[u8]) -> Vec<u8> {
+        match self {
+            CompressionType::None => data.to_vec(),
+            CompressionType::Lz4 => lz4_flex::block::compress_prepend_size_with_dict(data, dict),
+        }
+    }
+
+    /// Decompresses `data` that was compressed with the same algorithm and
+    /// dictionary
+    pub fn decompress_with_dict(self, data: &[u8], dict: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            CompressionType::None => Some(data.to_vec()),
+            CompressionType::Lz4 => {
+                lz4_flex::block::decompress_size_prepended_with_dict(data, dict).ok()
+            }
+        }
+    }
+}
+
+impl Codec for CompressionType {
+    fn id(&self) -> CodecId {
+        *self as CodecId
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            CompressionType::None => "none",
+            CompressionType::Lz4 => "lz4",
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        CompressionType::compress(*self, data)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        CompressionType::decompress(*self, data)
+    }
+
+    fn compress_with_dict(&self, data: &[u8], dict: &[u8]) -> Vec<u8> {
+        CompressionType::compress_with_dict(*self, data, dict)
+    }
+
+    fn decompress_with_dict(&self, data: &[u8], dict: &[u8]) -> Option<Vec<u8>> {
+        CompressionType::decompress_with_dict(*self, data, dict)
+    }
+}
+
+/// Which codec compresses the blocks of the tables of each level
+#[derive(Clone)]
+pub struct CompressionOptions {
+    /// Codec of each level, starting at L0. Levels past the end use the last
+    /// codec, and no codec at all means no compression
+    pub per_level: Vec<Arc<dyn Codec>>,
+    /// Codec of the bottommost level, which holds most of the data, instead of
+    /// the one of its level in `per_level`
+    pub bottommost: Option<Arc<dyn Codec>>,
+    /// Other codecs that tables may have been compressed with, such as custom
+    /// codecs that are no longer configured for any level, so those tables
+    /// stay readable
+    pub codecs: Vec<Arc<dyn Codec>>,
+    /// Maximum size of the dictionary trained for each table written by a
+    /// compaction, shared by all its data blocks, or 0 to not use
+    /// dictionaries. Dictionaries help most with small, similar values that
+    /// compress poorly on their own
+    pub max_dict_bytes: usize,
+    /// Maximum size of the values sampled to train a dictionary
+    pub max_train_bytes: usize,
+}
+
+impl Default for CompressionOptions {
+    /// L0 and L1 are rewritten soon after being written, so they are not
+    /// compressed. The deeper levels are compressed with LZ4
+    fn default() -> Self {
+        Self {
+            per_level: vec![
+                Arc::new(CompressionType::None),
+                Arc::new(CompressionType::None),
+                Arc::new(CompressionType::Lz4),
+            ],
+            bottommost: None,
+            codecs: Vec::new(),
+            max_dict_bytes: 0,
+            max_train_bytes: DEFAULT_MAX_TRAIN_BYTES,
+        }
+    }
+}
+
+impl fmt::Debug for CompressionOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = |codecs: &[Arc<dyn Codec>]| -> Vec<String> {
+            codecs.iter().map(|codec| codec.name().to_owned()).collect()
+        };
+        f.debug_struct("CompressionOptions")
+            .field("per_level", &names(&self.per_level))
+            .field(
+                "bottommost",
+                &self.bottommost.as_ref().map(|codec| codec.name()),
+            )
+            .field("codecs", &names(&self.codecs))
+            .field("max_dict_bytes", &self.max_dict_bytes)
+            .field("max_train_bytes", &self.max_train_bytes)
+            .finish()
+    }
+}
+
+impl CompressionOptions {
+    /// Codec compressing the blocks of the tables written to `level`
+    pub fn codec_for_level(&self, level: usize, bottommost: bool) -> &dyn Codec {
+        if let Some(codec) = self.bottommost.as_deref().filter(|_| bottommost) {
+            return codec;
+        }
+        match self.per_level.get(level).or(self.per_level.last()) {
+            Some(codec) => codec.as_ref(),
+            None => &CompressionType::None,
+        }
+    }
+
+    /// Finds the codec with the given id among the built-in codecs and the
+    /// configured ones
+    pub fn codec(&self, id: CodecId) -> Option<&dyn Codec> {
+        if let Some(compression) = CompressionType::from_u8(id) {
+            return Some(match compression {
+                CompressionType::None => &CompressionType::None,
+                CompressionType::Lz4 => &CompressionType::Lz4,
+            });
+        }
+        self.per_level
+            .iter()
+            .chain(&self.bottommost)
+            .chain(&self.codecs)
+            .find(|codec| codec.id() == id)
+            .map(Arc::as_ref)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let data = br#"{"fruit": "Lime", "smoothie": true, "fruit": "Lime"}"#.repeat(10);
+
+        for compression in [CompressionType::None, CompressionType::Lz4] {
+            let compressed = compression.compress(&data);
+            assert_eq!(compression.decompress(&compressed).unwrap(), data);
+        }
+
+        assert!(CompressionType::Lz4.compress(&data).len() < data.len());
+    }
+
+    #[test]
+    fn test_codec_per_level() {
+        let options = CompressionOptions {
+            bottommost: Some(Arc::new(CompressionType::Lz4)),
+            ..CompressionOptions::default()
+        };
+        let codec_ids: Vec<CodecId> = (0..5)
+            .map(|level| options.codec_for_level(level, false).id())
+            .collect();
+        assert_eq!(codec_ids, vec![0, 0, 1, 1, 1]);
+        assert_eq!(options.codec_for_level(1, true).name(), "lz4");
+
+        let options = CompressionOptions {
+            per_level: Vec::new(),
+            ..CompressionOptions::default()
+        };
+        assert_eq!(options.codec_for_level(3, true).name(), "none");
+        assert_eq!(options.codec(1).unwrap().name(), "lz4");
+        assert!(options.codec(FIRST_CUSTOM_CODEC_ID).is_none());
+    }
+
+    #[test]
+    fn test_compress_with_dict() {
+        let dict = br#"{"fruit": "Lime", "smoothie": true}"#;
+        let data = br#"{"fruit": "Apple", "smoothie": true}"#;
+
+        let compressed = CompressionType::Lz4.compress_with_dict(data, dict);
+        assert!(compressed.len() < CompressionType::Lz4.compress(data).len());
+        assert_eq!(
+            CompressionType::Lz4
+                .decompress_with_dict(&compressed, dict)
+                .unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn test_decompress_invalid() {
+        let compressed = CompressionType::Lz4.compress(b"Lime Smoothie");
+        assert!(CompressionType::Lz4
+            .decompress(&compressed[..compressed.len() - 2])
+            .is_none());
+    }
+}
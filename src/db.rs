@@ -0,0 +1,2327 @@
+//! Handle to an open database.
+//!
+//! Writes are appended to the WAL and then applied to the MemTable while
+//! holding a lock, so writers are serialized and the sequence numbers of the
+//! MemTable follow the order of the WAL.
+//!
+//! The data is split in column families, each with its own MemTable and
+//! options, that share the WAL. The column families that exist are recorded
+//! in the manifest.
+//!
+//! Flushing writes the MemTables to new L0 tables and records them in the
+//! manifest, together with the sequence number of the last operation they
+//! hold, so the WAL is only replayed after it when opening the database.
+//! Reads look for a key in the MemTable of its column family first, and then
+//! in its tables from newest to oldest, until they find a value or a
+//! Tombstone.
+//!
+//! Opening the database with a recovery target replays the WAL only up to it,
+//! bringing the database back to an earlier point in time, as long as its
+//! tables don't hold any later operation.
+//!
+//! Compaction merges all the tables of a column family into one table of L1,
+//! the bottommost level. The blocks of every table are compressed with the
+//! codec configured for its level.
+//!
+//! Point lookups can be served by an optional row cache, checked before the
+//! MemTables. Writes invalidate the rows of the keys they touch while holding
+//! the lock, so the cache never serves a stale value.
+//!
+//! Keys are ordered by the comparator of the database, bytewise unless
+//! another one is configured. Its name is recorded in the manifest when the
+//! database is created, and opening the database with a different one fails.
+//! Databases created before the name was recorded are ordered bytewise.
+
+#![allow(dead_code)]
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{create_dir_all, remove_file},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use crate::{
+    block_cache::{BlockCache, BlockKind, CacheKey, CacheStats},
+    column_family::{ColumnFamily, ColumnFamilyId, ColumnFamilyOptions, DEFAULT_COLUMN_FAMILY},
+    compaction::{train_dictionary, Compaction},
+    compaction_filter::CompactionFilter,
+    comparator::{BytewiseComparator, Comparator},
+    compression::CompressionOptions,
+    error::{Error, Result},
+    lock_manager::LockManager,
+    manifest::{Manifest, TableFile},
+    memtable::{MemTable, MemTableEntry, RangeTombstone},
+    merge_operator::MergeOperator,
+    row_cache::{Row, RowCache, RowCacheStats},
+    table::{decode_entries, table_file_path, write_table, BlockHandle, TableReader},
+    table_cache::{TableCache, DEFAULT_MAX_OPEN_FILES},
+    utils::now_micros,
+    wal::{RecoveryTarget, Wal, WalOptions},
+    write_batch::{WriteBatch, WriteOp},
+};
+
+/// Level of the tables written by compactions, which is the bottommost level
+/// since compactions merge every table of a column family
+const COMPACTION_OUTPUT_LEVEL: usize = 1;
+
+/// Options used when opening a database
+#[derive(Clone)]
+pub struct DbOptions {
+    /// Options of the WAL of the database
+    pub wal: WalOptions,
+    /// How long pessimistic transactions wait for the lock of a key
+    pub lock_timeout: Duration,
+    /// Operator combining the operands written with [`Db::merge`] to the
+    /// default column family. Merging fails if none is set
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Filter deciding what happens to the values of the default column
+    /// family when they are compacted
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Options of the other column families that already exist, by name.
+    /// Column families missing here are opened with the default options
+    pub column_families: HashMap<String, ColumnFamilyOptions>,
+    /// Capacity in bytes of the cache of the rows read by point lookups, or
+    /// `None` to not cache rows
+    pub row_cache_capacity: Option<usize>,
+    /// Comparator the keys of every column family are ordered with. It can't
+    /// be changed once the database is created
+    pub comparator: Arc<dyn Comparator>,
+    /// Maximum number of tables kept open, the least recently used ones are
+    /// closed past it
+    pub max_open_files: usize,
+    /// Cache of the blocks read from tables, or `None` to not cache blocks.
+    /// It picks the eviction policy, and can be shared by several databases
+    pub block_cache: Option<Arc<BlockCache>>,
+    /// Codecs compressing the blocks of the tables of each level
+    pub compression: CompressionOptions,
+    /// Point in time to bring the database back to, discarding the operations
+    /// of the WAL that happened after it. Opening fails if the tables already
+    /// hold such operations, since they can't be undone
+    pub recovery_target: Option<RecoveryTarget>,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        Self {
+            wal: WalOptions::default(),
+            lock_timeout: Duration::from_secs(1),
+            merge_operator: None,
+            compaction_filter: None,
+            column_families: HashMap::new(),
+            row_cache_capacity: None,
+            comparator: Arc::new(BytewiseComparator),
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            block_cache: None,
+            compression: CompressionOptions::default(),
+            recovery_target: None,
+        }
+    }
+}
+
+impl fmt::Debug for DbOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DbOptions")
+            .field("wal", &self.wal)
+            .field("lock_timeout", &self.lock_timeout)
+            .field(
+                "merge_operator",
+                &self.merge_operator.as_ref().map(|op| op.name()),
+            )
+            .field(
+                "compaction_filter",
+                &self.compaction_filter.as_ref().map(|filter| filter.name()),
+            )
+            .field("column_families", &self.column_families)
+            .field("row_cache_capacity", &self.row_cache_capacity)
+            .field("comparator", &self.comparator.name())
+            .field("max_open_files", &self.max_open_files)
+            .field("block_cache", &self.block_cache)
+            .field("compression", &self.compression)
+            .field("recovery_target", &self.recovery_target)
+            .finish()
+    }
+}
+
+/// Options of a read
+#[derive(Debug, Clone, Copy)]
+pub struct ReadOptions {
+    /// Whether the blocks read from tables are inserted in the block cache.
+    /// Bulk scans can turn it off to not evict the blocks read often
+    pub fill_cache: bool,
+    /// Whether the checksums of the data blocks read from disk are verified.
+    /// Reads that can tolerate corruption can turn it off to skip the cost
+    pub verify_checksums: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            fill_cache: true,
+            verify_checksums: true,
+        }
+    }
+}
+
+/// State of a column family
+struct ColumnFamilyData {
+    name: String,
+    memtable: MemTable,
+    options: ColumnFamilyOptions,
+}
+
+impl ColumnFamilyData {
+    fn merge_operator(&self) -> Result<&dyn MergeOperator> {
+        self.options.merge_operator.as_deref().ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "no merge operator is configured for column family {}",
+                self.name
+            ))
+        })
+    }
+}
+
+/// Versions of a key found in the sorted runs of a column family, visited
+/// from the newest run to the oldest
+#[derive(Default)]
+struct KeyVersions {
+    /// Sequence number of the newest version
+    version: u64,
+    /// Value below the merge operands, if any
+    value: Option<Vec<u8>>,
+    expires_at: Option<u128>,
+    /// Merge operands on top of the value, newest first
+    operands: Vec<Vec<u8>>,
+}
+
+impl KeyVersions {
+    /// Adds the entry of the key in the next run, if the run has one, and
+    /// whether the Range Tombstones of the run cover the key, at time `now`
+    ///
+    /// Returns true once the older runs can't change the value of the key.
+    /// The entries of a run are always newer than its own Range Tombstones.
+    fn add(&mut self, entry: Option<&MemTableEntry>, covered: bool, now: u128) -> bool {
+        let Some(entry) = entry else {
+            return covered;
+        };
+        if self.version == 0 {
+            self.version = entry.seq;
+        }
+        self.operands.extend(entry.operands.iter().rev().cloned());
+        if entry.deleted {
+            return true;
+        }
+        match &entry.value {
+            // An expired value is missing, but operands merged after it still
+            // apply
+            Some(value) => {
+                self.value = Some(value.clone()).filter(|_| !entry.is_expired(now));
+                self.expires_at = entry.expires_at;
+                true
+            }
+            None => covered,
+        }
+    }
+
+    /// Value of the key, combining the merge operands with the operator of
+    /// `family` if there are any
+    fn into_row(self, key: &[u8], family: &ColumnFamilyData) -> Result<Row> {
+        let value = match self.operands.is_empty() {
+            true => self.value,
+            false => {
+                let operator = family.merge_operator()?;
+                let operands: Vec<&[u8]> = self.operands.iter().rev().map(Vec::as_slice).collect();
+                // Operands with no value below them in any run are merged into
+                // a missing one
+                Some(operator.full_merge(key, self.value.as_deref(), &operands))
+            }
+        };
+        Ok(Row {
+            value,
+            version: self.version,
+            expires_at: self.expires_at,
+        })
+    }
+}
+
+/// State of the database guarded by its lock
+pub(crate) struct DbState {
+    dir: PathBuf,
+    pub(crate) wal: Wal,
+    manifest: Manifest,
+    /// Every column family that exists, by id
+    column_families: BTreeMap<ColumnFamilyId, ColumnFamilyData>,
+    row_cache: Option<RowCache>,
+    /// Readers of the tables opened recently
+    table_cache: TableCache<TableReader>,
+    block_cache: Option<Arc<BlockCache>>,
+    /// Id of the database in the block cache
+    cache_id: u64,
+    compression: CompressionOptions,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl DbState {
+    /// Appends a batch to the WAL and applies it to the MemTables
+    fn apply(&mut self, batch: &WriteBatch) -> Result<()> {
+        let timestamp = now_micros();
+        let last_seq = self.wal.write_batch(batch, timestamp)?;
+        self.wal.flush()?;
+        self.apply_to_memtable(batch, last_seq, timestamp);
+        Ok(())
+    }
+
+    /// Applies a batch already appended to the WAL to the MemTables, given the
+    /// sequence number assigned to its last operation
+    ///
+    /// Operations on column families dropped since the batch was checked are
+    /// skipped
+    fn apply_to_memtable(&mut self, batch: &WriteBatch, last_seq: u64, timestamp: u128) {
+        let first_seq = last_seq + 1 - batch.len() as u64;
+        for (seq, (cf, op)) in (first_seq..).zip(batch.iter_cf()) {
+            let Some(family) = self.column_families.get_mut(&cf) else {
+                continue;
+            };
+            if let Some(cache) = &mut self.row_cache {
+                match op {
+                    WriteOp::DeleteRange { start, end } => {
+                        cache.invalidate_range(cf, start, end, self.comparator.as_ref())
+                    }
+                    _ => cache.invalidate(cf, op.key()),
+                }
+            }
+            let memtable = &mut family.memtable;
+            match op {
+                WriteOp::Set { key, value } => memtable.set(key, value, timestamp, seq),
+                WriteOp::SetWithTtl { key, value, ttl } => {
+                    memtable.set_with_ttl(key, value, timestamp, *ttl, seq)
+                }
+                WriteOp::Delete { key } => memtable.delete(key, timestamp, seq),
+                WriteOp::Merge { key, operand } => memtable.merge(key, operand, timestamp, seq),
+                WriteOp::DeleteRange { start, end } => {
+                    memtable.delete_range(start, end, timestamp, seq)
+                }
+            }
+        }
+    }
+
+    /// Gets the value of a key in a column family together with its version,
+    /// from the row cache if it holds the key
+    fn get_with_version(
+        &mut self,
+        cf: ColumnFamilyId,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        let now = now_micros();
+        if let Some(row) = self
+            .row_cache
+            .as_mut()
+            .and_then(|cache| cache.get(cf, key, now))
+        {
+            return Ok((row.value, row.version));
+        }
+
+        let row = self.read(cf, key, now, options)?;
+        let result = (row.value.clone(), row.version);
+        if let Some(cache) = &mut self.row_cache {
+            cache.insert(cf, key, row);
+        }
+        Ok(result)
+    }
+
+    /// Version of a key of the default column family, the sequence number of
+    /// the last write to it, or 0 if it was never written
+    pub(crate) fn version(&self, key: &[u8]) -> Result<u64> {
+        let row = self.read(
+            DEFAULT_COLUMN_FAMILY,
+            key,
+            now_micros(),
+            &ReadOptions::default(),
+        )?;
+        Ok(row.version)
+    }
+
+    /// Looks up a key of a column family at time `now`, in its MemTable and
+    /// then in its tables from newest to oldest
+    fn read(
+        &self,
+        cf: ColumnFamilyId,
+        key: &[u8],
+        now: u128,
+        options: &ReadOptions,
+    ) -> Result<Row> {
+        let family = self.column_family(cf)?;
+        let memtable = &family.memtable;
+        let mut versions = KeyVersions::default();
+        let mut done = versions.add(
+            memtable.get(key),
+            self.covers(memtable.range_tombstones(), key),
+            now,
+        );
+        for table in self.manifest.tables(cf) {
+            if done {
+                break;
+            }
+            let reader = self.open_table(table)?;
+            let entry = self.table_entry(table, &reader, key, options)?;
+            done = versions.add(
+                entry.as_ref(),
+                self.covers(reader.range_tombstones(), key),
+                now,
+            );
+        }
+        versions.into_row(key, family)
+    }
+
+    /// Reads every Key-Value pair of a column family at time `now`, ordered by
+    /// key
+    fn scan(
+        &self,
+        cf: ColumnFamilyId,
+        now: u128,
+        options: &ReadOptions,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let family = self.column_family(cf)?;
+        let memtable = &family.memtable;
+        let comparator = self.comparator.as_ref();
+
+        // Every key of the tables is read, so they are read whole
+        let mut tables = Vec::new();
+        for table in self.manifest.tables(cf) {
+            let reader = self.open_table(table)?;
+            let mut entries = Vec::new();
+            for (_, handle) in reader.index() {
+                entries.extend(self.read_entries(table, &reader, *handle, options)?);
+            }
+            tables.push((entries, reader));
+        }
+
+        let mut keys: Vec<&[u8]> = memtable
+            .iter()
+            .chain(tables.iter().flat_map(|(entries, _)| entries))
+            .map(|entry| entry.key.as_slice())
+            .collect();
+        keys.sort_by(|a, b| comparator.compare(a, b));
+        keys.dedup_by(|a, b| comparator.compare(a, b).is_eq());
+
+        let mut pairs = Vec::new();
+        for key in keys {
+            let mut versions = KeyVersions::default();
+            let mut done = versions.add(
+                memtable.get(key),
+                self.covers(memtable.range_tombstones(), key),
+                now,
+            );
+            for (entries, reader) in &tables {
+                if done {
+                    break;
+                }
+                let entry = entries
+                    .binary_search_by(|entry| comparator.compare(&entry.key, key))
+                    .ok()
+                    .map(|idx| &entries[idx]);
+                done = versions.add(entry, self.covers(reader.range_tombstones(), key), now);
+            }
+            if let Some(value) = versions.into_row(key, family)?.value {
+                pairs.push((key.to_vec(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Whether the Range Tombstones of a run hide a key in the older runs
+    fn covers(&self, range_tombstones: &[RangeTombstone], key: &[u8]) -> bool {
+        range_tombstones
+            .iter()
+            .any(|tombstone| tombstone.covers(key, 0, self.comparator.as_ref()))
+    }
+
+    /// Gets the reader of a table from the table cache, opening the table if
+    /// it is not cached
+    fn open_table(&self, table: &TableFile) -> io::Result<Arc<TableReader>> {
+        self.table_cache.get_or_open(table.file_number, || {
+            let path = table_file_path(&self.dir, table.file_number);
+            match &self.block_cache {
+                Some(cache) => TableReader::open_with_cache(
+                    &path,
+                    cache,
+                    self.cache_id,
+                    table.file_number,
+                    table.level,
+                ),
+                None => TableReader::open(&path),
+            }
+        })
+    }
+
+    /// Finds the entry of a key in a table, reading the only data block that
+    /// may hold it
+    fn table_entry(
+        &self,
+        table: &TableFile,
+        reader: &TableReader,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<Option<MemTableEntry>> {
+        let comparator = self.comparator.as_ref();
+        let Some(handle) = reader.block_for_key(key, comparator) else {
+            return Ok(None);
+        };
+        let mut entries = self.read_entries(table, reader, handle, options)?;
+        let idx = entries.binary_search_by(|entry| comparator.compare(&entry.key, key));
+        Ok(idx.ok().map(|idx| entries.swap_remove(idx)))
+    }
+
+    /// Reads the entries of a data block of a table, from the block cache if
+    /// it holds the block, or else from disk, verifying its checksum and
+    /// inserting it in the cache if `options` ask for it
+    fn read_entries(
+        &self,
+        table: &TableFile,
+        reader: &TableReader,
+        handle: BlockHandle,
+        options: &ReadOptions,
+    ) -> io::Result<Vec<MemTableEntry>> {
+        let read = || {
+            reader
+                .read_data_block(handle, &self.compression, options.verify_checksums)
+                .map(Arc::from)
+        };
+        let data = match &self.block_cache {
+            Some(cache) => {
+                let key = CacheKey {
+                    cache_id: self.cache_id,
+                    file_number: table.file_number,
+                    offset: handle.offset,
+                };
+                cache.get_or_load(key, BlockKind::Data, table.level, options.fill_cache, read)?
+            }
+            None => read()?,
+        };
+        decode_entries(&data)
+    }
+
+    /// Writes the MemTable of every column family holding data to a new L0
+    /// table, and replaces the MemTables with empty ones
+    ///
+    /// The tables are recorded in the manifest together with the sequence
+    /// number of the last operation of the WAL, since every operation up to it
+    /// is now persisted in tables, and the WAL segments only holding such
+    /// operations are deleted.
+    fn flush(&mut self) -> Result<()> {
+        let flushed_sequence = self.wal.last_sequence();
+        let mut added = Vec::new();
+        for (&id, family) in &self.column_families {
+            let memtable = &family.memtable;
+            if memtable.len() == 0 && memtable.range_tombstones().is_empty() {
+                continue;
+            }
+            let table = TableFile {
+                file_number: self.manifest.new_file_number(),
+                level: 0,
+            };
+            write_table(
+                &table_file_path(&self.dir, table.file_number),
+                memtable.iter(),
+                memtable.range_tombstones(),
+                self.compression.codec_for_level(table.level, false),
+                None,
+            )?;
+            added.push((id, table));
+        }
+        if added.is_empty() {
+            return Ok(());
+        }
+
+        self.manifest
+            .update_tables(flushed_sequence, added, Vec::new())?;
+        for family in self.column_families.values_mut() {
+            family.memtable = MemTable::with_comparator(self.comparator.clone());
+        }
+        self.wal.mark_persisted(flushed_sequence)?;
+        Ok(())
+    }
+
+    /// Merges the tables of every column family into a single table of the
+    /// bottommost level, dropping the data that is no longer visible
+    ///
+    /// The new tables replace the old ones in a single manifest edit, so a
+    /// crash leaves either of them in use, and the old ones are deleted
+    /// afterwards.
+    fn compact(&mut self) -> Result<()> {
+        let ids: Vec<ColumnFamilyId> = self.column_families.keys().copied().collect();
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        let mut filtered_keys = Vec::new();
+        for id in ids {
+            let inputs = self.manifest.tables(id).to_vec();
+            if inputs.is_empty() {
+                continue;
+            }
+            let (output, keys) = self.compaction_output(id, &inputs)?;
+            filtered_keys.extend(keys.into_iter().map(|key| (id, key)));
+            if !output.is_empty() {
+                let dict = train_dictionary(&output, &self.compression);
+                let table = TableFile {
+                    file_number: self.manifest.new_file_number(),
+                    level: COMPACTION_OUTPUT_LEVEL,
+                };
+                write_table(
+                    &table_file_path(&self.dir, table.file_number),
+                    &output,
+                    &[],
+                    self.compression.codec_for_level(table.level, true),
+                    dict.as_deref(),
+                )?;
+                added.push((id, table));
+            }
+            removed.extend(inputs.iter().map(|table| (id, table.file_number)));
+        }
+        if removed.is_empty() {
+            return Ok(());
+        }
+
+        let flushed_sequence = self.manifest.flushed_sequence();
+        self.manifest
+            .update_tables(flushed_sequence, added, removed.clone())?;
+        if let Some(cache) = &mut self.row_cache {
+            for (id, key) in &filtered_keys {
+                cache.invalidate(*id, key);
+            }
+        }
+        for (_, file_number) in removed {
+            self.delete_table(file_number)?;
+        }
+        Ok(())
+    }
+
+    /// Compacts the entries of the tables of a column family, ordered from
+    /// newest to oldest, into the entries of a table of the bottommost level,
+    /// also returning the keys whose value the compaction filter removed or
+    /// changed
+    fn compaction_output(
+        &self,
+        cf: ColumnFamilyId,
+        inputs: &[TableFile],
+    ) -> Result<(Vec<MemTableEntry>, Vec<Vec<u8>>)> {
+        let family = self.column_family(cf)?;
+        let comparator = self.comparator.as_ref();
+
+        // The blocks are read once, so they are not worth caching
+        let options = ReadOptions {
+            fill_cache: false,
+            ..ReadOptions::default()
+        };
+        let mut entries = Vec::new();
+        let mut range_tombstones = Vec::new();
+        for table in inputs {
+            let reader = self.open_table(table)?;
+            for (_, handle) in reader.index() {
+                entries.extend(self.read_entries(table, &reader, *handle, &options)?);
+            }
+            range_tombstones.extend_from_slice(reader.range_tombstones());
+        }
+        // The sort is stable, so the versions of every key stay ordered from
+        // newest to oldest
+        entries.sort_by(|a, b| comparator.compare(&a.key, &b.key));
+
+        let compaction = Compaction {
+            level: COMPACTION_OUTPUT_LEVEL,
+            bottommost: true,
+            merge_operator: family.options.merge_operator.as_deref(),
+            range_tombstones: &range_tombstones,
+            now: now_micros(),
+            filter: family.options.compaction_filter.as_deref(),
+            comparator: Some(comparator),
+        };
+        Ok(compaction.compact_with_filtered_keys(entries))
+    }
+
+    /// Closes a table that is no longer in use, drops its blocks from the
+    /// block cache and deletes its file
+    fn delete_table(&self, file_number: u64) -> io::Result<()> {
+        self.table_cache.evict(file_number);
+        if let Some(cache) = &self.block_cache {
+            cache.erase_file(self.cache_id, file_number);
+        }
+        remove_file(table_file_path(&self.dir, file_number))
+    }
+
+    /// Gets a column family, failing if it doesn't exist
+    fn column_family(&self, cf: ColumnFamilyId) -> Result<&ColumnFamilyData> {
+        self.column_families
+            .get(&cf)
+            .ok_or_else(|| Error::InvalidArgument(format!("column family {} does not exist", cf)))
+    }
+
+    /// Checks that a batch can be applied with the options of the database
+    fn check_batch(&self, batch: &WriteBatch) -> Result<()> {
+        for (cf, op) in batch.iter_cf() {
+            let family = self.column_family(cf)?;
+            match op {
+                WriteOp::Merge { .. } => {
+                    family.merge_operator()?;
+                }
+                WriteOp::DeleteRange { start, end }
+                    if self.comparator.compare(start, end).is_gt() =>
+                {
+                    return Err(Error::InvalidArgument(
+                        "range delete starts after its end".to_owned(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An open database
+///
+/// All methods take `&self`, so the database can be shared between threads
+pub struct Db {
+    inner: Mutex<DbState>,
+    /// Locks of the keys used by pessimistic transactions
+    pub(crate) locks: LockManager,
+    pub(crate) lock_timeout: Duration,
+}
+
+impl Db {
+    /// Opens the database stored in `dir`, creating it if it does not exist
+    pub fn open(dir: &Path) -> Result<Self> {
+        Self::open_with_options(dir, DbOptions::default())
+    }
+
+    pub fn open_with_options(dir: &Path, options: DbOptions) -> Result<Self> {
+        create_dir_all(dir)?;
+        let mut manifest = Manifest::open(dir)?;
+        let comparator = options.comparator;
+        let bytewise = BytewiseComparator;
+        let recorded = match manifest.comparator() {
+            Some(name) => Some(name),
+            // Databases created before the comparator was recorded always
+            // ordered their keys bytewise
+            None if !manifest.created() => Some(bytewise.name()),
+            None => None,
+        };
+        if let Some(name) = recorded.filter(|&name| name != comparator.name()) {
+            return Err(Error::InvalidArgument(format!(
+                "database was created with comparator {}, not {}",
+                name,
+                comparator.name()
+            )));
+        }
+        if manifest.comparator().is_none() {
+            manifest.set_comparator(comparator.name())?;
+        }
+        if let Some(target) = options.recovery_target {
+            check_recovery_target(dir, &manifest, &options.compression, target)?;
+        }
+        let (wal, mut memtables) = Wal::load_from_dir_with_column_families(
+            dir,
+            options.wal,
+            options.recovery_target,
+            comparator.clone(),
+            manifest.flushed_sequence(),
+        )?;
+
+        // The operations recovered for column families that were dropped are
+        // discarded with their MemTables
+        let mut column_families = BTreeMap::new();
+        for (&id, name) in manifest.column_families() {
+            let cf_options = match id {
+                DEFAULT_COLUMN_FAMILY => ColumnFamilyOptions {
+                    merge_operator: options.merge_operator.clone(),
+                    compaction_filter: options.compaction_filter.clone(),
+                },
+                _ => options
+                    .column_families
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_default(),
+            };
+            let data = ColumnFamilyData {
+                name: name.clone(),
+                memtable: memtables
+                    .remove(&id)
+                    .unwrap_or_else(|| MemTable::with_comparator(comparator.clone())),
+                options: cf_options,
+            };
+            column_families.insert(id, data);
+        }
+
+        Ok(Self {
+            inner: Mutex::new(DbState {
+                dir: dir.to_owned(),
+                wal,
+                manifest,
+                column_families,
+                row_cache: options.row_cache_capacity.map(RowCache::new),
+                table_cache: TableCache::new(options.max_open_files),
+                cache_id: options
+                    .block_cache
+                    .as_ref()
+                    .map_or(0, |cache| cache.new_id()),
+                block_cache: options.block_cache.clone(),
+                compression: options.compression.clone(),
+                comparator,
+            }),
+            locks: LockManager::new(),
+            lock_timeout: options.lock_timeout,
+        })
+    }
+
+    /// Gets the value of a key, or `None` if it does not exist
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_with_options(key, &ReadOptions::default())
+    }
+
+    /// Gets the value of a key like [`Db::get`], with options of the read
+    pub fn get_with_options(&self, key: &[u8], options: &ReadOptions) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .lock()
+            .get_with_version(DEFAULT_COLUMN_FAMILY, key, options)?
+            .0)
+    }
+
+    /// Gets the value of a key in a column family, or `None` if it does not
+    /// exist
+    pub fn get_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .lock()
+            .get_with_version(cf.id(), key, &ReadOptions::default())?
+            .0)
+    }
+
+    /// Sets the value of a key
+    pub fn set(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.set(key, value);
+        self.write(&batch)
+    }
+
+    /// Sets the value of a key in a column family
+    pub fn set_cf(&self, cf: &ColumnFamily, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.set_cf(cf, key, value);
+        self.write(&batch)
+    }
+
+    /// Deletes a key
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(&batch)
+    }
+
+    /// Deletes a key in a column family
+    pub fn delete_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_cf(cf, key);
+        self.write(&batch)
+    }
+
+    /// Merges an operand into the value of a key with the merge operator of
+    /// the database
+    ///
+    /// The operand is stored as is and only combined with the value when the
+    /// key is read
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.merge(key, operand);
+        self.write(&batch)
+    }
+
+    /// Merges an operand into the value of a key in a column family with the
+    /// merge operator of the column family
+    pub fn merge_cf(&self, cf: &ColumnFamily, key: &[u8], operand: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.merge_cf(cf, key, operand);
+        self.write(&batch)
+    }
+
+    /// Sets the value of a key that expires `ttl` after it is written
+    ///
+    /// Once expired the key reads as missing, and its value is removed by
+    /// compaction
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.set_with_ttl(key, value, ttl);
+        self.write(&batch)
+    }
+
+    /// Deletes every key from `start` (inclusive) to `end` (exclusive)
+    ///
+    /// The whole range is deleted with a single write, no matter how many keys
+    /// it holds. Fails with [`Error::InvalidArgument`] if `start` is after `end`
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range(start, end);
+        self.write(&batch)
+    }
+
+    /// Applies all the operations of a batch atomically, even if they write to
+    /// several column families
+    pub fn write(&self, batch: &WriteBatch) -> Result<()> {
+        self.write_if(batch, |_| Ok(()))
+    }
+
+    /// Sets the value of a key only if it doesn't exist
+    ///
+    /// Returns whether the value was set
+    pub fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let mut batch = WriteBatch::new();
+        batch.set(key, value);
+        self.write_if_value(key, None, &batch)
+    }
+
+    /// Sets the value of a key to `new` only if its current value is
+    /// `expected`
+    ///
+    /// Returns whether the value was swapped
+    pub fn compare_and_swap(&self, key: &[u8], expected: &[u8], new: &[u8]) -> Result<bool> {
+        let mut batch = WriteBatch::new();
+        batch.set(key, new);
+        self.write_if_value(key, Some(expected), &batch)
+    }
+
+    /// Deletes a key only if its current value is `expected`
+    ///
+    /// Returns whether the key was deleted
+    pub fn delete_if(&self, key: &[u8], expected: &[u8]) -> Result<bool> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write_if_value(key, Some(expected), &batch)
+    }
+
+    /// Persists a batch in the WAL as a prepared transaction without applying
+    /// it, as the first phase of a two-phase commit
+    ///
+    /// The transaction is committed or rolled back later by name, which is
+    /// possible even after reopening the database
+    pub fn prepare(&self, name: &str, batch: &WriteBatch) -> Result<()> {
+        let mut state = self.lock();
+        state.check_batch(batch)?;
+        state.wal.prepare(name, batch)?;
+        state.wal.flush()?;
+        Ok(())
+    }
+
+    /// Applies the batch of a prepared transaction atomically
+    pub fn commit_prepared(&self, name: &str) -> Result<()> {
+        let mut state = self.lock();
+        let timestamp = now_micros();
+        let (batch, last_seq) = state.wal.commit_prepared(name, timestamp)?;
+        state.wal.flush()?;
+        state.apply_to_memtable(&batch, last_seq, timestamp);
+        Ok(())
+    }
+
+    /// Discards the batch of a prepared transaction
+    pub fn rollback_prepared(&self, name: &str) -> Result<()> {
+        let mut state = self.lock();
+        state.wal.rollback_prepared(name)?;
+        state.wal.flush()?;
+        Ok(())
+    }
+
+    /// Names of the transactions that are prepared but not committed nor
+    /// rolled back yet, including the ones recovered when opening the database
+    pub fn prepared_transactions(&self) -> Vec<String> {
+        self.lock().wal.prepared_transactions()
+    }
+
+    /// Creates a column family with the given options, returning its handle
+    ///
+    /// Fails with [`Error::InvalidArgument`] if a column family with the same
+    /// name already exists
+    pub fn create_column_family(
+        &self,
+        name: &str,
+        options: ColumnFamilyOptions,
+    ) -> Result<ColumnFamily> {
+        let mut state = self.lock();
+        if state.column_families.values().any(|cf| cf.name == name) {
+            return Err(Error::InvalidArgument(format!(
+                "column family {} already exists",
+                name
+            )));
+        }
+
+        let id = state.manifest.add_column_family(name)?;
+        let data = ColumnFamilyData {
+            name: name.to_owned(),
+            memtable: MemTable::with_comparator(state.comparator.clone()),
+            options,
+        };
+        state.column_families.insert(id, data);
+        Ok(ColumnFamily::new(id, name))
+    }
+
+    /// Drops a column family and all its data
+    ///
+    /// Fails with [`Error::InvalidArgument`] for the default column family,
+    /// which can't be dropped, or if the column family doesn't exist
+    pub fn drop_column_family(&self, cf: &ColumnFamily) -> Result<()> {
+        if cf.id() == DEFAULT_COLUMN_FAMILY {
+            return Err(Error::InvalidArgument(
+                "the default column family can't be dropped".to_owned(),
+            ));
+        }
+
+        let mut state = self.lock();
+        state.column_family(cf.id())?;
+        let tables = state.manifest.tables(cf.id()).to_vec();
+        state.manifest.drop_column_family(cf.id())?;
+        state.column_families.remove(&cf.id());
+        if let Some(cache) = &mut state.row_cache {
+            cache.invalidate_column_family(cf.id());
+        }
+        for table in tables {
+            state.delete_table(table.file_number)?;
+        }
+        Ok(())
+    }
+
+    /// Gets the handle of a column family by name, or `None` if it does not
+    /// exist
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamily> {
+        let state = self.lock();
+        let (id, data) = state
+            .column_families
+            .iter()
+            .find(|(_, data)| data.name == name)?;
+        Some(ColumnFamily::new(*id, &data.name))
+    }
+
+    /// Handles of all the column families, including the default one
+    pub fn column_families(&self) -> Vec<ColumnFamily> {
+        let state = self.lock();
+        state
+            .column_families
+            .iter()
+            .map(|(id, data)| ColumnFamily::new(*id, &data.name))
+            .collect()
+    }
+
+    /// Returns an iterator over all the Key-Value pairs of the database,
+    /// ordered by key
+    ///
+    /// The iterator works on a copy of the data taken when it is created, so
+    /// writes that happen afterwards are not seen
+    pub fn iter(&self) -> Result<DbIterator> {
+        self.iter_with_options(&ReadOptions::default())
+    }
+
+    /// Returns an iterator like [`Db::iter`], with options of the reads.
+    /// Turning off [`ReadOptions::fill_cache`] keeps a bulk scan from
+    /// evicting the working set of the block cache
+    pub fn iter_with_options(&self, options: &ReadOptions) -> Result<DbIterator> {
+        self.iter_column_family(DEFAULT_COLUMN_FAMILY, options)
+    }
+
+    /// Returns an iterator over all the Key-Value pairs of a column family,
+    /// ordered by key
+    pub fn iter_cf(&self, cf: &ColumnFamily) -> Result<DbIterator> {
+        self.iter_column_family(cf.id(), &ReadOptions::default())
+    }
+
+    /// Writes the MemTables of every column family to new tables on disk
+    ///
+    /// The flushed operations are not replayed from the WAL anymore when the
+    /// database is opened.
+    pub fn flush(&self) -> Result<()> {
+        self.lock().flush()
+    }
+
+    /// Flushes the MemTables, and then merges the tables of every column
+    /// family into a single table of the bottommost level
+    ///
+    /// Compaction drops the overwritten, deleted and expired data, and
+    /// collapses the merge operands.
+    pub fn compact(&self) -> Result<()> {
+        let mut state = self.lock();
+        state.flush()?;
+        state.compact()
+    }
+
+    /// Sequence number of the last write applied to the database
+    pub fn last_sequence(&self) -> u64 {
+        self.lock().wal.last_sequence()
+    }
+
+    /// Verifies the checksums of every table and WAL segment of the database,
+    /// returning the paths of the files that are corrupted
+    ///
+    /// Fails only if the files can't be read.
+    pub fn verify_checksums(&self) -> Result<Vec<PathBuf>> {
+        let mut state = self.lock();
+        let mut corrupted = state.wal.verify_checksums()?;
+        let mut tables: Vec<TableFile> = state
+            .column_families
+            .keys()
+            .flat_map(|&id| state.manifest.tables(id))
+            .copied()
+            .collect();
+        tables.sort_by_key(|table| table.file_number);
+        for table in tables {
+            match state
+                .open_table(&table)
+                .and_then(|reader| reader.verify_checksums())
+            {
+                Ok(()) => {}
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                    ) =>
+                {
+                    corrupted.push(table_file_path(&state.dir, table.file_number))
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(corrupted)
+    }
+
+    /// Counters of the use of the row cache, or `None` if it is disabled
+    pub fn row_cache_stats(&self) -> Option<RowCacheStats> {
+        self.lock().row_cache.as_ref().map(RowCache::stats)
+    }
+
+    /// Counters of the use of the block cache, or `None` if it is disabled.
+    /// They cover every database sharing the cache
+    pub fn block_cache_stats(&self) -> Option<CacheStats> {
+        self.lock().block_cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Comparator the keys of the database are ordered with
+    pub(crate) fn comparator(&self) -> Arc<dyn Comparator> {
+        self.lock().comparator.clone()
+    }
+
+    /// Gets the value of a key together with its version, the sequence number
+    /// of the last write to it. Keys that were never written have version 0
+    pub(crate) fn get_with_version(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, u64)> {
+        self.lock()
+            .get_with_version(DEFAULT_COLUMN_FAMILY, key, &ReadOptions::default())
+    }
+
+    /// Applies a batch only if `check` succeeds on the state of the database.
+    /// No other write can happen between the check and the batch being
+    /// applied
+    pub(crate) fn write_if(
+        &self,
+        batch: &WriteBatch,
+        check: impl FnOnce(&DbState) -> Result<()>,
+    ) -> Result<()> {
+        let mut state = self.lock();
+        state.check_batch(batch)?;
+        check(&state)?;
+        state.apply(batch)
+    }
+
+    /// Applies a batch only if the current value of `key` is `expected`, where
+    /// `None` stands for a missing key. Returns whether it was applied
+    fn write_if_value(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        batch: &WriteBatch,
+    ) -> Result<bool> {
+        let mut state = self.lock();
+        let (current, _) =
+            state.get_with_version(DEFAULT_COLUMN_FAMILY, key, &ReadOptions::default())?;
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        state.apply(batch)?;
+        Ok(true)
+    }
+
+    fn iter_column_family(&self, cf: ColumnFamilyId, options: &ReadOptions) -> Result<DbIterator> {
+        let pairs = self.lock().scan(cf, now_micros(), options)?;
+        Ok(DbIterator {
+            pairs: pairs.into_iter(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DbState> {
+        self.inner.lock().expect("database lock poisoned")
+    }
+}
+
+/// Checks that the tables of a database only hold operations up to a recovery
+/// target, failing with [`Error::InvalidArgument`] otherwise
+///
+/// It runs before the WAL is replayed up to the target, so a database that
+/// can't be brought back to it is left untouched.
+fn check_recovery_target(
+    dir: &Path,
+    manifest: &Manifest,
+    compression: &CompressionOptions,
+    target: RecoveryTarget,
+) -> Result<()> {
+    for &id in manifest.column_families().keys() {
+        for table in manifest.tables(id) {
+            let reader = TableReader::open(&table_file_path(dir, table.file_number))?;
+            let mut past_target = reader
+                .range_tombstones()
+                .iter()
+                .any(|tombstone| !target.includes_operation(tombstone.seq, tombstone.timestamp));
+            for (_, handle) in reader.index() {
+                let data = reader.read_data_block(*handle, compression, true)?;
+                past_target |= decode_entries(&data)?
+                    .iter()
+                    .any(|entry| !target.includes_operation(entry.seq, entry.timestamp));
+            }
+            if past_target {
+                return Err(Error::InvalidArgument(format!(
+                    "table {} holds operations past the recovery target {:?}",
+                    table.file_number, target
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An iterator over the Key-Value pairs of a [`Db`], ordered by key with the
+/// comparator of the database
+///
+/// Created by [`Db::iter`] or [`Db::iter_with_options`]
+pub struct DbIterator {
+    pairs: std::vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Iterator for DbIterator {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pairs.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block::BLOCK_TRAILER_SIZE,
+        block_cache::{BlockCacheOptions, EvictionPolicy},
+        compaction_filter::{CompactionFilterContext, FilterDecision},
+        comparator::ReverseBytewiseComparator,
+        compression::{CodecId, CompressionType},
+        manifest::MANIFEST_FILE,
+        merge_operator::{AppendOperator, U64AddOperator},
+        table::{DICTIONARY_BLOCK, TABLE_FILE_EXTENSION},
+        utils::files_with_ext,
+        write_batch::WriteBatchWithIndex,
+    };
+    use rand::Rng;
+    use std::{fs::remove_dir_all, path::PathBuf, thread};
+
+    #[test]
+    fn test_db_set_get_delete() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let db = Db::open(&dir).unwrap();
+        db.set(b"Lime", b"Lime Smoothie").unwrap();
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+        db.delete(b"Apple").unwrap();
+
+        assert_eq!(db.get(b"Lime").unwrap().unwrap(), b"Lime Smoothie");
+        assert_eq!(db.get(b"Apple").unwrap(), None);
+        assert_eq!(db.get(b"Orange").unwrap(), None);
+        assert_eq!(db.last_sequence(), 3);
+        let pairs: Vec<_> = db.iter().unwrap().collect();
+        assert_eq!(pairs, vec![(b"Lime".to_vec(), b"Lime Smoothie".to_vec())]);
+        drop(db);
+
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(db.get(b"Lime").unwrap().unwrap(), b"Lime Smoothie");
+        assert_eq!(db.get(b"Apple").unwrap(), None);
+        assert_eq!(db.last_sequence(), 3);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_write_batch() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let db = Db::open(&dir).unwrap();
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.set(b"Lime", b"Lime Smoothie");
+        batch.delete(b"Apple");
+        batch.set(b"Orange", b"Orange Smoothie");
+        db.write(&batch).unwrap();
+
+        assert_eq!(db.get_with_version(b"Lime").unwrap().1, 2);
+        assert_eq!(db.get_with_version(b"Apple").unwrap(), (None, 3));
+        assert_eq!(db.get_with_version(b"Orange").unwrap().1, 4);
+        assert_eq!(db.get_with_version(b"Banana").unwrap(), (None, 0));
+        drop(db);
+
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(db.get(b"Orange").unwrap().unwrap(), b"Orange Smoothie");
+        assert_eq!(db.get_with_version(b"Orange").unwrap().1, 4);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_conditional_writes() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let db = Db::open(&dir).unwrap();
+        assert!(db.put_if_absent(b"Apple", b"Apple Smoothie").unwrap());
+        assert!(!db.put_if_absent(b"Apple", b"Apple Milkshake").unwrap());
+
+        assert!(!db
+            .compare_and_swap(b"Apple", b"Apple Milkshake", b"Apple Pie")
+            .unwrap());
+        assert!(!db
+            .compare_and_swap(b"Lime", b"Lime Smoothie", b"Lime Pie")
+            .unwrap());
+        assert!(db
+            .compare_and_swap(b"Apple", b"Apple Smoothie", b"Apple Pie")
+            .unwrap());
+
+        assert!(!db.delete_if(b"Apple", b"Apple Smoothie").unwrap());
+        assert!(db.delete_if(b"Apple", b"Apple Pie").unwrap());
+        assert!(db.put_if_absent(b"Lime", b"Lime Smoothie").unwrap());
+        assert_eq!(db.last_sequence(), 4);
+        drop(db);
+
+        // Conditional writes are logged like any other write
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(db.get(b"Apple").unwrap(), None);
+        assert_eq!(db.get(b"Lime").unwrap().unwrap(), b"Lime Smoothie");
+        assert!(db.put_if_absent(b"Apple", b"Apple Smoothie").unwrap());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_compare_and_swap_concurrently() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let db = Db::open(&dir).unwrap();
+        db.set(b"counter", &0u64.to_le_bytes()).unwrap();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..25 {
+                        loop {
+                            let current = db.get(b"counter").unwrap().unwrap();
+                            let counter = u64::from_le_bytes(current.clone().try_into().unwrap());
+                            let new = (counter + 1).to_le_bytes();
+                            if db.compare_and_swap(b"counter", &current, &new).unwrap() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let value = db.get(b"counter").unwrap().unwrap();
+        assert_eq!(u64::from_le_bytes(value.try_into().unwrap()), 100);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_merge() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        let options = DbOptions {
+            merge_operator: Some(Arc::new(U64AddOperator)),
+            ..DbOptions::default()
+        };
+
+        let db = Db::open_with_options(&dir, options.clone()).unwrap();
+        db.merge(b"counter", &1u64.to_le_bytes()).unwrap();
+        db.merge(b"counter", &2u64.to_le_bytes()).unwrap();
+        assert_eq!(db.get(b"counter").unwrap().unwrap(), 3u64.to_le_bytes());
+        db.set(b"counter", &10u64.to_le_bytes()).unwrap();
+        db.merge(b"counter", &5u64.to_le_bytes()).unwrap();
+        assert_eq!(db.get(b"counter").unwrap().unwrap(), 15u64.to_le_bytes());
+        assert!(db
+            .compare_and_swap(b"counter", &15u64.to_le_bytes(), &0u64.to_le_bytes())
+            .unwrap());
+        db.merge(b"counter", &7u64.to_le_bytes()).unwrap();
+        drop(db);
+
+        // Operands are recovered from the WAL
+        let db = Db::open_with_options(&dir, options).unwrap();
+        assert_eq!(db.get(b"counter").unwrap().unwrap(), 7u64.to_le_bytes());
+        let pairs: Vec<_> = db.iter().unwrap().collect();
+        assert_eq!(
+            pairs,
+            vec![(b"counter".to_vec(), 7u64.to_le_bytes().to_vec())]
+        );
+        drop(db);
+
+        // Operands can't be read nor written without a merge operator
+        let db = Db::open(&dir).unwrap();
+        assert!(matches!(db.get(b"counter"), Err(Error::InvalidArgument(_))));
+        assert!(matches!(
+            db.merge(b"counter", b"1"),
+            Err(Error::InvalidArgument(_))
+        ));
+        drop(db);
+
+        let options = DbOptions {
+            merge_operator: Some(Arc::new(AppendOperator::with_delimiter(b","))),
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.delete(b"list");
+        batch.merge(b"list", b"a");
+        batch.merge(b"list", b"b");
+        db.write(&batch).unwrap();
+        assert_eq!(db.get(b"list").unwrap().unwrap(), b"a,b");
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_delete_range() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let db = Db::open(&dir).unwrap();
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+        db.set(b"Lime", b"Lime Smoothie").unwrap();
+        db.set(b"Mango", b"Mango Smoothie").unwrap();
+        db.set(b"Orange", b"Orange Smoothie").unwrap();
+
+        db.delete_range(b"Banana", b"Orange").unwrap();
+        db.set(b"Mango", b"Mango Lassi").unwrap();
+        assert!(matches!(
+            db.delete_range(b"Orange", b"Apple"),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        assert_eq!(db.get(b"Lime").unwrap(), None);
+        assert_eq!(db.get(b"Orange").unwrap().unwrap(), b"Orange Smoothie");
+        let keys: Vec<_> = db.iter().unwrap().map(|(key, _)| key).collect();
+        assert_eq!(
+            keys,
+            vec![b"Apple".to_vec(), b"Mango".to_vec(), b"Orange".to_vec()]
+        );
+        drop(db);
+
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(db.get(b"Lime").unwrap(), None);
+        assert_eq!(db.get(b"Mango").unwrap().unwrap(), b"Mango Lassi");
+        assert_eq!(db.last_sequence(), 6);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_put_with_ttl() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let db = Db::open(&dir).unwrap();
+        db.put_with_ttl(b"session", b"Lime", Duration::from_millis(50))
+            .unwrap();
+        db.put_with_ttl(b"cache", b"Apple", Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(db.get(b"session").unwrap().unwrap(), b"Lime");
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(db.get(b"session").unwrap(), None);
+        assert!(db.put_if_absent(b"session", b"Orange").unwrap());
+        db.put_with_ttl(b"token", b"Mango", Duration::ZERO).unwrap();
+        let keys: Vec<_> = db.iter().unwrap().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![b"cache".to_vec(), b"session".to_vec()]);
+        drop(db);
+
+        // Expiry is kept across restarts
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(db.get(b"cache").unwrap().unwrap(), b"Apple");
+        assert_eq!(db.get(b"session").unwrap().unwrap(), b"Orange");
+        assert_eq!(db.get(b"token").unwrap(), None);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_row_cache() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let options = DbOptions {
+            merge_operator: Some(Arc::new(AppendOperator::with_delimiter(b","))),
+            row_cache_capacity: Some(1024),
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options).unwrap();
+        let users = db
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+        db.set_cf(&users, b"Apple", b"Apple Pie").unwrap();
+        db.merge(b"Lime", b"Lime").unwrap();
+
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"Apple Smoothie");
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"Apple Smoothie");
+        assert_eq!(db.get_cf(&users, b"Apple").unwrap().unwrap(), b"Apple Pie");
+        assert_eq!(db.get(b"Orange").unwrap(), None);
+        assert_eq!(db.get(b"Orange").unwrap(), None);
+        assert_eq!(db.get(b"Lime").unwrap().unwrap(), b"Lime");
+        let stats = db.row_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (2, 4));
+
+        // Every kind of write invalidates the rows it touches
+        db.set(b"Orange", b"Orange Juice").unwrap();
+        db.merge(b"Lime", b"Smoothie").unwrap();
+        db.delete_range(b"A", b"B").unwrap();
+        assert_eq!(db.get(b"Orange").unwrap().unwrap(), b"Orange Juice");
+        assert_eq!(db.get(b"Lime").unwrap().unwrap(), b"Lime,Smoothie");
+        assert_eq!(db.get(b"Apple").unwrap(), None);
+        assert_eq!(db.get_cf(&users, b"Apple").unwrap().unwrap(), b"Apple Pie");
+        assert!(db
+            .compare_and_swap(b"Orange", b"Orange Juice", b"Orange Pie")
+            .unwrap());
+        assert_eq!(db.get(b"Orange").unwrap().unwrap(), b"Orange Pie");
+
+        db.put_with_ttl(b"session", b"Mango", Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(db.get(b"session").unwrap().unwrap(), b"Mango");
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(db.get(b"session").unwrap(), None);
+
+        db.drop_column_family(&users).unwrap();
+        assert!(db.get_cf(&users, b"Apple").is_err());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_verify_checksums() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let db = Db::open(&dir).unwrap();
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+        db.set(b"Lime", b"Lime Smoothie").unwrap();
+        db.flush().unwrap();
+
+        let table_path = files_with_ext(&dir, TABLE_FILE_EXTENSION).pop().unwrap();
+        assert!(db.verify_checksums().unwrap().is_empty());
+
+        let corrupt = |path: &Path, offset: usize| {
+            let mut data = std::fs::read(path).unwrap();
+            data[offset] ^= 0xFF;
+            std::fs::write(path, data).unwrap();
+        };
+        corrupt(&table_path, 2);
+        assert_eq!(db.verify_checksums().unwrap(), vec![table_path.clone()]);
+
+        // Flip a byte in the key of the first write to the WAL
+        let wal_path = files_with_ext(&dir, "wal").pop().unwrap();
+        let data = std::fs::read(&wal_path).unwrap();
+        let offset = data.windows(5).position(|w| w == b"Apple").unwrap();
+        corrupt(&wal_path, offset);
+        assert_eq!(db.verify_checksums().unwrap(), vec![wal_path, table_path]);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_read_without_checksums() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let db = Db::open(&dir).unwrap();
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        // Change the case of a letter of the value in the data block
+        let table_path = files_with_ext(&dir, TABLE_FILE_EXTENSION).pop().unwrap();
+        let mut data = std::fs::read(&table_path).unwrap();
+        let offset = data.windows(8).position(|w| w == b"Smoothie").unwrap();
+        data[offset] = b's';
+        std::fs::write(&table_path, data).unwrap();
+
+        let db = Db::open(&dir).unwrap();
+        let verified = db.get(b"Apple").err().unwrap();
+        assert!(matches!(verified, Error::Io(err) if err.kind() == io::ErrorKind::InvalidData));
+        let unverified = ReadOptions {
+            verify_checksums: false,
+            ..ReadOptions::default()
+        };
+        assert_eq!(
+            db.get_with_options(b"Apple", &unverified).unwrap().unwrap(),
+            b"Apple smoothie"
+        );
+        assert_eq!(db.iter_with_options(&unverified).unwrap().count(), 1);
+        assert!(db.iter().is_err());
+        drop(db);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_flush() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let options = DbOptions {
+            merge_operator: Some(Arc::new(AppendOperator::with_delimiter(b","))),
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options.clone()).unwrap();
+        let users = db
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+        db.set(b"Kiwi", b"Kiwi Smoothie").unwrap();
+        db.set(b"Lime", b"Lime Smoothie").unwrap();
+        db.merge(b"Mango", b"Mango").unwrap();
+        db.set_cf(&users, b"Apple", b"Apple Pie").unwrap();
+        db.flush().unwrap();
+        assert_eq!(files_with_ext(&dir, TABLE_FILE_EXTENSION).len(), 2);
+
+        // Newer writes are read on top of the tables
+        db.delete(b"Apple").unwrap();
+        db.merge(b"Mango", b"Smoothie").unwrap();
+        db.merge(b"Lime", b"Pie").unwrap();
+        db.flush().unwrap();
+        db.delete_range(b"K", b"L").unwrap();
+        db.set(b"Orange", b"Orange Smoothie").unwrap();
+
+        let check = |db: &Db| {
+            assert_eq!(db.get(b"Apple").unwrap(), None);
+            assert_eq!(db.get(b"Kiwi").unwrap(), None);
+            assert_eq!(db.get(b"Lime").unwrap().unwrap(), b"Lime Smoothie,Pie");
+            assert_eq!(db.get(b"Mango").unwrap().unwrap(), b"Mango,Smoothie");
+            assert_eq!(db.get_with_version(b"Mango").unwrap().1, 7);
+            assert_eq!(db.get_cf(&users, b"Apple").unwrap().unwrap(), b"Apple Pie");
+            let keys: Vec<_> = db.iter().unwrap().map(|(key, _)| key).collect();
+            assert_eq!(
+                keys,
+                vec![b"Lime".to_vec(), b"Mango".to_vec(), b"Orange".to_vec()]
+            );
+        };
+        check(&db);
+        drop(db);
+
+        // Flushed operations are not replayed again, so operands are only
+        // merged once
+        let db = Db::open_with_options(&dir, options).unwrap();
+        check(&db);
+        assert_eq!(db.last_sequence(), 10);
+        drop(db);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_compact() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let options = DbOptions {
+            merge_operator: Some(Arc::new(AppendOperator::with_delimiter(b","))),
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options.clone()).unwrap();
+        let users = db
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+        db.set(b"Kiwi", b"Kiwi Smoothie").unwrap();
+        db.merge(b"Lime", b"Lime").unwrap();
+        db.set_cf(&users, b"Apple", b"Apple Pie").unwrap();
+        db.flush().unwrap();
+        db.delete(b"Apple").unwrap();
+        db.merge(b"Lime", b"Smoothie").unwrap();
+        db.flush().unwrap();
+        db.delete_range(b"K", b"L").unwrap();
+        db.set(b"Orange", b"Orange Smoothie").unwrap();
+
+        // The MemTables are flushed, and every column family ends up with a
+        // single table
+        db.compact().unwrap();
+        assert_eq!(files_with_ext(&dir, TABLE_FILE_EXTENSION).len(), 2);
+        for id in [DEFAULT_COLUMN_FAMILY, users.id()] {
+            let tables = db.lock().manifest.tables(id).to_vec();
+            assert_eq!(tables.len(), 1);
+            assert_eq!(tables[0].level, COMPACTION_OUTPUT_LEVEL);
+        }
+
+        let check = |db: &Db| {
+            assert_eq!(db.get(b"Apple").unwrap(), None);
+            assert_eq!(db.get(b"Kiwi").unwrap(), None);
+            assert_eq!(db.get(b"Lime").unwrap().unwrap(), b"Lime,Smoothie");
+            assert_eq!(db.get_cf(&users, b"Apple").unwrap().unwrap(), b"Apple Pie");
+            let pairs: Vec<_> = db.iter().unwrap().collect();
+            assert_eq!(
+                pairs,
+                vec![
+                    (b"Lime".to_vec(), b"Lime,Smoothie".to_vec()),
+                    (b"Orange".to_vec(), b"Orange Smoothie".to_vec()),
+                ]
+            );
+        };
+        check(&db);
+
+        // Deleted data is gone from the table of the bottommost level
+        let tables = db.lock().manifest.tables(DEFAULT_COLUMN_FAMILY).to_vec();
+        let reader = db.lock().open_table(&tables[0]).unwrap();
+        assert!(reader.range_tombstones().is_empty());
+        assert_eq!(
+            db.lock()
+                .read_entries(
+                    &tables[0],
+                    &reader,
+                    reader.index()[0].1,
+                    &ReadOptions::default()
+                )
+                .unwrap()
+                .len(),
+            2
+        );
+        drop(reader);
+        drop(db);
+
+        let db = Db::open_with_options(&dir, options).unwrap();
+        check(&db);
+        drop(db);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    /// Removes the keys of deleted accounts, and hides the email of the
+    /// others
+    struct AccountFilter;
+
+    impl CompactionFilter for AccountFilter {
+        fn name(&self) -> &str {
+            "AccountFilter"
+        }
+
+        fn filter(
+            &self,
+            _context: &CompactionFilterContext,
+            key: &[u8],
+            _value: &[u8],
+            _timestamp: u128,
+        ) -> FilterDecision {
+            if key.starts_with(b"deleted/") {
+                FilterDecision::Remove
+            } else if key.ends_with(b"/email") {
+                FilterDecision::ChangeValue(b"hidden".to_vec())
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn test_db_compaction_filter() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let options = DbOptions {
+            compaction_filter: Some(Arc::new(AccountFilter)),
+            row_cache_capacity: Some(1024),
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options.clone()).unwrap();
+        let users = db
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        db.set(b"alice/email", b"alice@example.com").unwrap();
+        db.set(b"alice/name", b"Alice").unwrap();
+        db.set(b"deleted/bob", b"Bob").unwrap();
+        db.set_cf(&users, b"deleted/bob", b"Bob").unwrap();
+        db.flush().unwrap();
+
+        // The rows cached before the compaction are invalidated
+        assert_eq!(
+            db.get(b"alice/email").unwrap().unwrap(),
+            b"alice@example.com"
+        );
+        assert_eq!(db.get(b"deleted/bob").unwrap().unwrap(), b"Bob");
+        db.compact().unwrap();
+        let check = |db: &Db| {
+            assert_eq!(db.get(b"alice/email").unwrap().unwrap(), b"hidden");
+            assert_eq!(db.get(b"alice/name").unwrap().unwrap(), b"Alice");
+            assert_eq!(db.get(b"deleted/bob").unwrap(), None);
+            // The filter only applies to the default column family
+            assert_eq!(db.get_cf(&users, b"deleted/bob").unwrap().unwrap(), b"Bob");
+        };
+        check(&db);
+        drop(db);
+
+        let db = Db::open_with_options(&dir, options).unwrap();
+        check(&db);
+        drop(db);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_compression_per_level() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        // Tables are left uncompressed, except in the bottommost level
+        let options = DbOptions {
+            compression: CompressionOptions {
+                per_level: vec![Arc::new(CompressionType::None)],
+                bottommost: Some(Arc::new(CompressionType::Lz4)),
+                ..CompressionOptions::default()
+            },
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options.clone()).unwrap();
+        let value = |id: u32| format!(r#"{{"id": {}, "fruit": "Lime", "smoothie": true}}"#, id);
+        for id in 0..200 {
+            db.set(format!("user/{:03}", id).as_bytes(), value(id).as_bytes())
+                .unwrap();
+        }
+        db.compact().unwrap();
+        for id in 200..400 {
+            db.set(format!("user/{:03}", id).as_bytes(), value(id).as_bytes())
+                .unwrap();
+        }
+        db.flush().unwrap();
+
+        // Codec recorded in the data blocks of each table, by level
+        let codecs = |db: &Db| -> Vec<(usize, Vec<CodecId>)> {
+            let state = db.lock();
+            let tables = state.manifest.tables(DEFAULT_COLUMN_FAMILY).to_vec();
+            tables
+                .iter()
+                .map(|table| {
+                    let reader = state.open_table(table).unwrap();
+                    let mut codecs: Vec<CodecId> = reader
+                        .index()
+                        .iter()
+                        .map(|(_, handle)| {
+                            let block = reader.read_block(*handle, true).unwrap();
+                            block[block.len() - BLOCK_TRAILER_SIZE]
+                        })
+                        .collect();
+                    codecs.dedup();
+                    (table.level, codecs)
+                })
+                .collect()
+        };
+        assert_eq!(
+            codecs(&db),
+            vec![
+                (0, vec![CompressionType::None as CodecId]),
+                (1, vec![CompressionType::Lz4 as CodecId]),
+            ]
+        );
+        drop(db);
+
+        // Both tables stay readable with the default options
+        let db = Db::open(&dir).unwrap();
+        for id in [0, 199, 200, 399] {
+            assert_eq!(
+                db.get(format!("user/{:03}", id).as_bytes())
+                    .unwrap()
+                    .unwrap(),
+                value(id).as_bytes()
+            );
+        }
+        assert_eq!(db.iter().unwrap().count(), 400);
+        drop(db);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_compression_dictionary() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let options = DbOptions {
+            compression: CompressionOptions {
+                bottommost: Some(Arc::new(CompressionType::Lz4)),
+                max_dict_bytes: 256,
+                ..CompressionOptions::default()
+            },
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options.clone()).unwrap();
+        let value = |id: u32| format!(r#"{{"id": {}, "fruit": "Lime", "smoothie": true}}"#, id);
+        for id in 0..200 {
+            db.set(format!("user/{:03}", id).as_bytes(), value(id).as_bytes())
+                .unwrap();
+        }
+
+        // Only the table written by the compaction has a dictionary
+        let has_dictionary = |db: &Db| -> Vec<bool> {
+            let state = db.lock();
+            let tables = state.manifest.tables(DEFAULT_COLUMN_FAMILY).to_vec();
+            tables
+                .iter()
+                .map(|table| {
+                    let reader = state.open_table(table).unwrap();
+                    reader.meta_block(DICTIONARY_BLOCK).is_some()
+                })
+                .collect()
+        };
+        db.flush().unwrap();
+        assert_eq!(has_dictionary(&db), vec![false]);
+        db.compact().unwrap();
+        assert_eq!(has_dictionary(&db), vec![true]);
+        drop(db);
+
+        let db = Db::open_with_options(&dir, options).unwrap();
+        for id in [0, 7, 199] {
+            assert_eq!(
+                db.get(format!("user/{:03}", id).as_bytes())
+                    .unwrap()
+                    .unwrap(),
+                value(id).as_bytes()
+            );
+        }
+        assert_eq!(db.iter().unwrap().count(), 200);
+        drop(db);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_recovery_target() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let options = DbOptions {
+            comparator: Arc::new(ReverseBytewiseComparator),
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options.clone()).unwrap();
+        let users = db
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+        db.set_cf(&users, b"Apple", b"Apple Pie").unwrap();
+        db.flush().unwrap();
+        db.set_cf(&users, b"Lime", b"Lime Pie").unwrap();
+        let target = db.last_sequence();
+        db.set(b"Apple", b"Garbage").unwrap();
+        db.set_cf(&users, b"Kiwi", b"Garbage").unwrap();
+        drop(db);
+
+        // The tables can't be brought back before their last operation, and
+        // the WAL is left untouched
+        for target in [RecoveryTarget::Sequence(1), RecoveryTarget::Timestamp(0)] {
+            let options = DbOptions {
+                recovery_target: Some(target),
+                ..options.clone()
+            };
+            let err = Db::open_with_options(&dir, options).err().unwrap();
+            assert!(matches!(err, Error::InvalidArgument(_)));
+        }
+        let db = Db::open_with_options(&dir, options.clone()).unwrap();
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"Garbage");
+        drop(db);
+
+        let check = |db: &Db| {
+            assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"Apple Smoothie");
+            let users = db.column_family("users").unwrap();
+            let pairs: Vec<_> = db.iter_cf(&users).unwrap().collect();
+            assert_eq!(
+                pairs,
+                vec![
+                    (b"Lime".to_vec(), b"Lime Pie".to_vec()),
+                    (b"Apple".to_vec(), b"Apple Pie".to_vec()),
+                ]
+            );
+            assert_eq!(db.last_sequence(), target);
+        };
+        let recovery_options = DbOptions {
+            recovery_target: Some(RecoveryTarget::Sequence(target)),
+            ..options.clone()
+        };
+        let db = Db::open_with_options(&dir, recovery_options).unwrap();
+        check(&db);
+        drop(db);
+
+        // The operations after the target are gone for good
+        let db = Db::open_with_options(&dir, options).unwrap();
+        check(&db);
+        drop(db);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_flush_deletes_wal_segments() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let options = DbOptions {
+            wal: WalOptions {
+                max_segment_size: 100,
+                ..WalOptions::default()
+            },
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options.clone()).unwrap();
+        for i in 0..10u32 {
+            db.set(&i.to_be_bytes(), b"Lime Smoothie").unwrap();
+        }
+        assert!(files_with_ext(&dir, "wal").len() > 1);
+
+        // Only the active segment is left once everything is flushed
+        db.flush().unwrap();
+        assert_eq!(files_with_ext(&dir, "wal").len(), 1);
+        drop(db);
+
+        // The sequence numbers continue after the flushed operations, even
+        // with no segment left holding them
+        for _ in 0..2 {
+            let db = Db::open_with_options(&dir, options.clone()).unwrap();
+            assert_eq!(db.last_sequence(), 10);
+        }
+        assert_eq!(files_with_ext(&dir, "wal").len(), 1);
+        let db = Db::open_with_options(&dir, options).unwrap();
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+        assert_eq!(db.get_with_version(b"Apple").unwrap().1, 11);
+        assert_eq!(
+            db.get(&9u32.to_be_bytes()).unwrap().unwrap(),
+            b"Lime Smoothie"
+        );
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_max_open_files() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let options = DbOptions {
+            max_open_files: 2,
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options).unwrap();
+        for fruit in ["Apple", "Kiwi", "Lime", "Mango"] {
+            db.set(fruit.as_bytes(), b"Smoothie").unwrap();
+            db.flush().unwrap();
+        }
+
+        // Every table is read, but only two of them are kept open
+        for fruit in ["Apple", "Kiwi", "Lime", "Mango"] {
+            assert_eq!(db.get(fruit.as_bytes()).unwrap().unwrap(), b"Smoothie");
+        }
+        assert_eq!(db.iter().unwrap().count(), 4);
+        assert_eq!(db.lock().table_cache.len(), 2);
+        assert!(db.verify_checksums().unwrap().is_empty());
+        assert_eq!(db.lock().table_cache.len(), 2);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_block_cache() {
+        let mut rng = rand::thread_rng();
+        let dir_1 = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        let dir_2 = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let cache = Arc::new(BlockCache::new(BlockCacheOptions {
+            policy: EvictionPolicy::Clock,
+            ..BlockCacheOptions::default()
+        }));
+        let options = DbOptions {
+            block_cache: Some(cache.clone()),
+            ..DbOptions::default()
+        };
+        let db_1 = Db::open_with_options(&dir_1, options.clone()).unwrap();
+        let db_2 = Db::open_with_options(&dir_2, options).unwrap();
+        assert_eq!(
+            Db::open(&dir_1.join("other")).unwrap().block_cache_stats(),
+            None
+        );
+
+        // Both databases write their table to file number 1
+        db_1.set(b"Apple", b"Pie").unwrap();
+        db_1.flush().unwrap();
+        db_2.set(b"Apple", b"Juice").unwrap();
+        db_2.flush().unwrap();
+
+        // The first reads load the index and data blocks into the cache
+        assert_eq!(db_1.get(b"Apple").unwrap().unwrap(), b"Pie");
+        assert_eq!(db_2.get(b"Apple").unwrap().unwrap(), b"Juice");
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (0, 4));
+
+        // The next ones find the data blocks of their own database
+        assert_eq!(db_1.get(b"Apple").unwrap().unwrap(), b"Pie");
+        assert_eq!(db_2.get(b"Apple").unwrap().unwrap(), b"Juice");
+        let stats = db_1.block_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (2, 4));
+        assert!(stats.usage > 0);
+
+        remove_dir_all(&dir_1).unwrap();
+        remove_dir_all(&dir_2).unwrap();
+    }
+
+    #[test]
+    fn test_db_fill_cache() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let options = DbOptions {
+            block_cache: Some(Arc::new(BlockCache::new(BlockCacheOptions::default()))),
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options).unwrap();
+        for fruit in ["Apple", "Kiwi", "Lime"] {
+            db.set(fruit.as_bytes(), b"Smoothie").unwrap();
+        }
+        db.flush().unwrap();
+
+        // Opening the table caches its index block, but the scans skipping the
+        // cache read the data block from disk every time
+        let no_fill = ReadOptions {
+            fill_cache: false,
+            ..ReadOptions::default()
+        };
+        assert_eq!(db.iter_with_options(&no_fill).unwrap().count(), 3);
+        let index_usage = db.block_cache_stats().unwrap().usage;
+        assert!(index_usage > 0);
+        assert_eq!(db.iter_with_options(&no_fill).unwrap().count(), 3);
+        assert_eq!(
+            db.get_with_options(b"Kiwi", &no_fill).unwrap().unwrap(),
+            b"Smoothie"
+        );
+        let stats = db.block_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.usage), (0, 4, index_usage));
+
+        // Scans filling the cache find the data block afterwards
+        assert_eq!(db.iter().unwrap().count(), 3);
+        assert_eq!(db.get(b"Kiwi").unwrap().unwrap(), b"Smoothie");
+        let stats = db.block_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (1, 5));
+        assert!(stats.usage > index_usage);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_pin_l0_index_blocks() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let options = DbOptions {
+            block_cache: Some(Arc::new(BlockCache::new(BlockCacheOptions {
+                pin_l0_index_and_filter_blocks: true,
+                ..BlockCacheOptions::default()
+            }))),
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options).unwrap();
+        db.set(b"Apple", b"Pie").unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.block_cache_stats().unwrap().pinned_usage, 0);
+
+        // Only the index block of the L0 table is pinned, not its data block
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"Pie");
+        let stats = db.block_cache_stats().unwrap();
+        assert!(stats.pinned_usage > 0);
+        assert!(stats.usage > stats.pinned_usage);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_column_families() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let db = Db::open(&dir).unwrap();
+        let users = db
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        let counters_options = ColumnFamilyOptions {
+            merge_operator: Some(Arc::new(U64AddOperator)),
+            ..ColumnFamilyOptions::default()
+        };
+        let counters = db
+            .create_column_family("counters", counters_options.clone())
+            .unwrap();
+        assert!(matches!(
+            db.create_column_family("users", ColumnFamilyOptions::default()),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        // The same key lives independently in every column family
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+        db.set_cf(&users, b"Apple", b"Apple Pie").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.delete(b"Apple");
+        batch.merge_cf(&counters, b"Apple", &1u64.to_le_bytes());
+        batch.set_cf(&users, b"Lime", b"Lime Pie");
+        db.write(&batch).unwrap();
+        assert!(matches!(
+            db.merge(b"Apple", &1u64.to_le_bytes()),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        assert_eq!(db.get(b"Apple").unwrap(), None);
+        assert_eq!(db.get_cf(&users, b"Apple").unwrap().unwrap(), b"Apple Pie");
+        let keys: Vec<_> = db.iter_cf(&users).unwrap().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![b"Apple".to_vec(), b"Lime".to_vec()]);
+        drop(db);
+
+        let options = DbOptions {
+            column_families: HashMap::from([("counters".to_owned(), counters_options)]),
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options.clone()).unwrap();
+        let names: Vec<_> = db
+            .column_families()
+            .iter()
+            .map(|cf| cf.name().to_owned())
+            .collect();
+        assert_eq!(names, vec!["default", "users", "counters"]);
+        assert_eq!(db.column_family("users").unwrap(), users);
+        assert_eq!(db.get_cf(&users, b"Lime").unwrap().unwrap(), b"Lime Pie");
+        assert_eq!(
+            db.get_cf(&counters, b"Apple").unwrap().unwrap(),
+            1u64.to_le_bytes()
+        );
+
+        db.drop_column_family(&users).unwrap();
+        let default = db.column_family("default").unwrap();
+        assert!(matches!(
+            db.drop_column_family(&default),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            db.set_cf(&users, b"Apple", b"Apple Pie"),
+            Err(Error::InvalidArgument(_))
+        ));
+        drop(db);
+
+        // Dropped column families stay dropped, and their data is gone
+        let db = Db::open_with_options(&dir, options).unwrap();
+        assert_eq!(db.column_family("users"), None);
+        assert!(db.get_cf(&users, b"Lime").is_err());
+        let users = db
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        assert_eq!(users.id(), 3);
+        assert_eq!(db.get_cf(&users, b"Lime").unwrap(), None);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_comparator() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        let options = DbOptions {
+            comparator: Arc::new(ReverseBytewiseComparator),
+            row_cache_capacity: Some(1024),
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options.clone()).unwrap();
+        for key in [b"Apple", b"Lemon", b"Mango", b"Peach"] {
+            db.set(key, b"Smoothie").unwrap();
+        }
+        assert_eq!(db.get(b"Lemon").unwrap().unwrap(), b"Smoothie");
+
+        // Ranges run from the largest key to the smallest
+        assert!(matches!(
+            db.delete_range(b"A", b"M"),
+            Err(Error::InvalidArgument(_))
+        ));
+        db.delete_range(b"M", b"B").unwrap();
+        assert_eq!(db.get(b"Lemon").unwrap(), None);
+        let keys: Vec<_> = db.iter().unwrap().map(|(key, _)| key).collect();
+        assert_eq!(
+            keys,
+            vec![b"Peach".to_vec(), b"Mango".to_vec(), b"Apple".to_vec()]
+        );
+
+        let mut batch = WriteBatchWithIndex::new();
+        batch.set(b"Kiwi", b"Smoothie");
+        batch.delete(b"Mango");
+        let keys: Vec<_> = batch
+            .iter_with_db(&db)
+            .unwrap()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![b"Peach".to_vec(), b"Kiwi".to_vec(), b"Apple".to_vec()]
+        );
+        drop(db);
+
+        // The data can only be read back in the order it was written with
+        let err = Db::open(&dir).err().unwrap();
+        assert!(matches!(err, Error::InvalidArgument(_)));
+        let db = Db::open_with_options(&dir, options).unwrap();
+        let keys: Vec<_> = db.iter().unwrap().map(|(key, _)| key).collect();
+        assert_eq!(
+            keys,
+            vec![b"Peach".to_vec(), b"Mango".to_vec(), b"Apple".to_vec()]
+        );
+        drop(db);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_db_comparator_old_manifest() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+
+        // A manifest written before comparators were recorded
+        create_dir_all(&dir).unwrap();
+        let mut manifest = Manifest::open(&dir).unwrap();
+        manifest.add_column_family("users").unwrap();
+        drop(manifest);
+        let path = dir.join(MANIFEST_FILE);
+        let mut data = std::fs::read(&path).unwrap();
+        data[8..12].copy_from_slice(&1u32.to_le_bytes());
+        std::fs::write(&path, data).unwrap();
+
+        // Its keys were ordered bytewise, so no other comparator can open it
+        let options = DbOptions {
+            comparator: Arc::new(ReverseBytewiseComparator),
+            ..DbOptions::default()
+        };
+        let err = Db::open_with_options(&dir, options.clone()).err().unwrap();
+        assert!(matches!(err, Error::InvalidArgument(_)));
+        let db = Db::open(&dir).unwrap();
+        assert!(db.column_family("users").is_some());
+        drop(db);
+        assert_eq!(
+            Manifest::open(&dir).unwrap().comparator(),
+            Some(BytewiseComparator.name())
+        );
+        assert!(Db::open_with_options(&dir, options).is_err());
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+//! Training of compression dictionaries from sampled values.
+//!
+//! Small values compress poorly on their own, since a codec finds few
+//! repetitions in a single value. When a compaction writes a table, it samples
+//! the values it outputs and trains a dictionary with the content they have in
+//! common. The dictionary is stored in a meta block of the table and shared by
+//! all its data blocks, letting each block refer to the content of the
+//! dictionary as if it preceded the block.
+//!
+//! Training keeps the fragments of the samples covering the most substrings
+//! that appear in many values, in the spirit of the COVER algorithm of Zstd.
+
+#![allow(dead_code)]
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+/// Length of the substrings counted to score fragments
+const KMER_SIZE: usize = 8;
+
+/// Length of the fragments of the samples the dictionary is made of
+const SEGMENT_SIZE: usize = 32;
+
+/// Distance between the starts of two candidate fragments of a sample
+const SEGMENT_STEP: usize = 8;
+
+/// Collects a sample of the values written to a table, spread over the whole
+/// table and bounded in size
+///
+/// Every `stride`-th value is kept. Once the sample is full, every other value
+/// kept is dropped and the stride doubles.
+#[derive(Debug)]
+pub struct DictionarySampler {
+    max_train_bytes: usize,
+    samples: Vec<Vec<u8>>,
+    sampled_bytes: usize,
+    stride: u64,
+    /// Values seen since the last one kept
+    skipped: u64,
+}
+
+impl DictionarySampler {
+    /// Creates a sampler keeping up to `max_train_bytes` bytes of values
+    pub fn new(max_train_bytes: usize) -> Self {
+        Self {
+            max_train_bytes,
+            samples: Vec::new(),
+            sampled_bytes: 0,
+            stride: 1,
+            skipped: 0,
+        }
+    }
+
+    /// Offers a value written to the table to the sample
+    pub fn add(&mut self, value: &[u8]) {
+        if value.is_empty() || value.len() > self.max_train_bytes {
+            return;
+        }
+        self.skipped += 1;
+        if self.skipped < self.stride {
+            return;
+        }
+        self.skipped = 0;
+
+        while self.sampled_bytes + value.len() > self.max_train_bytes {
+            let mut index = 0;
+            self.samples.retain(|_| {
+                index += 1;
+                index % 2 == 0
+            });
+            self.sampled_bytes = self.samples.iter().map(Vec::len).sum();
+            self.stride *= 2;
+        }
+        self.sampled_bytes += value.len();
+        self.samples.push(value.to_vec());
+    }
+
+    /// Values sampled so far
+    pub fn samples(&self) -> &[Vec<u8>] {
+        &self.samples
+    }
+
+    /// Trains a dictionary of up to `max_dict_bytes` bytes from the sample
+    ///
+    /// Returns `None` if the values have nothing in common to put in it.
+    pub fn train(&self, max_dict_bytes: usize) -> Option<Vec<u8>> {
+        Some(train_dictionary(&self.samples, max_dict_bytes)).filter(|dict| !dict.is_empty())
+    }
+}
+
+/// Builds a dictionary of up to `max_dict_bytes` bytes from fragments of the
+/// samples
+///
+/// Fragments are scored by how many samples contain each substring they hold,
+/// counting only substrings found in at least two samples and not already in
+/// the dictionary. The best fragment is added until the dictionary is full.
+/// Codecs reach the end of the dictionary more cheaply, or only its end when
+/// it is large, so the best fragments are placed last.
+pub fn train_dictionary(samples: &[Vec<u8>], max_dict_bytes: usize) -> Vec<u8> {
+    let mut frequencies: HashMap<&[u8], u32> = HashMap::new();
+    for sample in samples {
+        let kmers: HashSet<&[u8]> = sample.windows(KMER_SIZE).collect();
+        for kmer in kmers {
+            *frequencies.entry(kmer).or_default() += 1;
+        }
+    }
+    frequencies.retain(|_, frequency| *frequency >= 2);
+
+    let segments: Vec<&[u8]> = samples
+        .iter()
+        .flat_map(|sample| {
+            (0..sample.len().saturating_sub(KMER_SIZE - 1))
+                .step_by(SEGMENT_STEP)
+                .map(|start| &sample[start..sample.len().min(start + SEGMENT_SIZE)])
+        })
+        .collect();
+
+    let mut covered: HashSet<&[u8]> = HashSet::new();
+    let score = |segment: &[u8], covered: &HashSet<&[u8]>| -> u64 {
+        let kmers: HashSet<&[u8]> = segment.windows(KMER_SIZE).collect();
+        kmers
+            .into_iter()
+            .filter(|kmer| !covered.contains(kmer))
+            .filter_map(|kmer| frequencies.get(kmer))
+            .map(|&frequency| frequency as u64)
+            .sum()
+    };
+
+    // Scores only decrease as the dictionary grows, so a fragment whose score
+    // is still the best after being recomputed is the best fragment
+    let mut heap: BinaryHeap<(u64, Reverse<usize>)> = segments
+        .iter()
+        .enumerate()
+        .map(|(index, segment)| (score(segment, &covered), Reverse(index)))
+        .filter(|(score, _)| *score > 0)
+        .collect();
+    let mut selected = Vec::new();
+    let mut size = 0;
+    while let Some((old_score, Reverse(index))) = heap.pop() {
+        if size >= max_dict_bytes {
+            break;
+        }
+        let segment = segments[index];
+        let new_score = score(segment, &covered);
+        if new_score < old_score {
+            if new_score > 0 {
+                heap.push((new_score, Reverse(index)));
+            }
+            continue;
+        }
+
+        covered.extend(segment.windows(KMER_SIZE));
+        size += segment.len();
+        selected.push(segment);
+    }
+
+    let mut dict: Vec<u8> = selected.into_iter().rev().flatten().copied().collect();
+    if dict.len() > max_dict_bytes {
+        dict.drain(..dict.len() - max_dict_bytes);
+    }
+    dict
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::CompressionType;
+
+    fn user(id: u32) -> Vec<u8> {
+        format!(
+            r#"{{"id": {}, "name": "user{}", "email": "user{}@example.com", "active": {}, "plan": "{}"}}"#,
+            id,
+            id * 7,
+            id * 13,
+            id.is_multiple_of(2),
+            ["free", "premium", "enterprise"][id as usize % 3]
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_dictionary_improves_compression() {
+        let mut sampler = DictionarySampler::new(4096);
+        for id in 0..1000 {
+            sampler.add(&user(id));
+        }
+        let dict = sampler.train(512).unwrap();
+        assert!(dict.len() <= 512);
+
+        let values: Vec<Vec<u8>> = (1000..1100).map(user).collect();
+        let size = |compress: &dyn Fn(&[u8]) -> Vec<u8>| -> usize {
+            values.iter().map(|value| compress(value).len()).sum()
+        };
+        let without_dict = size(&|value| CompressionType::Lz4.compress(value));
+        let with_dict = size(&|value| CompressionType::Lz4.compress_with_dict(value, &dict));
+        assert!(with_dict * 2 < without_dict);
+
+        for value in &values {
+            let compressed = CompressionType::Lz4.compress_with_dict(value, &dict);
+            assert_eq!(
+                &CompressionType::Lz4
+                    .decompress_with_dict(&compressed, &dict)
+                    .unwrap(),
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_dictionary_sampler_bounds_sample() {
+        let mut sampler = DictionarySampler::new(1000);
+        for id in 0..10_000 {
+            sampler.add(&user(id));
+        }
+        let sampled: usize = sampler.samples().iter().map(Vec::len).sum();
+        assert!(sampled <= 1000);
+        assert!(sampled > 500);
+        // The sample spreads over all the values, not only the first ones
+        assert!((5000..10_000).any(|id| sampler.samples().contains(&user(id))));
+
+        // Values with nothing in common don't make a dictionary
+        let mut sampler = DictionarySampler::new(1000);
+        sampler.add(b"Lime Smoothie");
+        sampler.add(b"Apple Pie");
+        assert_eq!(sampler.train(512), None);
+    }
+}
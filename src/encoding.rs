@@ -0,0 +1,125 @@
+//! Portable encoding of integers for the data written to disk.
+//!
+//! Variable length integers (varints) use 7 bits per byte, least significant
+//! group first, with the high bit of each byte set when more bytes follow.
+//! Small values such as the length of short keys take a single byte.
+
+/// Maximum number of bytes of an encoded `u64` varint
+pub const MAX_VARINT_LEN: usize = 10;
+
+/// Appends `value` to `buffer` as a varint
+pub fn put_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// Reads a varint from the start of `buffer`, advancing it past the value
+///
+/// Returns `None` if the buffer ends before the varint does, or if the value
+/// overflows a `u64`
+pub fn get_varint(buffer: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, byte) in buffer.iter().enumerate().take(MAX_VARINT_LEN) {
+        let bits = (byte & 0x7F) as u64;
+        if i == MAX_VARINT_LEN - 1 && bits > 1 {
+            return None;
+        }
+
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            *buffer = &buffer[i + 1..];
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Reads a little endian `u64` from the start of `buffer`, advancing it past
+/// the value
+pub fn get_fixed_u64(buffer: &mut &[u8]) -> Option<u64> {
+    let bytes = buffer.get(..8)?;
+    let value = u64::from_le_bytes(bytes.try_into().unwrap());
+    *buffer = &buffer[8..];
+    Some(value)
+}
+
+/// Reads `len` bytes from the start of `buffer`, advancing it past them
+pub fn get_bytes<'a>(buffer: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let bytes = buffer.get(..len)?;
+    *buffer = &buffer[len..];
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_round_trip() {
+        let values = [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16_383,
+            16_384,
+            u32::MAX as u64,
+            u64::MAX,
+        ];
+
+        let mut buffer = Vec::new();
+        for value in values {
+            put_varint(&mut buffer, value);
+        }
+
+        let mut reader = buffer.as_slice();
+        for value in values {
+            assert_eq!(get_varint(&mut reader), Some(value));
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_varint_sizes() {
+        let mut buffer = Vec::new();
+        put_varint(&mut buffer, 5);
+        assert_eq!(buffer, vec![5]);
+
+        buffer.clear();
+        put_varint(&mut buffer, 300);
+        assert_eq!(buffer, vec![0xAC, 0x02]);
+
+        buffer.clear();
+        put_varint(&mut buffer, u64::MAX);
+        assert_eq!(buffer.len(), MAX_VARINT_LEN);
+    }
+
+    #[test]
+    fn test_varint_invalid() {
+        // Truncated
+        assert_eq!(get_varint(&mut [0x80, 0x80].as_slice()), None);
+        assert_eq!(get_varint(&mut [].as_slice()), None);
+
+        // Overflows a u64
+        let mut buffer = vec![0xFF; 9];
+        buffer.push(0x02);
+        assert_eq!(get_varint(&mut buffer.as_slice()), None);
+    }
+
+    #[test]
+    fn test_get_fixed_and_bytes() {
+        let mut buffer = 42u64.to_le_bytes().to_vec();
+        buffer.extend_from_slice(b"Lime");
+
+        let mut reader = buffer.as_slice();
+        assert_eq!(get_fixed_u64(&mut reader), Some(42));
+        assert_eq!(get_bytes(&mut reader, 5), None);
+        assert_eq!(get_bytes(&mut reader, 4), Some(b"Lime".as_slice()));
+        assert_eq!(get_fixed_u64(&mut reader), None);
+    }
+}
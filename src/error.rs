@@ -0,0 +1,53 @@
+//! Errors returned by the database.
+
+#![allow(dead_code)]
+
+use std::{fmt, io};
+
+/// Error of a database operation
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing the files of the database failed
+    Io(io::Error),
+    /// A transaction could not be committed because a key it used was changed
+    /// by someone else since the transaction started. Retrying the transaction
+    /// may succeed
+    Conflict,
+    /// A transaction waited too long for the lock of a key
+    LockTimeout,
+    /// A transaction was aborted because waiting for the lock of a key would
+    /// deadlock with other transactions. Retrying the transaction may succeed
+    Deadlock,
+    /// The operation is not valid with the given arguments or in the current
+    /// state
+    InvalidArgument(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "IO error: {}", err),
+            Error::Conflict => write!(f, "transaction conflict"),
+            Error::LockTimeout => write!(f, "timed out waiting for a lock"),
+            Error::Deadlock => write!(f, "deadlock detected"),
+            Error::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
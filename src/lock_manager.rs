@@ -0,0 +1,274 @@
+//! Per key locks used by pessimistic transactions.
+//!
+//! Keys can be locked shared, by any number of transactions that only read
+//! them, or exclusive, by a single transaction that writes them. A transaction
+//! that can not get a lock waits until the holders release it or the lock
+//! timeout expires.
+//!
+//! While a transaction waits, the lock manager records which transactions it
+//! waits for. Those edges form the wait-for graph, and a cycle in it is a
+//! deadlock that no timeout would resolve quickly. Before waiting, the
+//! requesting transaction checks if it closes a cycle, and if so it is chosen
+//! as the victim and fails with [`Error::Deadlock`] instead.
+
+#![allow(dead_code)]
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::error::{Error, Result};
+
+/// Identifier of a transaction holding or waiting for locks
+pub type TxnId = u64;
+
+/// How a key is locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Compatible with other shared locks, used to read keys
+    Shared,
+    /// Incompatible with any other lock, used to write keys
+    Exclusive,
+}
+
+/// Holders of the lock of a key
+#[derive(Default)]
+struct KeyLock {
+    exclusive: Option<TxnId>,
+    shared: HashSet<TxnId>,
+}
+
+impl KeyLock {
+    /// Transactions other than `txn` that prevent it from locking the key in
+    /// `mode`
+    fn blockers(&self, txn: TxnId, mode: LockMode) -> HashSet<TxnId> {
+        let mut blockers: HashSet<TxnId> = self.exclusive.iter().copied().collect();
+        if mode == LockMode::Exclusive {
+            blockers.extend(self.shared.iter().copied());
+        }
+        blockers.remove(&txn);
+        blockers
+    }
+
+    fn is_free(&self) -> bool {
+        self.exclusive.is_none() && self.shared.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct LockState {
+    next_txn: TxnId,
+    locks: HashMap<Vec<u8>, KeyLock>,
+    /// Keys locked by every transaction
+    held: HashMap<TxnId, HashSet<Vec<u8>>>,
+    /// Wait-for graph: transactions every waiting transaction waits for
+    waits_for: HashMap<TxnId, HashSet<TxnId>>,
+}
+
+impl LockState {
+    /// Returns true if following the wait-for graph from `from` reaches `to`
+    fn reaches(&self, from: &HashSet<TxnId>, to: TxnId) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack: Vec<TxnId> = from.iter().copied().collect();
+        while let Some(txn) = stack.pop() {
+            if txn == to {
+                return true;
+            }
+            if visited.insert(txn) {
+                if let Some(next) = self.waits_for.get(&txn) {
+                    stack.extend(next.iter().copied());
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Grants and releases the locks of keys to transactions
+#[derive(Default)]
+pub struct LockManager {
+    state: Mutex<LockState>,
+    /// Notified every time locks are released
+    released: Condvar,
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocates the identifier of a new transaction
+    pub fn new_txn(&self) -> TxnId {
+        let mut state = self.lock_state();
+        state.next_txn += 1;
+        state.next_txn
+    }
+
+    /// Locks a key for a transaction, waiting up to `timeout` for the holders
+    /// to release it
+    ///
+    /// Locking a key the transaction already holds is a no-op, unless a shared
+    /// lock is upgraded to exclusive. Fails with [`Error::LockTimeout`] if the
+    /// lock was not granted in time, or [`Error::Deadlock`] if waiting would
+    /// deadlock.
+    pub fn lock(&self, txn: TxnId, key: &[u8], mode: LockMode, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock_state();
+        loop {
+            let blockers = state
+                .locks
+                .get(key)
+                .map(|lock| lock.blockers(txn, mode))
+                .unwrap_or_default();
+            if blockers.is_empty() {
+                state.waits_for.remove(&txn);
+                let lock = state.locks.entry(key.to_owned()).or_default();
+                match mode {
+                    LockMode::Shared if lock.exclusive != Some(txn) => {
+                        lock.shared.insert(txn);
+                    }
+                    LockMode::Shared => {}
+                    LockMode::Exclusive => {
+                        lock.shared.remove(&txn);
+                        lock.exclusive = Some(txn);
+                    }
+                }
+                state.held.entry(txn).or_default().insert(key.to_owned());
+                return Ok(());
+            }
+
+            if state.reaches(&blockers, txn) {
+                state.waits_for.remove(&txn);
+                return Err(Error::Deadlock);
+            }
+            state.waits_for.insert(txn, blockers);
+
+            let now = Instant::now();
+            if now >= deadline {
+                state.waits_for.remove(&txn);
+                return Err(Error::LockTimeout);
+            }
+            state = self
+                .released
+                .wait_timeout(state, deadline - now)
+                .expect("lock manager lock poisoned")
+                .0;
+        }
+    }
+
+    /// Releases all the locks held by a transaction
+    pub fn unlock_all(&self, txn: TxnId) {
+        let mut state = self.lock_state();
+        state.waits_for.remove(&txn);
+        let Some(keys) = state.held.remove(&txn) else {
+            return;
+        };
+        for key in keys {
+            if let Some(lock) = state.locks.get_mut(&key) {
+                if lock.exclusive == Some(txn) {
+                    lock.exclusive = None;
+                }
+                lock.shared.remove(&txn);
+                if lock.is_free() {
+                    state.locks.remove(&key);
+                }
+            }
+        }
+        self.released.notify_all();
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, LockState> {
+        self.state.lock().expect("lock manager lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn test_shared_locks_are_compatible() {
+        let locks = LockManager::new();
+        let (a, b) = (locks.new_txn(), locks.new_txn());
+
+        locks.lock(a, b"Apple", LockMode::Shared, TIMEOUT).unwrap();
+        locks.lock(b, b"Apple", LockMode::Shared, TIMEOUT).unwrap();
+        assert!(matches!(
+            locks.lock(b, b"Apple", LockMode::Exclusive, TIMEOUT),
+            Err(Error::LockTimeout)
+        ));
+
+        // Once the other reader is gone the lock can be upgraded
+        locks.unlock_all(a);
+        locks
+            .lock(b, b"Apple", LockMode::Exclusive, TIMEOUT)
+            .unwrap();
+        locks.lock(b, b"Apple", LockMode::Shared, TIMEOUT).unwrap();
+        assert!(matches!(
+            locks.lock(a, b"Apple", LockMode::Shared, TIMEOUT),
+            Err(Error::LockTimeout)
+        ));
+
+        locks.unlock_all(b);
+        assert!(locks.lock_state().locks.is_empty());
+    }
+
+    #[test]
+    fn test_waiter_gets_released_lock() {
+        let locks = Arc::new(LockManager::new());
+        let (a, b) = (locks.new_txn(), locks.new_txn());
+        locks
+            .lock(a, b"Apple", LockMode::Exclusive, TIMEOUT)
+            .unwrap();
+
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                locks.lock(b, b"Apple", LockMode::Exclusive, Duration::from_secs(5))
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        locks.unlock_all(a);
+
+        waiter.join().unwrap().unwrap();
+        assert_eq!(
+            locks.lock_state().locks[b"Apple".as_slice()].exclusive,
+            Some(b)
+        );
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let locks = Arc::new(LockManager::new());
+        let (a, b) = (locks.new_txn(), locks.new_txn());
+        locks
+            .lock(a, b"Apple", LockMode::Exclusive, TIMEOUT)
+            .unwrap();
+        locks
+            .lock(b, b"Lime", LockMode::Exclusive, TIMEOUT)
+            .unwrap();
+
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                locks.lock(a, b"Lime", LockMode::Exclusive, Duration::from_secs(5))
+            })
+        };
+        while !locks.lock_state().waits_for.contains_key(&a) {
+            thread::yield_now();
+        }
+
+        // b closes the cycle, so it is the victim
+        assert!(matches!(
+            locks.lock(b, b"Apple", LockMode::Exclusive, Duration::from_secs(5)),
+            Err(Error::Deadlock)
+        ));
+        locks.unlock_all(b);
+        waiter.join().unwrap().unwrap();
+    }
+}
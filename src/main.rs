@@ -1,6 +1,26 @@
+mod block;
+mod block_cache;
+mod checksum;
+mod column_family;
+mod compaction;
+mod compaction_filter;
+mod comparator;
+mod compression;
+mod db;
+mod dictionary;
+mod encoding;
+mod error;
+mod lock_manager;
+mod manifest;
 mod memtable;
+mod merge_operator;
+mod row_cache;
+mod table;
+mod table_cache;
+mod transaction;
 mod wal;
 mod utils;
+mod write_batch;
 
 fn main() {
     println!("Hello from IronDB!");
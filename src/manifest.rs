@@ -0,0 +1,589 @@
+//! The manifest records the metadata of the database that is not stored in
+//! the WAL: the column families that exist, the name of the comparator the
+//! keys are ordered with and the tables holding the data of every column
+//! family.
+//!
+//! It is a log of edits in the `MANIFEST` file of the database directory,
+//! replayed when the database is opened. The file starts with a header:
+//!
+//! +------------+--------------+
+//! | Magic (8B) | Version (4B) |
+//! +------------+--------------+
+//! Magic = Identifies the file as an IronDB manifest
+//! Version = Version of the format of the manifest
+//!
+//! followed by one record per edit:
+//!
+//! +----------+-------------+---...---+
+//! | CRC (4B) | Length (4B) | Payload |
+//! +----------+-------------+---...---+
+//! CRC = CRC-32C of the Payload
+//! Length = Length of the Payload
+//!
+//! where the payload has the following structure, and (V) fields are varints:
+//!
+//! +-----------+----------------------+---------------+--...--+
+//! | Kind (1B) | Column Family Id (V) | Name Size (V) | Name  |
+//! +-----------+----------------------+---------------+--...--+
+//! Kind = Kind of edit: 1 to create a column family, 2 to drop it, which has
+//!        no Name, and 3 to set the comparator, which has no Column Family Id
+//!
+//! except for the edits of kind 4, which add and remove tables:
+//!
+//! +-----------+----------------------+-----------------+-------------+-----+-------------------+---------------+-----+
+//! | Kind (1B) | Flushed Sequence (V) | Added Count (V) | Added Table | ... | Removed Count (V) | Removed Table | ... |
+//! +-----------+----------------------+-----------------+-------------+-----+-------------------+---------------+-----+
+//! Flushed Sequence = Every operation up to this sequence number is persisted
+//!                    in tables, so the WAL is only replayed after it
+//! Added Table = Column Family Id (V), Level (V) and File Number (V) of every
+//!               table added
+//! Removed Table = Column Family Id (V) and File Number (V) of every table
+//!                 removed
+//!
+//! All the tables added and removed by a flush or a compaction are recorded in
+//! a single edit, so they are applied all or none.
+//!
+//! Version 2 of the format added the edit setting the comparator, and version
+//! 3 the edit of the tables. An older manifest is upgraded when one of these
+//! edits is first recorded in it, so older code doesn't mistake the new edit
+//! for a torn record.
+//!
+//! A record that was being appended when the process crashed is incomplete
+//! or fails its checksum. It is discarded when the manifest is opened, since
+//! the edit it held was never acknowledged.
+
+#![allow(dead_code)]
+
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    checksum,
+    column_family::{ColumnFamilyId, DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME},
+    encoding::{get_bytes, get_varint, put_varint},
+};
+
+/// Name of the manifest file inside the database directory
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Magic bytes the manifest starts with
+const MANIFEST_MAGIC: &[u8; 8] = b"IRONMFT\0";
+
+/// Version of the format of the manifest written by this code
+pub const MANIFEST_FORMAT_VERSION: u32 = 3;
+
+/// Size of the header at the start of the manifest
+const MANIFEST_HEADER_SIZE: usize = 8 + 4;
+
+/// Size of the header in front of every record
+const RECORD_HEADER_SIZE: usize = 4 + 4;
+
+/// Kind byte of an edit
+const KIND_ADD_COLUMN_FAMILY: u8 = 1;
+const KIND_DROP_COLUMN_FAMILY: u8 = 2;
+const KIND_SET_COMPARATOR: u8 = 3;
+const KIND_UPDATE_TABLES: u8 = 4;
+
+/// A table holding data of a column family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableFile {
+    pub file_number: u64,
+    /// Level of the LSM tree the table belongs to
+    pub level: usize,
+}
+
+/// A change to the metadata of the database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestEdit {
+    AddColumnFamily {
+        id: ColumnFamilyId,
+        name: String,
+    },
+    DropColumnFamily {
+        id: ColumnFamilyId,
+    },
+    SetComparator {
+        name: String,
+    },
+    UpdateTables {
+        flushed_sequence: u64,
+        added: Vec<(ColumnFamilyId, TableFile)>,
+        /// File numbers of the tables removed, by column family
+        removed: Vec<(ColumnFamilyId, u64)>,
+    },
+}
+
+impl ManifestEdit {
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            ManifestEdit::AddColumnFamily { id, name } => {
+                payload.push(KIND_ADD_COLUMN_FAMILY);
+                put_varint(&mut payload, *id as u64);
+                put_varint(&mut payload, name.len() as u64);
+                payload.extend_from_slice(name.as_bytes());
+            }
+            ManifestEdit::DropColumnFamily { id } => {
+                payload.push(KIND_DROP_COLUMN_FAMILY);
+                put_varint(&mut payload, *id as u64);
+            }
+            ManifestEdit::SetComparator { name } => {
+                payload.push(KIND_SET_COMPARATOR);
+                put_varint(&mut payload, name.len() as u64);
+                payload.extend_from_slice(name.as_bytes());
+            }
+            ManifestEdit::UpdateTables {
+                flushed_sequence,
+                added,
+                removed,
+            } => {
+                payload.push(KIND_UPDATE_TABLES);
+                put_varint(&mut payload, *flushed_sequence);
+                put_varint(&mut payload, added.len() as u64);
+                for (id, table) in added {
+                    put_varint(&mut payload, *id as u64);
+                    put_varint(&mut payload, table.level as u64);
+                    put_varint(&mut payload, table.file_number);
+                }
+                put_varint(&mut payload, removed.len() as u64);
+                for (id, file_number) in removed {
+                    put_varint(&mut payload, *id as u64);
+                    put_varint(&mut payload, *file_number);
+                }
+            }
+        }
+        payload
+    }
+
+    fn decode(mut payload: &[u8]) -> Option<Self> {
+        let kind = *get_bytes(&mut payload, 1)?.first()?;
+        match kind {
+            KIND_ADD_COLUMN_FAMILY => {
+                let id = get_column_family_id(&mut payload)?;
+                Some(ManifestEdit::AddColumnFamily {
+                    id,
+                    name: get_name(&mut payload)?,
+                })
+            }
+            KIND_DROP_COLUMN_FAMILY => Some(ManifestEdit::DropColumnFamily {
+                id: get_column_family_id(&mut payload)?,
+            }),
+            KIND_SET_COMPARATOR => Some(ManifestEdit::SetComparator {
+                name: get_name(&mut payload)?,
+            }),
+            KIND_UPDATE_TABLES => {
+                let flushed_sequence = get_varint(&mut payload)?;
+                let mut added = Vec::new();
+                for _ in 0..get_varint(&mut payload)? {
+                    let id = get_column_family_id(&mut payload)?;
+                    let level = get_varint(&mut payload)? as usize;
+                    let file_number = get_varint(&mut payload)?;
+                    added.push((id, TableFile { file_number, level }));
+                }
+                let mut removed = Vec::new();
+                for _ in 0..get_varint(&mut payload)? {
+                    let id = get_column_family_id(&mut payload)?;
+                    removed.push((id, get_varint(&mut payload)?));
+                }
+                Some(ManifestEdit::UpdateTables {
+                    flushed_sequence,
+                    added,
+                    removed,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn get_column_family_id(payload: &mut &[u8]) -> Option<ColumnFamilyId> {
+    ColumnFamilyId::try_from(get_varint(payload)?).ok()
+}
+
+fn get_name(payload: &mut &[u8]) -> Option<String> {
+    let name_len = get_varint(payload)? as usize;
+    let name = get_bytes(payload, name_len)?;
+    String::from_utf8(name.to_vec()).ok()
+}
+
+/// The manifest of a database, holding the current state of its metadata
+pub struct Manifest {
+    file: File,
+    /// Name of every column family that exists, by id
+    column_families: BTreeMap<ColumnFamilyId, String>,
+    /// Identifier given to the next column family created
+    next_column_family: ColumnFamilyId,
+    /// Name of the comparator the keys are ordered with, if recorded
+    comparator: Option<String>,
+    /// Tables of every column family, by level and then newest first
+    tables: BTreeMap<ColumnFamilyId, Vec<TableFile>>,
+    /// Sequence number up to which every operation is persisted in tables
+    flushed_sequence: u64,
+    /// File number given to the next table created
+    next_file_number: u64,
+    /// Version of the format of the manifest file
+    version: u32,
+    /// Whether the manifest file was created when it was opened
+    created: bool,
+}
+
+impl Manifest {
+    /// Opens the manifest of the database in `dir`, creating it if it doesn't
+    /// exist, and replays its edits
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(MANIFEST_FILE))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut manifest = Self {
+            file,
+            column_families: BTreeMap::from([(
+                DEFAULT_COLUMN_FAMILY,
+                DEFAULT_COLUMN_FAMILY_NAME.to_owned(),
+            )]),
+            next_column_family: DEFAULT_COLUMN_FAMILY + 1,
+            comparator: None,
+            tables: BTreeMap::new(),
+            flushed_sequence: 0,
+            next_file_number: 1,
+            version: MANIFEST_FORMAT_VERSION,
+            created: data.is_empty(),
+        };
+
+        if data.is_empty() {
+            let mut header = MANIFEST_MAGIC.to_vec();
+            header.extend_from_slice(&MANIFEST_FORMAT_VERSION.to_le_bytes());
+            manifest.file.write_all(&header)?;
+            manifest.file.sync_all()?;
+            return Ok(manifest);
+        }
+
+        if data.len() < MANIFEST_HEADER_SIZE || &data[..8] != MANIFEST_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid manifest header",
+            ));
+        }
+        let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        if version == 0 || version > MANIFEST_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported manifest format version {}", version),
+            ));
+        }
+        manifest.version = version;
+
+        let mut offset = MANIFEST_HEADER_SIZE;
+        while let Some((edit, len)) = read_record(&data[offset..]) {
+            manifest.apply(edit);
+            offset += len;
+        }
+
+        // Drop a torn record left by a crash, so new records are appended
+        // right after the last complete one
+        if offset < data.len() {
+            manifest.file.set_len(offset as u64)?;
+            manifest.file.seek(SeekFrom::Start(offset as u64))?;
+            manifest.file.sync_all()?;
+        }
+
+        Ok(manifest)
+    }
+
+    /// Name of every column family that exists, by id
+    pub fn column_families(&self) -> &BTreeMap<ColumnFamilyId, String> {
+        &self.column_families
+    }
+
+    /// Whether the manifest file was created when it was opened, so the
+    /// database is new
+    pub fn created(&self) -> bool {
+        self.created
+    }
+
+    /// Name of the comparator the keys are ordered with, if one was recorded
+    pub fn comparator(&self) -> Option<&str> {
+        self.comparator.as_deref()
+    }
+
+    /// Records the name of the comparator the keys are ordered with
+    pub fn set_comparator(&mut self, name: &str) -> io::Result<()> {
+        self.upgrade()?;
+        self.log_edit(ManifestEdit::SetComparator {
+            name: name.to_owned(),
+        })
+    }
+
+    /// Tables of a column family, sorted by level and then from newest to
+    /// oldest, which is the order they are read in
+    pub fn tables(&self, id: ColumnFamilyId) -> &[TableFile] {
+        self.tables.get(&id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Sequence number up to which every operation is persisted in tables
+    pub fn flushed_sequence(&self) -> u64 {
+        self.flushed_sequence
+    }
+
+    /// Reserves the file number of a new table
+    ///
+    /// The number is only persisted once the table is added, so numbers of
+    /// tables that were never added may be given again after reopening.
+    pub fn new_file_number(&mut self) -> u64 {
+        let file_number = self.next_file_number;
+        self.next_file_number += 1;
+        file_number
+    }
+
+    /// Records tables added and removed by a flush or a compaction, together
+    /// with the sequence number up to which every operation is persisted in
+    /// tables
+    pub fn update_tables(
+        &mut self,
+        flushed_sequence: u64,
+        added: Vec<(ColumnFamilyId, TableFile)>,
+        removed: Vec<(ColumnFamilyId, u64)>,
+    ) -> io::Result<()> {
+        self.upgrade()?;
+        self.log_edit(ManifestEdit::UpdateTables {
+            flushed_sequence,
+            added,
+            removed,
+        })
+    }
+
+    /// Records the creation of a column family, returning its id
+    pub fn add_column_family(&mut self, name: &str) -> io::Result<ColumnFamilyId> {
+        let id = self.next_column_family;
+        self.log_edit(ManifestEdit::AddColumnFamily {
+            id,
+            name: name.to_owned(),
+        })?;
+        Ok(id)
+    }
+
+    /// Records that a column family was dropped
+    pub fn drop_column_family(&mut self, id: ColumnFamilyId) -> io::Result<()> {
+        self.log_edit(ManifestEdit::DropColumnFamily { id })
+    }
+
+    /// Upgrades the header of a manifest of an older format version before
+    /// the first edit only known to the current version is appended
+    fn upgrade(&mut self) -> io::Result<()> {
+        if self.version < MANIFEST_FORMAT_VERSION {
+            self.file
+                .seek(SeekFrom::Start(MANIFEST_MAGIC.len() as u64))?;
+            self.file
+                .write_all(&MANIFEST_FORMAT_VERSION.to_le_bytes())?;
+            self.file.sync_all()?;
+            self.file.seek(SeekFrom::End(0))?;
+            self.version = MANIFEST_FORMAT_VERSION;
+        }
+        Ok(())
+    }
+
+    /// Appends an edit to the manifest, and applies it once it is durable
+    fn log_edit(&mut self, edit: ManifestEdit) -> io::Result<()> {
+        let payload = edit.encode();
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.extend_from_slice(&checksum::crc32c(&payload).to_le_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        self.file.write_all(&record)?;
+        self.file.sync_all()?;
+        self.apply(edit);
+        Ok(())
+    }
+
+    fn apply(&mut self, edit: ManifestEdit) {
+        match edit {
+            ManifestEdit::AddColumnFamily { id, name } => {
+                self.next_column_family = self.next_column_family.max(id + 1);
+                self.column_families.insert(id, name);
+            }
+            ManifestEdit::DropColumnFamily { id } => {
+                self.column_families.remove(&id);
+                self.tables.remove(&id);
+            }
+            ManifestEdit::SetComparator { name } => {
+                self.comparator = Some(name);
+            }
+            ManifestEdit::UpdateTables {
+                flushed_sequence,
+                added,
+                removed,
+            } => {
+                self.flushed_sequence = self.flushed_sequence.max(flushed_sequence);
+                for (id, file_number) in removed {
+                    if let Some(tables) = self.tables.get_mut(&id) {
+                        tables.retain(|table| table.file_number != file_number);
+                    }
+                }
+                for (id, table) in added {
+                    self.next_file_number = self.next_file_number.max(table.file_number + 1);
+                    let tables = self.tables.entry(id).or_default();
+                    tables.push(table);
+                    tables.sort_by_key(|table| (table.level, Reverse(table.file_number)));
+                }
+            }
+        }
+    }
+}
+
+/// Reads the record at the start of `data`, returning its edit and length
+///
+/// Returns `None` if the data ends before the record does or the record is
+/// corrupted
+fn read_record(data: &[u8]) -> Option<(ManifestEdit, usize)> {
+    let header = data.get(..RECORD_HEADER_SIZE)?;
+    let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    let payload = data.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len)?;
+    if checksum::crc32c(payload) != crc {
+        return None;
+    }
+
+    Some((ManifestEdit::decode(payload)?, RECORD_HEADER_SIZE + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::{
+        fs::{create_dir, remove_dir_all},
+        path::PathBuf,
+    };
+
+    #[test]
+    fn test_manifest_column_families() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let mut manifest = Manifest::open(&dir).unwrap();
+        assert_eq!(manifest.add_column_family("users").unwrap(), 1);
+        assert_eq!(manifest.add_column_family("sessions").unwrap(), 2);
+        manifest.drop_column_family(2).unwrap();
+        drop(manifest);
+
+        let mut manifest = Manifest::open(&dir).unwrap();
+        let names: Vec<&str> = manifest
+            .column_families()
+            .values()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(names, vec!["default", "users"]);
+        // Ids of dropped column families are not reused
+        assert_eq!(manifest.add_column_family("sessions").unwrap(), 3);
+        drop(manifest);
+
+        // A torn record is discarded, and new edits go after the last
+        // complete one
+        let path = dir.join(MANIFEST_FILE);
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(&[1, 2, 3]);
+        std::fs::write(&path, data).unwrap();
+
+        let mut manifest = Manifest::open(&dir).unwrap();
+        assert_eq!(manifest.column_families().len(), 3);
+        manifest.drop_column_family(1).unwrap();
+        drop(manifest);
+
+        let manifest = Manifest::open(&dir).unwrap();
+        assert_eq!(
+            manifest
+                .column_families()
+                .keys()
+                .copied()
+                .collect::<Vec<_>>(),
+            vec![0, 3]
+        );
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_comparator() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        // A manifest written before comparators were recorded
+        let mut manifest = Manifest::open(&dir).unwrap();
+        manifest.add_column_family("users").unwrap();
+        drop(manifest);
+        let path = dir.join(MANIFEST_FILE);
+        let mut data = std::fs::read(&path).unwrap();
+        data[8..12].copy_from_slice(&1u32.to_le_bytes());
+        std::fs::write(&path, data).unwrap();
+
+        let mut manifest = Manifest::open(&dir).unwrap();
+        assert_eq!(manifest.comparator(), None);
+        manifest
+            .set_comparator("irondb.ReverseBytewiseComparator")
+            .unwrap();
+        drop(manifest);
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(
+            u32::from_le_bytes(data[8..12].try_into().unwrap()),
+            MANIFEST_FORMAT_VERSION
+        );
+        let manifest = Manifest::open(&dir).unwrap();
+        assert_eq!(
+            manifest.comparator(),
+            Some("irondb.ReverseBytewiseComparator")
+        );
+        assert_eq!(manifest.column_families().len(), 2);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_tables() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let table = |file_number, level| TableFile { file_number, level };
+        let mut manifest = Manifest::open(&dir).unwrap();
+        let users = manifest.add_column_family("users").unwrap();
+        assert_eq!(manifest.new_file_number(), 1);
+        assert_eq!(manifest.new_file_number(), 2);
+        manifest
+            .update_tables(10, vec![(0, table(1, 0)), (users, table(2, 0))], vec![])
+            .unwrap();
+        manifest
+            .update_tables(15, vec![(0, table(3, 0))], vec![])
+            .unwrap();
+        // A compaction doesn't persist any new operation
+        manifest
+            .update_tables(0, vec![(0, table(4, 1))], vec![(0, 1)])
+            .unwrap();
+        drop(manifest);
+
+        let mut manifest = Manifest::open(&dir).unwrap();
+        assert_eq!(manifest.tables(0), &[table(3, 0), table(4, 1)]);
+        assert_eq!(manifest.tables(users), &[table(2, 0)]);
+        assert_eq!(manifest.flushed_sequence(), 15);
+        assert_eq!(manifest.new_file_number(), 5);
+
+        // The tables of a dropped column family are gone with it
+        manifest.drop_column_family(users).unwrap();
+        assert!(manifest.tables(users).is_empty());
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,9 @@
 #![allow(dead_code)]
 
+use std::{sync::Arc, time::Duration};
+
+use crate::comparator::{BytewiseComparator, Comparator};
+
 /// MemTable holds a sorted list of the latest written records.
 ///
 /// Writes are duplicated to the WAL for recovery of the MemTable in the event
@@ -8,20 +12,37 @@
 /// MemTables have a max capacity and when that is reached, we flush them to
 /// disk as a Table (SSTable)
 ///
-/// Entries are stored in a Vector instead of a HashMap to support Scans
+/// Entries are stored in a Vector instead of a HashMap to support Scans, sorted
+/// by the comparator of the MemTable
 pub struct MemTable {
     entries: Vec<MemTableEntry>,
+    /// Range Tombstones written to the MemTable, oldest first
+    range_tombstones: Vec<RangeTombstone>,
     size: usize,
+    comparator: Arc<dyn Comparator>,
 }
 
 impl MemTable {
+    /// Creates a MemTable ordering keys bytewise
     pub fn new() -> Self {
+        Self::with_comparator(Arc::new(BytewiseComparator))
+    }
+
+    /// Creates a MemTable ordering keys with the given comparator
+    pub fn with_comparator(comparator: Arc<dyn Comparator>) -> Self {
         Self {
             entries: Vec::new(),
+            range_tombstones: Vec::new(),
             size: 0,
+            comparator,
         }
     }
 
+    /// Comparator ordering the keys of the MemTable
+    pub fn comparator(&self) -> &dyn Comparator {
+        self.comparator.as_ref()
+    }
+
     /// Get a Key-Value pair from the MemTable
     ///
     /// If no record with the same key exists, return None
@@ -32,20 +53,57 @@ impl MemTable {
     }
 
     /// Sets a Key-Value pair in the MemTable
-    pub fn set(&mut self, key: &[u8], value: &[u8], timestamp: u128) {
+    pub fn set(&mut self, key: &[u8], value: &[u8], timestamp: u128, seq: u64) {
+        self.set_entry(key, value, timestamp, None, seq);
+    }
+
+    /// Sets a Key-Value pair in the MemTable that expires `ttl` after it is
+    /// written
+    ///
+    /// Expired entries stay in the MemTable until compaction removes them, so
+    /// readers have to check [`MemTableEntry::is_expired`]
+    pub fn set_with_ttl(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        timestamp: u128,
+        ttl: Duration,
+        seq: u64,
+    ) {
+        self.set_entry(
+            key,
+            value,
+            timestamp,
+            Some(timestamp + ttl.as_micros()),
+            seq,
+        );
+    }
+
+    fn set_entry(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        timestamp: u128,
+        expires_at: Option<u128>,
+        seq: u64,
+    ) {
         // TODO(alvaro): Can we pass ownership of the key and value here instead
         // of copying
         let entry = MemTableEntry {
             key: key.to_owned(),
             value: Some(value.to_owned()),
             timestamp,
+            expires_at,
+            seq,
             deleted: false,
+            operands: Vec::new(),
         };
 
         match self.get_index(key) {
             Ok(idx) => {
                 // TODO(alvaro): We should be to use some kind of add operation
                 // to represent this logic
+                self.size -= self.entries[idx].operands_size();
 
                 // If a value already existed on the deleted record, add the
                 // difference of the new and old value to the MemTable's size
@@ -55,6 +113,8 @@ impl MemTable {
                     } else {
                         self.size += value.len() - v.len();
                     }
+                } else {
+                    self.size += value.len();
                 }
                 self.entries[idx] = entry;
             }
@@ -64,8 +124,8 @@ impl MemTable {
                 // alignment, padding, null pointer optimization, etc.)
 
                 // Increase the size of the MemTable by the Key size, the Value size, Timestamp
-                // size (16 bytes) and Tombstone size (1 byte)
-                self.size += key.len() + value.len() + 16 + 1;
+                // size (16 bytes), Sequence size (8 bytes) and Tombstone size (1 byte)
+                self.size += key.len() + value.len() + 16 + 8 + 1;
                 self.entries.insert(idx, entry);
             }
         }
@@ -75,20 +135,24 @@ impl MemTable {
     ///
     /// This is achieved by inserting a Tombstone, which will be checked and
     /// actually cleaned by the compaction process
-    pub fn delete(&mut self, key: &[u8], timestamp: u128) {
+    pub fn delete(&mut self, key: &[u8], timestamp: u128, seq: u64) {
         // TODO(alvaro): Can we pass ownership of the key and value here instead
         // of copying
         let entry = MemTableEntry {
             key: key.to_owned(),
             value: None,
             timestamp,
+            expires_at: None,
+            seq,
             deleted: true,
+            operands: Vec::new(),
         };
 
         match self.get_index(key) {
             Ok(idx) => {
                 // If a Value existed on the deleted record, then subtract the
                 // size of the Value from the MemTable
+                self.size -= self.entries[idx].operands_size();
                 if let Some(value) = self.entries[idx].value.as_ref() {
                     self.size -= value.len();
                 }
@@ -96,13 +160,77 @@ impl MemTable {
             }
             Err(idx) => {
                 // Increase the size of the MemTable by the Key size, Timestamp
-                // size (16 bytes) and Tombstone size (1 byte)
-                self.size += key.len() + 16 + 1;
+                // size (16 bytes), Sequence size (8 bytes) and Tombstone size (1 byte)
+                self.size += key.len() + 16 + 8 + 1;
                 self.entries.insert(idx, entry);
             }
         }
     }
 
+    /// Adds a merge operand on top of the value of a Key in the MemTable
+    ///
+    /// Operands are combined with the value by the merge operator when the Key
+    /// is read. If the Key is not in the MemTable, the operands apply on top of
+    /// whatever value older data holds for it
+    pub fn merge(&mut self, key: &[u8], operand: &[u8], timestamp: u128, seq: u64) {
+        match self.get_index(key) {
+            Ok(idx) => {
+                let entry = &mut self.entries[idx];
+                entry.operands.push(operand.to_owned());
+                entry.timestamp = timestamp;
+                entry.seq = seq;
+                self.size += operand.len();
+            }
+            Err(idx) => {
+                let entry = MemTableEntry {
+                    key: key.to_owned(),
+                    value: None,
+                    timestamp,
+                    expires_at: None,
+                    seq,
+                    deleted: false,
+                    operands: vec![operand.to_owned()],
+                };
+                // Increase the size of the MemTable by the Key size, the Operand
+                // size, Timestamp size (16 bytes), Sequence size (8 bytes) and
+                // Tombstone size (1 byte)
+                self.size += key.len() + operand.len() + 16 + 8 + 1;
+                self.entries.insert(idx, entry);
+            }
+        }
+    }
+
+    /// Deletes every Key from `start` (inclusive) to `end` (exclusive) in the
+    /// MemTable
+    ///
+    /// The entries in the range are removed right away, since they are older
+    /// than the delete. A Range Tombstone is kept to hide the Keys of the range
+    /// in older data, until compaction cleans it
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8], timestamp: u128, seq: u64) {
+        let from = self.get_index(start).unwrap_or_else(|idx| idx);
+        let to = self.get_index(end).unwrap_or_else(|idx| idx).max(from);
+        for entry in self.entries.drain(from..to) {
+            // Decrease the size of the MemTable by everything the entry added
+            self.size -= entry.key.len() + 16 + 8 + 1;
+            self.size -= entry.value.as_ref().map_or(0, Vec::len) + entry.operands_size();
+        }
+
+        // Increase the size of the MemTable by the Start and End sizes,
+        // Timestamp size (16 bytes) and Sequence size (8 bytes)
+        self.size += start.len() + end.len() + 16 + 8;
+        self.range_tombstones.push(RangeTombstone {
+            start: start.to_owned(),
+            end: end.to_owned(),
+            timestamp,
+            seq,
+        });
+    }
+
+    /// Return the Range Tombstones written to the MemTable, oldest first
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
     /// Performs Binary Search to find a record in the MemTable
     ///
     /// If the record is found `[Result::Ok]` is returned with the index of the
@@ -110,7 +238,14 @@ impl MemTable {
     /// the index to insert the record at while maintaining sorted order.
     fn get_index(&self, key: &[u8]) -> Result<usize, usize> {
         self.entries
-            .binary_search_by_key(&key, |e| e.key.as_slice())
+            .binary_search_by(|e| self.comparator.compare(&e.key, key))
+    }
+
+    /// Return an iterator over the entries of the MemTable, ordered by key
+    ///
+    /// Tombstones are included
+    pub fn iter(&self) -> std::slice::Iter<'_, MemTableEntry> {
+        self.entries.iter()
     }
 
     /// Return the number of entries in the MemTable
@@ -130,8 +265,51 @@ pub struct MemTableEntry {
     /// Time this write occurred in microseconds, used to order writes when
     /// cleaning old data in SSTables
     pub timestamp: u128,
+    /// Time in microseconds after which the value is no longer visible,
+    /// `None` if it never expires
+    pub expires_at: Option<u128>,
+    /// Sequence number of the write, which is also the version of the key
+    pub seq: u64,
     /// Tombstone mark
     pub deleted: bool,
+    /// Merge operands written on top of the value, oldest first
+    ///
+    /// An entry with operands and neither a value nor a Tombstone only holds
+    /// operands, which apply on top of the value in older data
+    pub operands: Vec<Vec<u8>>,
+}
+
+impl MemTableEntry {
+    /// Returns true if the value of the entry expired at time `now`
+    pub fn is_expired(&self, now: u128) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Bytes taken by the merge operands of the entry
+    fn operands_size(&self) -> usize {
+        self.operands.iter().map(Vec::len).sum()
+    }
+}
+
+/// Deletion of every Key from `start` (inclusive) to `end` (exclusive)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    /// Time this delete occurred in microseconds
+    pub timestamp: u128,
+    /// Sequence number of the delete
+    pub seq: u64,
+}
+
+impl RangeTombstone {
+    /// Returns true if the version `seq` of `key` is hidden by the Tombstone,
+    /// with keys ordered by `comparator`
+    pub fn covers(&self, key: &[u8], seq: u64, comparator: &dyn Comparator) -> bool {
+        seq < self.seq
+            && comparator.compare(&self.start, key).is_le()
+            && comparator.compare(key, &self.end).is_lt()
+    }
 }
 
 #[cfg(test)]
@@ -141,10 +319,10 @@ mod tests {
     #[test]
     fn test_mem_table_put_start() {
         let mut table = MemTable::new();
-        table.set(b"Lime", b"Lime Smoothie", 0); // 17 + 16 + 1
-        table.set(b"Orange", b"Orange Smoothie", 10); // 21 + 16 + 1
+        table.set(b"Lime", b"Lime Smoothie", 0, 1); // 17 + 16 + 8 + 1
+        table.set(b"Orange", b"Orange Smoothie", 10, 2); // 21 + 16 + 8 + 1
 
-        table.set(b"Apple", b"Apple Smoothie", 20); // 19 + 16 + 1
+        table.set(b"Apple", b"Apple Smoothie", 20, 3); // 19 + 16 + 8 + 1
 
         assert_eq!(table.entries[0].key, b"Apple");
         assert_eq!(table.entries[0].value.as_ref().unwrap(), b"Apple Smoothie");
@@ -159,16 +337,16 @@ mod tests {
         assert_eq!(table.entries[2].timestamp, 10);
         assert!(!table.entries[2].deleted);
 
-        assert_eq!(table.size, 108);
+        assert_eq!(table.size, 132);
     }
 
     #[test]
     fn test_mem_table_put_middle() {
         let mut table = MemTable::new();
-        table.set(b"Apple", b"Apple Smoothie", 0);
-        table.set(b"Orange", b"Orange Smoothie", 10);
+        table.set(b"Apple", b"Apple Smoothie", 0, 1);
+        table.set(b"Orange", b"Orange Smoothie", 10, 2);
 
-        table.set(b"Lime", b"Lime Smoothie", 20);
+        table.set(b"Lime", b"Lime Smoothie", 20, 3);
 
         assert_eq!(table.entries[0].key, b"Apple");
         assert_eq!(table.entries[0].value.as_ref().unwrap(), b"Apple Smoothie");
@@ -183,16 +361,16 @@ mod tests {
         assert_eq!(table.entries[2].timestamp, 10);
         assert!(!table.entries[2].deleted);
 
-        assert_eq!(table.size, 108);
+        assert_eq!(table.size, 132);
     }
 
     #[test]
     fn test_mem_table_put_end() {
         let mut table = MemTable::new();
-        table.set(b"Apple", b"Apple Smoothie", 0);
-        table.set(b"Lime", b"Lime Smoothie", 10);
+        table.set(b"Apple", b"Apple Smoothie", 0, 1);
+        table.set(b"Lime", b"Lime Smoothie", 10, 2);
 
-        table.set(b"Orange", b"Orange Smoothie", 20);
+        table.set(b"Orange", b"Orange Smoothie", 20, 3);
 
         assert_eq!(table.entries[0].key, b"Apple");
         assert_eq!(table.entries[0].value.as_ref().unwrap(), b"Apple Smoothie");
@@ -207,17 +385,17 @@ mod tests {
         assert_eq!(table.entries[2].timestamp, 20);
         assert!(!table.entries[2].deleted);
 
-        assert_eq!(table.size, 108);
+        assert_eq!(table.size, 132);
     }
 
     #[test]
     fn test_mem_table_put_overwrite() {
         let mut table = MemTable::new();
-        table.set(b"Apple", b"Apple Smoothie", 0);
-        table.set(b"Lime", b"Lime Smoothie", 10);
-        table.set(b"Orange", b"Orange Smoothie", 20);
+        table.set(b"Apple", b"Apple Smoothie", 0, 1);
+        table.set(b"Lime", b"Lime Smoothie", 10, 2);
+        table.set(b"Orange", b"Orange Smoothie", 20, 3);
 
-        table.set(b"Lime", b"A sour fruit", 30);
+        table.set(b"Lime", b"A sour fruit", 30, 4);
 
         assert_eq!(table.entries[0].key, b"Apple");
         assert_eq!(table.entries[0].value.as_ref().unwrap(), b"Apple Smoothie");
@@ -232,29 +410,30 @@ mod tests {
         assert_eq!(table.entries[2].timestamp, 20);
         assert!(!table.entries[2].deleted);
 
-        assert_eq!(table.size, 107);
+        assert_eq!(table.size, 131);
     }
 
     #[test]
     fn test_mem_table_get_exists() {
         let mut table = MemTable::new();
-        table.set(b"Apple", b"Apple Smoothie", 0);
-        table.set(b"Lime", b"Lime Smoothie", 10);
-        table.set(b"Orange", b"Orange Smoothie", 20);
+        table.set(b"Apple", b"Apple Smoothie", 0, 1);
+        table.set(b"Lime", b"Lime Smoothie", 10, 2);
+        table.set(b"Orange", b"Orange Smoothie", 20, 3);
 
         let entry = table.get(b"Orange").unwrap();
 
         assert_eq!(entry.key, b"Orange");
         assert_eq!(entry.value.as_ref().unwrap(), b"Orange Smoothie");
         assert_eq!(entry.timestamp, 20);
+        assert_eq!(entry.seq, 3);
     }
 
     #[test]
     fn test_mem_table_get_not_exists() {
         let mut table = MemTable::new();
-        table.set(b"Apple", b"Apple Smoothie", 0);
-        table.set(b"Lime", b"Lime Smoothie", 0);
-        table.set(b"Orange", b"Orange Smoothie", 0);
+        table.set(b"Apple", b"Apple Smoothie", 0, 1);
+        table.set(b"Lime", b"Lime Smoothie", 0, 2);
+        table.set(b"Orange", b"Orange Smoothie", 0, 3);
 
         let res = table.get(b"Potato");
         assert!(res.is_none());
@@ -263,14 +442,15 @@ mod tests {
     #[test]
     fn test_mem_table_delete_exists() {
         let mut table = MemTable::new();
-        table.set(b"Apple", b"Apple Smoothie", 0);
+        table.set(b"Apple", b"Apple Smoothie", 0, 1);
 
-        table.delete(b"Apple", 10);
+        table.delete(b"Apple", 10, 2);
 
         let res = table.get(b"Apple").unwrap();
         assert_eq!(res.key, b"Apple");
         assert_eq!(res.value, None);
         assert_eq!(res.timestamp, 10);
+        assert_eq!(res.seq, 2);
         assert!(res.deleted);
 
         assert_eq!(table.entries[0].key, b"Apple");
@@ -278,14 +458,14 @@ mod tests {
         assert_eq!(table.entries[0].timestamp, 10);
         assert!(table.entries[0].deleted);
 
-        assert_eq!(table.size, 22);
+        assert_eq!(table.size, 30);
     }
 
     #[test]
     fn test_mem_table_delete_empty() {
         let mut table = MemTable::new();
 
-        table.delete(b"Apple", 10);
+        table.delete(b"Apple", 10, 1);
 
         let res = table.get(b"Apple").unwrap();
         assert_eq!(res.key, b"Apple");
@@ -298,6 +478,106 @@ mod tests {
         assert_eq!(table.entries[0].timestamp, 10);
         assert!(table.entries[0].deleted);
 
-        assert_eq!(table.size, 22);
+        assert_eq!(table.size, 30);
+    }
+
+    #[test]
+    fn test_mem_table_merge() {
+        let mut table = MemTable::new();
+        table.merge(b"Apple", b"1", 0, 1);
+        table.merge(b"Apple", b"2", 10, 2);
+
+        let res = table.get(b"Apple").unwrap();
+        assert_eq!(res.value, None);
+        assert!(!res.deleted);
+        assert_eq!(res.operands, vec![b"1".to_vec(), b"2".to_vec()]);
+        assert_eq!(res.timestamp, 10);
+        assert_eq!(res.seq, 2);
+        assert_eq!(table.size, 32);
+
+        // Writing a value replaces the operands
+        table.set(b"Apple", b"Apple Smoothie", 20, 3);
+        table.merge(b"Apple", b"3", 30, 4);
+        let res = table.get(b"Apple").unwrap();
+        assert_eq!(res.value.as_ref().unwrap(), b"Apple Smoothie");
+        assert_eq!(res.operands, vec![b"3".to_vec()]);
+
+        table.delete(b"Apple", 40, 5);
+        assert!(table.get(b"Apple").unwrap().operands.is_empty());
+    }
+
+    #[test]
+    fn test_mem_table_set_with_ttl() {
+        let mut table = MemTable::new();
+        table.set_with_ttl(b"Apple", b"Apple Smoothie", 10, Duration::from_micros(5), 1);
+
+        let res = table.get(b"Apple").unwrap();
+        assert_eq!(res.expires_at, Some(15));
+        assert!(!res.is_expired(14));
+        assert!(res.is_expired(15));
+        assert_eq!(table.size, 44);
+
+        // Overwriting the value removes the expiry
+        table.set(b"Apple", b"Apple Pie", 20, 2);
+        assert!(!table.get(b"Apple").unwrap().is_expired(u128::MAX));
+    }
+
+    #[test]
+    fn test_mem_table_delete_range() {
+        let mut table = MemTable::new();
+        table.set(b"Apple", b"Apple Smoothie", 0, 1);
+        table.set(b"Lime", b"Lime Smoothie", 10, 2);
+        table.merge(b"Mango", b"1", 20, 3);
+        table.set(b"Orange", b"Orange Smoothie", 30, 4);
+
+        table.delete_range(b"Banana", b"Orange", 40, 5);
+
+        let keys: Vec<&[u8]> = table.iter().map(|e| e.key.as_slice()).collect();
+        assert_eq!(keys, vec![b"Apple".as_slice(), b"Orange"]);
+        // 44 + 46 + Range Tombstone (6 + 6 + 16 + 8)
+        assert_eq!(table.size, 126);
+
+        let tombstone = &table.range_tombstones()[0];
+        assert!(tombstone.covers(b"Lime", 2, table.comparator()));
+        assert!(!tombstone.covers(b"Lime", 6, table.comparator()));
+        assert!(!tombstone.covers(b"Orange", 4, table.comparator()));
+
+        // An empty range deletes nothing
+        table.delete_range(b"Orange", b"Apple", 50, 6);
+        assert_eq!(table.len(), 2);
+    }
+
+    /// Orders decimal numbers by their value, so "9" comes before "10"
+    struct NumericComparator;
+
+    impl Comparator for NumericComparator {
+        fn name(&self) -> &str {
+            "test.NumericComparator"
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            (a.len(), a).cmp(&(b.len(), b))
+        }
+    }
+
+    #[test]
+    fn test_mem_table_comparator() {
+        let mut table = MemTable::with_comparator(Arc::new(NumericComparator));
+        for (seq, key) in [b"10".as_slice(), b"9", b"100", b"25"]
+            .into_iter()
+            .enumerate()
+        {
+            table.set(key, b"Lime Smoothie", 0, seq as u64 + 1);
+        }
+        assert!(table.get(b"25").is_some());
+
+        let keys: Vec<&[u8]> = table.iter().map(|e| e.key.as_slice()).collect();
+        assert_eq!(keys, vec![b"9".as_slice(), b"10", b"25", b"100"]);
+
+        table.delete_range(b"10", b"99", 0, 5);
+        let keys: Vec<&[u8]> = table.iter().map(|e| e.key.as_slice()).collect();
+        assert_eq!(keys, vec![b"9".as_slice(), b"100"]);
+        assert!(table.range_tombstones()[0].covers(b"50", 1, table.comparator()));
+        assert!(!table.range_tombstones()[0].covers(b"100", 1, table.comparator()));
     }
 }
@@ -0,0 +1,144 @@
+//! Merge operators combine the operands written with `merge` into a value.
+//!
+//! Merging lets read-modify-write updates, like incrementing a counter or
+//! appending to a list, be written without reading the current value first.
+//! Every update is stored as an operand on top of the value of the key, and the
+//! operands are only combined with the value when the key is read, or when
+//! compaction collapses them.
+
+#![allow(dead_code)]
+
+/// Combines the merge operands of a key with its value
+///
+/// Operands are always given oldest first. Implementations must be
+/// associative, since operands may be combined in several steps.
+pub trait MergeOperator: Send + Sync {
+    /// Name of the operator
+    fn name(&self) -> &str;
+
+    /// Combines the existing value of a key, `None` if it doesn't exist, with
+    /// its operands into the new value of the key
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8>;
+
+    /// Combines two operands into a single one, without knowing the value
+    /// of the key
+    ///
+    /// Returns `None` if the operands can't be combined on their own, in which
+    /// case both are kept. By default operands are never combined
+    fn partial_merge(&self, _key: &[u8], _left: &[u8], _right: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Adds up values and operands as 64-bit unsigned integers, in little endian
+///
+/// Values that are not 8 bytes long are taken as 0, and additions wrap around
+/// on overflow
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64AddOperator;
+
+impl U64AddOperator {
+    fn decode(value: &[u8]) -> u64 {
+        value.try_into().map_or(0, u64::from_le_bytes)
+    }
+}
+
+impl MergeOperator for U64AddOperator {
+    fn name(&self) -> &str {
+        "U64AddOperator"
+    }
+
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8> {
+        let base = existing.map_or(0, Self::decode);
+        let sum = operands
+            .iter()
+            .fold(base, |sum, operand| sum.wrapping_add(Self::decode(operand)));
+        sum.to_le_bytes().to_vec()
+    }
+
+    fn partial_merge(&self, _key: &[u8], left: &[u8], right: &[u8]) -> Option<Vec<u8>> {
+        let sum = Self::decode(left).wrapping_add(Self::decode(right));
+        Some(sum.to_le_bytes().to_vec())
+    }
+}
+
+/// Appends operands to the value, optionally separating them with a delimiter
+#[derive(Debug, Clone, Default)]
+pub struct AppendOperator {
+    delimiter: Vec<u8>,
+}
+
+impl AppendOperator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Separates every appended operand from the previous data with
+    /// `delimiter`
+    pub fn with_delimiter(delimiter: &[u8]) -> Self {
+        Self {
+            delimiter: delimiter.to_owned(),
+        }
+    }
+}
+
+impl MergeOperator for AppendOperator {
+    fn name(&self) -> &str {
+        "AppendOperator"
+    }
+
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8> {
+        let mut value = existing.unwrap_or_default().to_vec();
+        for (i, operand) in operands.iter().enumerate() {
+            if existing.is_some() || i > 0 {
+                value.extend_from_slice(&self.delimiter);
+            }
+            value.extend_from_slice(operand);
+        }
+        value
+    }
+
+    fn partial_merge(&self, _key: &[u8], left: &[u8], right: &[u8]) -> Option<Vec<u8>> {
+        Some([left, &self.delimiter, right].concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_u64_add_operator() {
+        let operator = U64AddOperator;
+        let one = 1u64.to_le_bytes();
+        let two = 2u64.to_le_bytes();
+
+        let value = operator.full_merge(b"counter", None, &[&one, &two]);
+        assert_eq!(value, 3u64.to_le_bytes());
+        let value = operator.full_merge(b"counter", Some(&value), &[&two, b"bad"]);
+        assert_eq!(value, 5u64.to_le_bytes());
+        let value = operator.full_merge(b"counter", Some(&u64::MAX.to_le_bytes()), &[&one]);
+        assert_eq!(value, 0u64.to_le_bytes());
+
+        let operand = operator.partial_merge(b"counter", &one, &two).unwrap();
+        assert_eq!(operand, 3u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_append_operator() {
+        let operator = AppendOperator::with_delimiter(b",");
+        assert_eq!(operator.full_merge(b"list", None, &[b"a", b"b"]), b"a,b");
+        assert_eq!(
+            operator.full_merge(b"list", Some(b"a"), &[b"b", b"c"]),
+            b"a,b,c"
+        );
+        assert_eq!(operator.full_merge(b"list", Some(b""), &[b"a"]), b",a");
+        assert_eq!(operator.partial_merge(b"list", b"a", b"b").unwrap(), b"a,b");
+
+        let operator = AppendOperator::new();
+        assert_eq!(
+            operator.full_merge(b"list", Some(b"a"), &[b"b", b"c"]),
+            b"abc"
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! Cache of the rows read by point lookups, so hot keys are served without
+//! searching the MemTable or any SSTable.
+//!
+//! A row is the result of a lookup: the value of a key with its merge
+//! operands combined, or its absence, together with its version. Rows are
+//! cached by column family and user key. Reads always see the latest write,
+//! so a cached row stays valid until the key is written again: every write
+//! invalidates the rows of the keys it touches, before it becomes visible.
+//!
+//! The cache is owned by the state of the database and used under its lock,
+//! so it doesn't need any synchronization of its own. It evicts the least
+//! recently used rows once their size goes over its capacity.
+
+#![allow(dead_code)]
+
+use std::{
+    collections::BTreeMap,
+    ops::Bound::{Excluded, Included},
+};
+
+use crate::{column_family::ColumnFamilyId, comparator::Comparator};
+
+/// Result of a point lookup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    /// Value of the key, or `None` if it does not exist
+    pub value: Option<Vec<u8>>,
+    /// Sequence number of the last write to the key, 0 if it was never
+    /// written
+    pub version: u64,
+    /// Time in microseconds at which the value expires, if it has a TTL
+    pub expires_at: Option<u128>,
+}
+
+/// Counters of the use of a row cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RowCacheStats {
+    /// Lookups served by the cache
+    pub hits: u64,
+    /// Lookups that had to read the row
+    pub misses: u64,
+    /// Bytes of the keys and values of all the cached rows
+    pub usage: usize,
+}
+
+struct CachedRow {
+    row: Row,
+    /// Last time the row was used, its position in the LRU list
+    tick: u64,
+}
+
+type RowKey = (ColumnFamilyId, Vec<u8>);
+
+/// An LRU cache of rows, by column family and key
+pub struct RowCache {
+    capacity: usize,
+    usage: usize,
+    /// Ordered by column family, so the rows of a column family can be
+    /// invalidated together. Keys are ordered bytewise, whatever the
+    /// comparator of the database
+    rows: BTreeMap<RowKey, CachedRow>,
+    /// Keys of the cached rows by last use, least recently used first
+    lru: BTreeMap<u64, RowKey>,
+    next_tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl RowCache {
+    /// Creates a cache holding up to `capacity` bytes of keys and values
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            usage: 0,
+            rows: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            next_tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Gets the cached row of a key at time `now`, marking it as the most
+    /// recently used
+    ///
+    /// A row whose value expired is removed, since the lookup has to be done
+    /// again to find what the key reads as now.
+    pub fn get(&mut self, cf: ColumnFamilyId, key: &[u8], now: u128) -> Option<Row> {
+        let row_key = (cf, key.to_vec());
+        let tick = self.next_tick;
+        let Some(cached) = self.rows.get_mut(&row_key) else {
+            self.misses += 1;
+            return None;
+        };
+        if cached
+            .row
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now)
+        {
+            self.remove(&row_key);
+            self.misses += 1;
+            return None;
+        }
+
+        self.next_tick += 1;
+        let old_tick = std::mem::replace(&mut cached.tick, tick);
+        let row = cached.row.clone();
+        self.lru.remove(&old_tick);
+        self.lru.insert(tick, row_key);
+        self.hits += 1;
+        Some(row)
+    }
+
+    /// Caches the row of a key, evicting the least recently used rows to make
+    /// room for it
+    ///
+    /// Rows larger than the whole cache are not cached.
+    pub fn insert(&mut self, cf: ColumnFamilyId, key: &[u8], row: Row) {
+        let row_key = (cf, key.to_vec());
+        self.remove(&row_key);
+        let charge = row_charge(&row_key, &row);
+        if charge > self.capacity {
+            return;
+        }
+
+        while self.usage + charge > self.capacity {
+            let Some((_, evicted)) = self.lru.pop_first() else {
+                break;
+            };
+            let cached = self.rows.remove(&evicted).unwrap();
+            self.usage -= row_charge(&evicted, &cached.row);
+        }
+
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.usage += charge;
+        self.lru.insert(tick, row_key.clone());
+        self.rows.insert(row_key, CachedRow { row, tick });
+    }
+
+    /// Removes the row of a key, when it is written
+    pub fn invalidate(&mut self, cf: ColumnFamilyId, key: &[u8]) {
+        self.remove(&(cf, key.to_vec()));
+    }
+
+    /// Removes the rows of the keys from `start` (inclusive) to `end`
+    /// (exclusive) in the order of `comparator`, when the range is deleted
+    pub fn invalidate_range(
+        &mut self,
+        cf: ColumnFamilyId,
+        start: &[u8],
+        end: &[u8],
+        comparator: &dyn Comparator,
+    ) {
+        if comparator.compare(start, end).is_ge() {
+            return;
+        }
+        self.remove_rows(cf, |key| {
+            comparator.compare(key, start).is_ge() && comparator.compare(key, end).is_lt()
+        });
+    }
+
+    /// Removes all the rows of a column family, when it is dropped
+    pub fn invalidate_column_family(&mut self, cf: ColumnFamilyId) {
+        self.remove_rows(cf, |_| true);
+    }
+
+    /// Counters of the use of the cache since it was created
+    pub fn stats(&self) -> RowCacheStats {
+        RowCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            usage: self.usage,
+        }
+    }
+
+    /// Removes the rows of the keys of a column family matching `predicate`
+    fn remove_rows(&mut self, cf: ColumnFamilyId, predicate: impl Fn(&[u8]) -> bool) {
+        let range = (Included((cf, Vec::new())), Excluded((cf + 1, Vec::new())));
+        let keys: Vec<RowKey> = self
+            .rows
+            .range(range)
+            .filter(|((_, key), _)| predicate(key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, row_key: &RowKey) {
+        if let Some(cached) = self.rows.remove(row_key) {
+            self.usage -= row_charge(row_key, &cached.row);
+            self.lru.remove(&cached.tick);
+        }
+    }
+}
+
+/// Bytes a row counts for in the usage of the cache
+fn row_charge((_, key): &RowKey, row: &Row) -> usize {
+    key.len() + row.value.as_ref().map_or(0, Vec::len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::{BytewiseComparator, ReverseBytewiseComparator};
+
+    fn row(value: &[u8], version: u64) -> Row {
+        Row {
+            value: Some(value.to_vec()),
+            version,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_row_cache_evicts_least_recently_used() {
+        let mut cache = RowCache::new(12);
+        cache.insert(0, b"a", row(b"111", 1));
+        cache.insert(0, b"b", row(b"222", 2));
+        cache.insert(0, b"c", row(b"333", 3));
+        assert_eq!(cache.get(0, b"a", 0), Some(row(b"111", 1)));
+
+        // "b" is the least recently used row
+        cache.insert(1, b"d", row(b"444", 4));
+        assert_eq!(cache.get(0, b"b", 0), None);
+        assert!(cache.get(0, b"a", 0).is_some());
+        assert!(cache.get(0, b"c", 0).is_some());
+        assert!(cache.get(1, b"d", 0).is_some());
+
+        // Rows larger than the cache are not cached
+        cache.insert(0, b"e", row(&[0; 16], 5));
+        assert_eq!(cache.get(0, b"e", 0), None);
+
+        assert_eq!(
+            cache.stats(),
+            RowCacheStats {
+                hits: 4,
+                misses: 2,
+                usage: 12,
+            }
+        );
+    }
+
+    #[test]
+    fn test_row_cache_invalidation() {
+        let mut cache = RowCache::new(1024);
+        for key in [b"a", b"b", b"c", b"d"] {
+            cache.insert(0, key, row(b"v", 1));
+            cache.insert(1, key, row(b"v", 1));
+        }
+        let missing = Row {
+            value: None,
+            version: 0,
+            expires_at: None,
+        };
+        cache.insert(0, b"e", missing.clone());
+        assert_eq!(cache.get(0, b"e", 0), Some(missing));
+
+        cache.invalidate(0, b"a");
+        cache.invalidate_range(0, b"b", b"d", &BytewiseComparator);
+        let cached: Vec<&[u8]> = [b"a", b"b", b"c", b"d"]
+            .into_iter()
+            .filter(|key| cache.get(0, *key, 0).is_some())
+            .map(|key| key.as_slice())
+            .collect();
+        assert_eq!(cached, vec![b"d"]);
+
+        // Ranges follow the order of the comparator
+        cache.invalidate_range(1, b"c", b"a", &ReverseBytewiseComparator);
+        let cached: Vec<&[u8]> = [b"a", b"b", b"c", b"d"]
+            .into_iter()
+            .filter(|key| cache.get(1, *key, 0).is_some())
+            .map(|key| key.as_slice())
+            .collect();
+        assert_eq!(cached, vec![b"a", b"d"]);
+        cache.invalidate_range(1, b"a", b"d", &ReverseBytewiseComparator);
+        assert!(cache.get(1, b"a", 0).is_some());
+
+        cache.invalidate_column_family(1);
+        assert!([b"a", b"b", b"c", b"d"]
+            .iter()
+            .all(|key| cache.get(1, *key, 0).is_none()));
+
+        // Expired rows are missing
+        let expiring = Row {
+            expires_at: Some(10),
+            ..row(b"v", 2)
+        };
+        cache.insert(0, b"f", expiring.clone());
+        assert_eq!(cache.get(0, b"f", 9), Some(expiring));
+        assert_eq!(cache.get(0, b"f", 10), None);
+        assert_eq!(cache.stats().usage, 3);
+    }
+}
@@ -0,0 +1,733 @@
+//! Layout of the SSTable files, which hold sorted runs of entries on disk.
+//!
+//! A table is a sequence of blocks, each followed by the trailer described in
+//! the `block` module, ending with a footer:
+//!
+//! +--------------+-----+--------------+-------------+------------+-------+--------+
+//! | Data Block 1 | ... | Data Block N | Meta Blocks | Meta Index | Index | Footer |
+//! +--------------+-----+--------------+-------------+------------+-------+--------+
+//! Data Block = Entries of the table, sorted by key with the comparator of the
+//!              database
+//! Meta Blocks = Other data about the table, like its Range Tombstones or its
+//!               compression dictionary
+//! Meta Index = Handle of every Meta Block, by name
+//! Index = Handle of every Data Block, by the last key it holds
+//!
+//! Data blocks hold up to [`DEFAULT_BLOCK_SIZE`] bytes of entries before
+//! compression, each with the following structure, where (V) fields are
+//! varints:
+//!
+//! +--------------+-----+--------------+-----------------+------------+------------------+----------------+-------+-------------------+----------+
+//! | Key Size (V) | Key | Sequence (V) | Timestamp (16B) | Flags (1B) | Expires At (16B) | Value Size (V) | Value | Operand Count (V) | Operands |
+//! +--------------+-----+--------------+-----------------+------------+------------------+----------------+-------+-------------------+----------+
+//! Flags = Bit 0 is set for Tombstones, bit 1 when the entry has a Value and
+//!         bit 2 when the value expires
+//! Expires At = Time the value expires at in microseconds, only present when
+//!              the value expires
+//! Value Size, Value = Only present when the entry has a Value
+//! Operands = Merge operands of the entry, oldest first, each one prefixed by
+//!            its size as a varint
+//!
+//! The Range Tombstones of the table are stored in a meta block, which is not
+//! compressed, one after the other:
+//!
+//! +----------------+-------+--------------+-----+--------------+-----------------+
+//! | Start Size (V) | Start | End Size (V) | End | Sequence (V) | Timestamp (16B) |
+//! +----------------+-------+--------------+-----+--------------+-----------------+
+//!
+//! A table written with a compression dictionary stores it as is in another
+//! meta block, and all its data blocks are compressed with it.
+//!
+//! The Index and Meta Index are not compressed, and hold one entry per block
+//! with the following structure, where (V) fields are varints:
+//!
+//! +--------------+-----+------------+----------+
+//! | Key Size (V) | Key | Offset (V) | Size (V) |
+//! +--------------+-----+------------+----------+
+//! Offset = Position of the block in the file
+//! Size = Size of the block, including its trailer
+//!
+//! The footer has a fixed size, so it can be found at the end of the file:
+//!
+//! +------------------------+----------------------+-------------------+-----------------+------------+----------+
+//! | Meta Index Offset (8B) | Meta Index Size (8B) | Index Offset (8B) | Index Size (8B) | Magic (8B) | CRC (4B) |
+//! +------------------------+----------------------+-------------------+-----------------+------------+----------+
+//! Magic = Identifies the file as an IronDB table
+//! CRC = CRC-32C of the previous fields of the footer
+//!
+//! The checksums of the footer and every block let corruption be detected
+//! before any of the data is served.
+
+#![allow(dead_code)]
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    block::{self, BLOCK_TRAILER_SIZE},
+    block_cache::{BlockCache, BlockKind, CacheKey},
+    checksum,
+    comparator::Comparator,
+    compression::{Codec, CompressionOptions, CompressionType},
+    encoding::{get_bytes, get_fixed_u64, get_varint, put_varint},
+    memtable::{MemTableEntry, RangeTombstone},
+};
+
+/// Extension of the table files inside the database directory
+pub const TABLE_FILE_EXTENSION: &str = "sst";
+
+/// Size of the entries of a data block before compression, after which a new
+/// block is started
+pub const DEFAULT_BLOCK_SIZE: usize = 4 * 1024;
+
+/// Name of the meta block holding the Range Tombstones of a table
+pub const RANGE_TOMBSTONES_BLOCK: &str = "range.tombstones";
+
+/// Name of the meta block holding the compression dictionary of a table
+pub const DICTIONARY_BLOCK: &str = "compression.dictionary";
+
+/// Flags of an entry of a data block
+const FLAG_TOMBSTONE: u8 = 1;
+const FLAG_VALUE: u8 = 1 << 1;
+const FLAG_EXPIRES: u8 = 1 << 2;
+
+/// Magic bytes the footer of every table ends with, before its checksum
+const TABLE_MAGIC: &[u8; 8] = b"IRONSST\0";
+
+/// Size of the footer at the end of every table
+pub const FOOTER_SIZE: usize = 4 * 8 + 8 + 4;
+
+/// Position and size of a block in a table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHandle {
+    pub offset: u64,
+    /// Size of the block, including its trailer
+    pub size: u64,
+}
+
+/// Handles of the index blocks, stored at the end of a table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+    pub meta_index: BlockHandle,
+    pub index: BlockHandle,
+}
+
+impl Footer {
+    fn encode(&self) -> [u8; FOOTER_SIZE] {
+        let mut buffer = [0; FOOTER_SIZE];
+        buffer[..8].copy_from_slice(&self.meta_index.offset.to_le_bytes());
+        buffer[8..16].copy_from_slice(&self.meta_index.size.to_le_bytes());
+        buffer[16..24].copy_from_slice(&self.index.offset.to_le_bytes());
+        buffer[24..32].copy_from_slice(&self.index.size.to_le_bytes());
+        buffer[32..40].copy_from_slice(TABLE_MAGIC);
+        let crc = checksum::crc32c(&buffer[..40]);
+        buffer[40..].copy_from_slice(&crc.to_le_bytes());
+        buffer
+    }
+
+    /// Decodes a footer, failing if it is not the footer of a table or it is
+    /// corrupted
+    fn decode(buffer: &[u8]) -> io::Result<Self> {
+        if buffer.len() != FOOTER_SIZE || &buffer[32..40] != TABLE_MAGIC {
+            return Err(corrupted("not a table footer"));
+        }
+        let crc = u32::from_le_bytes(buffer[40..].try_into().unwrap());
+        if checksum::crc32c(&buffer[..40]) != crc {
+            return Err(corrupted("table footer checksum mismatch"));
+        }
+
+        let mut fields = &buffer[..32];
+        let mut handle = || BlockHandle {
+            offset: get_fixed_u64(&mut fields).unwrap(),
+            size: get_fixed_u64(&mut fields).unwrap(),
+        };
+        Ok(Footer {
+            meta_index: handle(),
+            index: handle(),
+        })
+    }
+}
+
+/// Writes the blocks of a table, followed by its index blocks and footer
+///
+/// Blocks are given already encoded with [`block::encode_block`], in the
+/// order they are written to the file.
+pub struct TableBuilder<W: Write> {
+    writer: W,
+    offset: u64,
+    index: Vec<(Vec<u8>, BlockHandle)>,
+    meta_index: Vec<(Vec<u8>, BlockHandle)>,
+}
+
+impl<W: Write> TableBuilder<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            offset: 0,
+            index: Vec::new(),
+            meta_index: Vec::new(),
+        }
+    }
+
+    /// Appends a data block, whose entries all have keys up to `last_key`
+    ///
+    /// Blocks must be added in the order of their keys.
+    pub fn add_data_block(&mut self, last_key: &[u8], block: &[u8]) -> io::Result<()> {
+        let handle = self.write_block(block)?;
+        self.index.push((last_key.to_vec(), handle));
+        Ok(())
+    }
+
+    /// Appends a meta block with the given name
+    pub fn add_meta_block(&mut self, name: &str, block: &[u8]) -> io::Result<()> {
+        let handle = self.write_block(block)?;
+        self.meta_index.push((name.as_bytes().to_vec(), handle));
+        Ok(())
+    }
+
+    /// Writes the index blocks and the footer, returning the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let meta_index = encode_index(&self.meta_index);
+        let meta_index = self.write_block(&meta_index)?;
+        let index = encode_index(&self.index);
+        let index = self.write_block(&index)?;
+
+        let footer = Footer { meta_index, index };
+        self.writer.write_all(&footer.encode())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_block(&mut self, block: &[u8]) -> io::Result<BlockHandle> {
+        self.writer.write_all(block)?;
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: block.len() as u64,
+        };
+        self.offset += block.len() as u64;
+        Ok(handle)
+    }
+}
+
+/// An open table, with its index blocks parsed
+pub struct TableReader {
+    file: Mutex<File>,
+    /// Handle of every data block, by the last key it holds
+    index: Vec<(Vec<u8>, BlockHandle)>,
+    /// Handle of every meta block, by name
+    meta_index: Vec<(Vec<u8>, BlockHandle)>,
+    footer: Footer,
+    range_tombstones: Vec<RangeTombstone>,
+    /// Dictionary the data blocks are compressed with, if any
+    dictionary: Option<Vec<u8>>,
+}
+
+impl TableReader {
+    /// Opens a table, reading its footer, index blocks and Range Tombstones,
+    /// whose checksums are always verified
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::open_with(path, |reader, handle| {
+            reader.read_index_block(handle).map(Arc::from)
+        })
+    }
+
+    /// Opens a table like [`TableReader::open`], but reading its index block
+    /// through a block cache, as a block of the table `file_number` of
+    /// `level` of the database with id `cache_id`
+    ///
+    /// The index block stays in the cache after the reader is closed, and is
+    /// pinned there for L0 tables if the cache is configured to, so opening
+    /// the table again doesn't need to read it from disk.
+    pub fn open_with_cache(
+        path: &Path,
+        cache: &BlockCache,
+        cache_id: u64,
+        file_number: u64,
+        level: usize,
+    ) -> io::Result<Self> {
+        Self::open_with(path, |reader, handle| {
+            let key = CacheKey {
+                cache_id,
+                file_number,
+                offset: handle.offset,
+            };
+            cache.get_or_load(key, BlockKind::Index, level, true, || {
+                reader.read_index_block(handle).map(Arc::from)
+            })
+        })
+    }
+
+    /// Opens a table, getting the contents of its index block from
+    /// `load_index`
+    fn open_with(
+        path: &Path,
+        load_index: impl FnOnce(&Self, BlockHandle) -> io::Result<Arc<[u8]>>,
+    ) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if len < FOOTER_SIZE as u64 {
+            return Err(corrupted(&format!(
+                "table is too short: {}",
+                path.display()
+            )));
+        }
+
+        let mut footer = [0; FOOTER_SIZE];
+        file.seek(SeekFrom::Start(len - FOOTER_SIZE as u64))?;
+        file.read_exact(&mut footer)?;
+        let footer = Footer::decode(&footer)?;
+
+        let mut reader = Self {
+            file: Mutex::new(file),
+            index: Vec::new(),
+            meta_index: Vec::new(),
+            footer,
+            range_tombstones: Vec::new(),
+            dictionary: None,
+        };
+        let meta_index = reader.read_index_block(footer.meta_index)?;
+        reader.meta_index = decode_index(&meta_index)
+            .ok_or_else(|| corrupted("corrupted table meta index block"))?;
+        let index = load_index(&reader, footer.index)?;
+        reader.index =
+            decode_index(&index).ok_or_else(|| corrupted("corrupted table index block"))?;
+        if let Some(handle) = reader.meta_block(RANGE_TOMBSTONES_BLOCK) {
+            let block = reader.read_block(handle, true)?;
+            reader.range_tombstones =
+                decode_range_tombstones(&block[..block.len() - BLOCK_TRAILER_SIZE])
+                    .ok_or_else(|| corrupted("corrupted table range tombstones block"))?;
+        }
+        if let Some(handle) = reader.meta_block(DICTIONARY_BLOCK) {
+            let mut block = reader.read_block(handle, true)?;
+            block.truncate(block.len() - BLOCK_TRAILER_SIZE);
+            reader.dictionary = Some(block);
+        }
+        Ok(reader)
+    }
+
+    /// Range Tombstones of the table, oldest first
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Handle of every data block, by the last key it holds
+    pub fn index(&self) -> &[(Vec<u8>, BlockHandle)] {
+        &self.index
+    }
+
+    /// Handle of the data block that may hold `key`, the first one whose last
+    /// key is not before it in the order of `comparator`, or `None` if the key
+    /// is past the end of the table
+    pub fn block_for_key(&self, key: &[u8], comparator: &dyn Comparator) -> Option<BlockHandle> {
+        let idx = self
+            .index
+            .partition_point(|(last_key, _)| comparator.compare(last_key, key).is_lt());
+        self.index.get(idx).map(|(_, handle)| *handle)
+    }
+
+    /// Handle of the meta block with the given name, if the table has one
+    pub fn meta_block(&self, name: &str) -> Option<BlockHandle> {
+        self.meta_index
+            .iter()
+            .find(|(key, _)| key == name.as_bytes())
+            .map(|(_, handle)| *handle)
+    }
+
+    /// Reads a block as stored in the file, with its trailer
+    ///
+    /// The checksum of the block is verified if `verify_checksum` is set, so
+    /// reads that can tolerate corruption can skip the cost.
+    pub fn read_block(&self, handle: BlockHandle, verify_checksum: bool) -> io::Result<Vec<u8>> {
+        let mut block = vec![0; handle.size as usize];
+        {
+            let mut file = self.file.lock().expect("table file lock poisoned");
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut block)?;
+        }
+        if verify_checksum {
+            block::verify_block(&block)?;
+        }
+        Ok(block)
+    }
+
+    /// Reads a data block and decompresses it, with the codec recorded in its
+    /// trailer and the dictionary of the table
+    pub fn read_data_block(
+        &self,
+        handle: BlockHandle,
+        options: &CompressionOptions,
+        verify_checksum: bool,
+    ) -> io::Result<Vec<u8>> {
+        let block = self.read_block(handle, verify_checksum)?;
+        block::decode_block(&block, options, self.dictionary.as_deref(), false)
+    }
+
+    /// Reads every block of the table, failing on the first one that is
+    /// corrupted
+    ///
+    /// The index blocks are read again too, in case the table was opened
+    /// before they got corrupted.
+    pub fn verify_checksums(&self) -> io::Result<()> {
+        let index_blocks = [self.footer.meta_index, self.footer.index];
+        let blocks = self.meta_index.iter().chain(&self.index);
+        for handle in blocks.map(|(_, handle)| *handle).chain(index_blocks) {
+            self.read_block(handle, true)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the contents of an index block, which is not compressed
+    fn read_index_block(&self, handle: BlockHandle) -> io::Result<Vec<u8>> {
+        let mut block = self.read_block(handle, true)?;
+        block.truncate(block.len() - BLOCK_TRAILER_SIZE);
+        Ok(block)
+    }
+}
+
+/// Path of the table with the given file number inside the database directory
+pub fn table_file_path(dir: &Path, file_number: u64) -> PathBuf {
+    dir.join(format!("{}.{}", file_number, TABLE_FILE_EXTENSION))
+}
+
+/// Writes a table holding `entries`, sorted by key, and `range_tombstones`,
+/// compressing its data blocks with `codec` and `dict` if there is one
+///
+/// The file is synced before returning, so the table can be recorded in the
+/// manifest right away.
+pub fn write_table<'a>(
+    path: &Path,
+    entries: impl IntoIterator<Item = &'a MemTableEntry>,
+    range_tombstones: &[RangeTombstone],
+    codec: &dyn Codec,
+    dict: Option<&[u8]>,
+) -> io::Result<()> {
+    let mut builder = TableBuilder::new(BufWriter::new(File::create(path)?));
+    let mut data = Vec::new();
+    let mut last_key: &[u8] = &[];
+    for entry in entries {
+        encode_entry(&mut data, entry);
+        last_key = &entry.key;
+        if data.len() >= DEFAULT_BLOCK_SIZE {
+            let block = block::encode_block(codec, &data, dict);
+            builder.add_data_block(last_key, &block)?;
+            data.clear();
+        }
+    }
+    if !data.is_empty() {
+        let block = block::encode_block(codec, &data, dict);
+        builder.add_data_block(last_key, &block)?;
+    }
+
+    if !range_tombstones.is_empty() {
+        let data = encode_range_tombstones(range_tombstones);
+        let block = block::encode_block(&CompressionType::None, &data, None);
+        builder.add_meta_block(RANGE_TOMBSTONES_BLOCK, &block)?;
+    }
+    if let Some(dict) = dict {
+        let block = block::encode_block(&CompressionType::None, dict, None);
+        builder.add_meta_block(DICTIONARY_BLOCK, &block)?;
+    }
+
+    let file = builder
+        .finish()?
+        .into_inner()
+        .map_err(|err| err.into_error())?;
+    file.sync_all()
+}
+
+/// Appends an entry to the contents of a data block
+fn encode_entry(data: &mut Vec<u8>, entry: &MemTableEntry) {
+    put_varint(data, entry.key.len() as u64);
+    data.extend_from_slice(&entry.key);
+    put_varint(data, entry.seq);
+    data.extend_from_slice(&entry.timestamp.to_le_bytes());
+
+    let mut flags = 0;
+    if entry.deleted {
+        flags |= FLAG_TOMBSTONE;
+    }
+    if entry.value.is_some() {
+        flags |= FLAG_VALUE;
+    }
+    if entry.expires_at.is_some() {
+        flags |= FLAG_EXPIRES;
+    }
+    data.push(flags);
+    if let Some(expires_at) = entry.expires_at {
+        data.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    if let Some(value) = &entry.value {
+        put_varint(data, value.len() as u64);
+        data.extend_from_slice(value);
+    }
+
+    put_varint(data, entry.operands.len() as u64);
+    for operand in &entry.operands {
+        put_varint(data, operand.len() as u64);
+        data.extend_from_slice(operand);
+    }
+}
+
+/// Decodes the entries of a decompressed data block, sorted by key
+pub fn decode_entries(mut data: &[u8]) -> io::Result<Vec<MemTableEntry>> {
+    let mut entries = Vec::new();
+    while !data.is_empty() {
+        let entry =
+            decode_entry(&mut data).ok_or_else(|| corrupted("corrupted table data block"))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn decode_entry(data: &mut &[u8]) -> Option<MemTableEntry> {
+    let key = get_sized_bytes(data)?;
+    let seq = get_varint(data)?;
+    let timestamp = get_fixed_u128(data)?;
+    let flags = *get_bytes(data, 1)?.first()?;
+    let expires_at = match flags & FLAG_EXPIRES {
+        0 => None,
+        _ => Some(get_fixed_u128(data)?),
+    };
+    let value = match flags & FLAG_VALUE {
+        0 => None,
+        _ => Some(get_sized_bytes(data)?),
+    };
+
+    let operand_count = get_varint(data)?;
+    let mut operands = Vec::new();
+    for _ in 0..operand_count {
+        operands.push(get_sized_bytes(data)?);
+    }
+
+    Some(MemTableEntry {
+        key,
+        value,
+        timestamp,
+        expires_at,
+        seq,
+        deleted: flags & FLAG_TOMBSTONE != 0,
+        operands,
+    })
+}
+
+fn encode_range_tombstones(range_tombstones: &[RangeTombstone]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for tombstone in range_tombstones {
+        put_varint(&mut data, tombstone.start.len() as u64);
+        data.extend_from_slice(&tombstone.start);
+        put_varint(&mut data, tombstone.end.len() as u64);
+        data.extend_from_slice(&tombstone.end);
+        put_varint(&mut data, tombstone.seq);
+        data.extend_from_slice(&tombstone.timestamp.to_le_bytes());
+    }
+    data
+}
+
+fn decode_range_tombstones(mut data: &[u8]) -> Option<Vec<RangeTombstone>> {
+    let mut range_tombstones = Vec::new();
+    while !data.is_empty() {
+        range_tombstones.push(RangeTombstone {
+            start: get_sized_bytes(&mut data)?,
+            end: get_sized_bytes(&mut data)?,
+            seq: get_varint(&mut data)?,
+            timestamp: get_fixed_u128(&mut data)?,
+        });
+    }
+    Some(range_tombstones)
+}
+
+/// Reads bytes prefixed by their size as a varint
+fn get_sized_bytes(data: &mut &[u8]) -> Option<Vec<u8>> {
+    let len = get_varint(data)? as usize;
+    Some(get_bytes(data, len)?.to_vec())
+}
+
+fn get_fixed_u128(data: &mut &[u8]) -> Option<u128> {
+    let bytes = get_bytes(data, 16)?;
+    Some(u128::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn corrupted(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Encodes the entries of an index block into a block, which is not
+/// compressed
+fn encode_index(entries: &[(Vec<u8>, BlockHandle)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (key, handle) in entries {
+        put_varint(&mut data, key.len() as u64);
+        data.extend_from_slice(key);
+        put_varint(&mut data, handle.offset);
+        put_varint(&mut data, handle.size);
+    }
+    block::encode_block(&CompressionType::None, &data, None)
+}
+
+fn decode_index(mut data: &[u8]) -> Option<Vec<(Vec<u8>, BlockHandle)>> {
+    let mut entries = Vec::new();
+    while !data.is_empty() {
+        let key_len = get_varint(&mut data)? as usize;
+        let key = get_bytes(&mut data, key_len)?.to_vec();
+        let handle = BlockHandle {
+            offset: get_varint(&mut data)?,
+            size: get_varint(&mut data)?,
+        };
+        entries.push((key, handle));
+    }
+    Some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{comparator::BytewiseComparator, compression::CompressionOptions};
+    use rand::Rng;
+    use std::{
+        fs::{create_dir, remove_dir_all},
+        path::PathBuf,
+    };
+
+    /// Writes a table with a data block per value and a dictionary
+    fn write_blocks(path: &Path, values: &[&[u8]]) {
+        let mut builder = TableBuilder::new(File::create(path).unwrap());
+        for (i, value) in values.iter().enumerate() {
+            let block = block::encode_block(&CompressionType::Lz4, value, None);
+            builder
+                .add_data_block(format!("key{}", i).as_bytes(), &block)
+                .unwrap();
+        }
+        let dict = block::encode_block(&CompressionType::None, b"Smoothie", None);
+        builder.add_meta_block(DICTIONARY_BLOCK, &dict).unwrap();
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_table_checksums() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+        let path = dir.join("1.sst");
+        let lime = br#"{"fruit": "Lime", "smoothie": true}"#.repeat(10);
+        write_blocks(&path, &[b"Apple Pie", &lime]);
+
+        let table = TableReader::open(&path).unwrap();
+        table.verify_checksums().unwrap();
+        assert_eq!(table.index().len(), 2);
+        let (key, handle) = table.index()[1].clone();
+        assert_eq!(key, b"key1");
+        assert_eq!(
+            table.block_for_key(b"key09", &BytewiseComparator),
+            Some(handle)
+        );
+        assert_eq!(table.block_for_key(b"key2", &BytewiseComparator), None);
+        let block = table.read_block(handle, true).unwrap();
+        let options = CompressionOptions::default();
+        assert_eq!(
+            block::decode_block(&block, &options, None, true).unwrap(),
+            lime
+        );
+        let dict = table.meta_block(DICTIONARY_BLOCK).unwrap();
+        assert_eq!(table.read_block(dict, true).unwrap()[..8], *b"Smoothie");
+        drop(table);
+
+        // A flipped bit in a data block is only found when the block is read
+        let mut data = std::fs::read(&path).unwrap();
+        data[handle.offset as usize + 8] ^= 0x01;
+        std::fs::write(&path, &data).unwrap();
+        let table = TableReader::open(&path).unwrap();
+        assert!(table.read_block(handle, true).is_err());
+        assert!(table.read_block(handle, false).is_ok());
+        let err = table.verify_checksums().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        drop(table);
+
+        // A corrupted footer fails opening the table
+        data[handle.offset as usize + 8] ^= 0x01;
+        let footer = data.len() - FOOTER_SIZE;
+        data[footer] ^= 0x01;
+        std::fs::write(&path, &data).unwrap();
+        let err = TableReader::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_table() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+        let path = table_file_path(&dir, 7);
+        assert_eq!(path, dir.join("7.sst"));
+
+        let entries: Vec<MemTableEntry> = (0..200u64)
+            .map(|i| MemTableEntry {
+                key: format!("key{:03}", i).into_bytes(),
+                value: (i % 3 != 0).then(|| br#"{"fruit": "Lime"}"#.to_vec()),
+                timestamp: i as u128,
+                expires_at: (i % 5 == 0).then_some(1000),
+                seq: i + 1,
+                deleted: i % 3 == 0,
+                operands: vec![b"Pie".to_vec(); (i % 2) as usize],
+            })
+            .collect();
+        let range_tombstones = vec![RangeTombstone {
+            start: b"key010".to_vec(),
+            end: b"key020".to_vec(),
+            timestamp: 500,
+            seq: 300,
+        }];
+        write_table(
+            &path,
+            &entries,
+            &range_tombstones,
+            &CompressionType::Lz4,
+            None,
+        )
+        .unwrap();
+
+        // The entries are split in blocks, which decode back to them
+        let table = TableReader::open(&path).unwrap();
+        assert!(table.index().len() > 1);
+        assert_eq!(table.range_tombstones(), range_tombstones.as_slice());
+        let options = CompressionOptions::default();
+        let mut decoded = Vec::new();
+        for (last_key, handle) in table.index() {
+            let block = table.read_data_block(*handle, &options, true).unwrap();
+            let block_entries = decode_entries(&block).unwrap();
+            assert_eq!(&block_entries.last().unwrap().key, last_key);
+            decoded.extend(block_entries);
+        }
+        assert_eq!(decoded.len(), entries.len());
+        for (decoded, entry) in decoded.iter().zip(&entries) {
+            assert_eq!(decoded.key, entry.key);
+            assert_eq!(decoded.value, entry.value);
+            assert_eq!(decoded.timestamp, entry.timestamp);
+            assert_eq!(decoded.expires_at, entry.expires_at);
+            assert_eq!(decoded.seq, entry.seq);
+            assert_eq!(decoded.deleted, entry.deleted);
+            assert_eq!(decoded.operands, entry.operands);
+        }
+
+        // A table written with a dictionary needs it to decode its blocks
+        let dict = br#"{"fruit": "Lime"}Pie"#.repeat(4);
+        write_table(&path, &entries, &[], &CompressionType::Lz4, Some(&dict)).unwrap();
+        let table = TableReader::open(&path).unwrap();
+        assert!(table.meta_block(DICTIONARY_BLOCK).is_some());
+        let (last_key, handle) = &table.index()[0];
+        let block = table.read_data_block(*handle, &options, true).unwrap();
+        assert_eq!(
+            &decode_entries(&block).unwrap().last().unwrap().key,
+            last_key
+        );
+        let block = table.read_block(*handle, true).unwrap();
+        assert!(block::decode_block(&block, &options, None, true).is_err());
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+//! Cache of open SSTable readers, bounding the number of open files.
+//!
+//! Opening a table means opening its file and parsing its index and filter,
+//! so readers are kept around to be reused by later reads. With thousands of
+//! tables keeping all of them open would exceed the limit of file descriptors
+//! of the process, so at most `max_open_files` readers are cached: tables are
+//! opened lazily the first time they are read, and the least recently used
+//! reader is closed to make room for a new one.
+//!
+//! Readers are handed out as an `Arc`, so a reader evicted while it is in use
+//! is only closed once the last read using it finishes.
+
+#![allow(dead_code)]
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Default maximum number of tables kept open
+pub const DEFAULT_MAX_OPEN_FILES: usize = 1000;
+
+struct CachedTable<T> {
+    reader: Arc<T>,
+    /// Last time the reader was used, its position in the LRU list
+    tick: u64,
+}
+
+struct TableCacheState<T> {
+    tables: HashMap<u64, CachedTable<T>>,
+    /// File numbers of the cached tables, least recently used first
+    lru: BTreeMap<u64, u64>,
+    next_tick: u64,
+}
+
+/// An LRU cache of the readers of open tables, by file number
+pub struct TableCache<T> {
+    max_open_files: usize,
+    state: Mutex<TableCacheState<T>>,
+}
+
+impl<T> TableCache<T> {
+    /// Creates a cache keeping at most `max_open_files` tables open
+    pub fn new(max_open_files: usize) -> Self {
+        Self {
+            max_open_files: max_open_files.max(1),
+            state: Mutex::new(TableCacheState {
+                tables: HashMap::new(),
+                lru: BTreeMap::new(),
+                next_tick: 0,
+            }),
+        }
+    }
+
+    /// Gets the reader of a table, opening it with `open` if it is not cached
+    ///
+    /// Opening a table may close the least recently used one. The lock of the
+    /// cache is held while the table is opened, so a table is never opened
+    /// twice.
+    pub fn get_or_open(
+        &self,
+        file_number: u64,
+        open: impl FnOnce() -> io::Result<T>,
+    ) -> io::Result<Arc<T>> {
+        let mut state = self.lock();
+        let tick = state.next_tick;
+        state.next_tick += 1;
+
+        if let Some(table) = state.tables.get_mut(&file_number) {
+            let old_tick = std::mem::replace(&mut table.tick, tick);
+            let reader = table.reader.clone();
+            state.lru.remove(&old_tick);
+            state.lru.insert(tick, file_number);
+            return Ok(reader);
+        }
+
+        let reader = Arc::new(open()?);
+        while state.tables.len() >= self.max_open_files {
+            let Some((_, evicted)) = state.lru.pop_first() else {
+                break;
+            };
+            state.tables.remove(&evicted);
+        }
+        let table = CachedTable {
+            reader: reader.clone(),
+            tick,
+        };
+        state.tables.insert(file_number, table);
+        state.lru.insert(tick, file_number);
+        Ok(reader)
+    }
+
+    /// Closes the reader of a table, once the table is deleted
+    pub fn evict(&self, file_number: u64) {
+        let mut state = self.lock();
+        if let Some(table) = state.tables.remove(&file_number) {
+            state.lru.remove(&table.tick);
+        }
+    }
+
+    /// Number of tables open
+    pub fn len(&self) -> usize {
+        self.lock().tables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TableCacheState<T>> {
+        self.state.lock().expect("table cache lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::{
+        cell::Cell,
+        fs::{create_dir, remove_dir_all, File},
+        path::PathBuf,
+    };
+
+    #[test]
+    fn test_table_cache_limits_open_files() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+        for file_number in 0..4 {
+            File::create(dir.join(format!("{}.sst", file_number))).unwrap();
+        }
+
+        let opened = Cell::new(0);
+        let cache = TableCache::new(2);
+        let get = |file_number: u64| {
+            cache
+                .get_or_open(file_number, || {
+                    opened.set(opened.get() + 1);
+                    File::open(dir.join(format!("{}.sst", file_number)))
+                })
+                .unwrap()
+        };
+
+        get(0);
+        get(1);
+        get(0);
+        assert_eq!(opened.get(), 2);
+
+        // Table 1 is the least recently used, so it is closed
+        let reader = get(2);
+        assert_eq!(cache.len(), 2);
+        get(0);
+        assert_eq!(opened.get(), 3);
+        get(1);
+        assert_eq!(opened.get(), 4);
+
+        // Readers in use stay valid after being evicted
+        assert!(reader.metadata().is_ok());
+        cache.evict(1);
+        assert_eq!(cache.len(), 1);
+        get(0);
+        assert_eq!(opened.get(), 4);
+
+        let err = cache
+            .get_or_open(9, || File::open(dir.join("9.sst")))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(cache.len(), 1);
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,517 @@
+//! Transactions over a [`Db`].
+//!
+//! Optimistic transactions take no locks while running. Reads record the
+//! version of every key read and writes are buffered in a
+//! [`WriteBatchWithIndex`], so the transaction can read them back. On commit
+//! the transaction checks that no key it read or wrote changed since it
+//! started, and if so applies the buffered writes atomically. Otherwise it
+//! fails with [`Error::Conflict`] and nothing is written.
+//!
+//! Pessimistic transactions instead lock every key they use through the
+//! lock manager of the database: shared for reads and exclusive for writes.
+//! Locks are held until the transaction commits or rolls back, so commits
+//! never conflict, but taking a lock may fail with [`Error::LockTimeout`] or
+//! [`Error::Deadlock`]. The transaction should then be rolled back and retried.
+//!
+//! Pessimistic transactions can also be committed in two phases. Preparing the
+//! transaction persists its writes in the WAL under a name, and the commit or
+//! rollback that follows is logged too. If the database is reopened in the
+//! meantime, the prepared transaction is recovered and can be resolved by name
+//! with [`Db::commit_prepared`] or [`Db::rollback_prepared`].
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+
+use crate::{
+    db::Db,
+    error::{Error, Result},
+    lock_manager::{LockMode, TxnId},
+    write_batch::WriteBatchWithIndex,
+};
+
+/// A transaction that detects conflicts with other writers at commit
+pub struct OptimisticTransaction<'a> {
+    db: &'a Db,
+    /// Last sequence number of the database when the transaction started
+    start_seq: u64,
+    /// Version of every key read from the database
+    reads: HashMap<Vec<u8>, u64>,
+    /// Writes to apply on commit
+    writes: WriteBatchWithIndex,
+}
+
+/// A transaction that locks the keys it uses until it finishes
+///
+/// Dropping the transaction rolls it back
+pub struct PessimisticTransaction<'a> {
+    db: &'a Db,
+    id: TxnId,
+    /// Writes to apply on commit
+    writes: WriteBatchWithIndex,
+    /// Name the transaction was prepared with, if it was
+    prepared: Option<String>,
+}
+
+impl Db {
+    /// Starts a pessimistic transaction
+    pub fn begin_pessimistic(&self) -> PessimisticTransaction<'_> {
+        PessimisticTransaction {
+            db: self,
+            id: self.locks.new_txn(),
+            writes: WriteBatchWithIndex::with_comparator(self.comparator()),
+            prepared: None,
+        }
+    }
+
+    /// Starts an optimistic transaction
+    pub fn begin_optimistic(&self) -> OptimisticTransaction<'_> {
+        OptimisticTransaction {
+            db: self,
+            start_seq: self.last_sequence(),
+            reads: HashMap::new(),
+            writes: WriteBatchWithIndex::with_comparator(self.comparator()),
+        }
+    }
+}
+
+impl OptimisticTransaction<'_> {
+    /// Gets the value of a key, seeing the writes of this transaction
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get_from_batch(key) {
+            return Ok(value.map(|v| v.to_owned()));
+        }
+
+        let (value, version) = self.db.get_with_version(key)?;
+        self.reads.entry(key.to_owned()).or_insert(version);
+        Ok(value)
+    }
+
+    /// Sets the value of a key when the transaction commits
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.set(key, value);
+    }
+
+    /// Deletes a key when the transaction commits
+    pub fn delete(&mut self, key: &[u8]) {
+        self.writes.delete(key);
+    }
+
+    /// Applies the writes of the transaction, or returns [`Error::Conflict`]
+    /// if any key read or written by it changed since it started
+    pub fn commit(self) -> Result<()> {
+        let start_seq = self.start_seq;
+        let reads = self.reads;
+        self.db.write_if(self.writes.batch(), |state| {
+            for (key, read) in &reads {
+                if state.version(key)? != *read || *read > start_seq {
+                    return Err(Error::Conflict);
+                }
+            }
+            for (key, _) in self.writes.iter() {
+                if state.version(key)? > start_seq {
+                    return Err(Error::Conflict);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Discards the writes of the transaction
+    pub fn rollback(self) {}
+}
+
+impl PessimisticTransaction<'_> {
+    /// Gets the value of a key, seeing the writes of this transaction. The key
+    /// is locked shared
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lock(key, LockMode::Shared)?;
+        self.read(key)
+    }
+
+    /// Gets the value of a key locking it exclusive, so it can be written
+    /// later without waiting for other readers to finish
+    pub fn get_for_update(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lock(key, LockMode::Exclusive)?;
+        self.read(key)
+    }
+
+    /// Sets the value of a key when the transaction commits. The key is locked
+    /// exclusive
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.lock(key, LockMode::Exclusive)?;
+        self.writes.set(key, value);
+        Ok(())
+    }
+
+    /// Deletes a key when the transaction commits. The key is locked exclusive
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.lock(key, LockMode::Exclusive)?;
+        self.writes.delete(key);
+        Ok(())
+    }
+
+    /// Persists the writes of the transaction in the WAL under `name`, so they
+    /// can still be committed after a restart. The transaction keeps its locks
+    /// and can not be written to anymore
+    pub fn prepare(&mut self, name: &str) -> Result<()> {
+        self.check_not_prepared()?;
+        self.db.prepare(name, self.writes.batch())?;
+        self.prepared = Some(name.to_owned());
+        Ok(())
+    }
+
+    /// Applies the writes of the transaction and releases its locks
+    pub fn commit(self) -> Result<()> {
+        match &self.prepared {
+            Some(name) => self.db.commit_prepared(name),
+            None => self.db.write(self.writes.batch()),
+        }
+    }
+
+    /// Discards the writes of the transaction and releases its locks
+    pub fn rollback(self) -> Result<()> {
+        match &self.prepared {
+            Some(name) => self.db.rollback_prepared(name),
+            None => Ok(()),
+        }
+    }
+
+    fn lock(&self, key: &[u8], mode: LockMode) -> Result<()> {
+        self.check_not_prepared()?;
+        self.db.locks.lock(self.id, key, mode, self.db.lock_timeout)
+    }
+
+    fn check_not_prepared(&self) -> Result<()> {
+        match &self.prepared {
+            Some(name) => Err(Error::InvalidArgument(format!(
+                "transaction {} is already prepared",
+                name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get_from_batch(key) {
+            return Ok(value.map(|v| v.to_owned()));
+        }
+        self.db.get(key)
+    }
+}
+
+impl Drop for PessimisticTransaction<'_> {
+    fn drop(&mut self) {
+        self.db.locks.unlock_all(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DbOptions;
+    use rand::Rng;
+    use std::{
+        fs::remove_dir_all,
+        path::PathBuf,
+        sync::{Arc, Barrier},
+        thread,
+        time::Duration,
+    };
+
+    fn open_db() -> (PathBuf, Db) {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        let db = Db::open(&dir).unwrap();
+        (dir, db)
+    }
+
+    #[test]
+    fn test_commit() {
+        let (dir, db) = open_db();
+        db.set(b"Apple", b"1").unwrap();
+
+        let mut txn = db.begin_optimistic();
+        assert_eq!(txn.get(b"Apple").unwrap().unwrap(), b"1");
+        txn.set(b"Apple", b"2");
+        txn.set(b"Lime", b"3");
+        txn.delete(b"Lime");
+
+        // Nothing is visible before commit
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"1");
+        txn.commit().unwrap();
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"2");
+        assert_eq!(db.get(b"Lime").unwrap(), None);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_your_own_writes() {
+        let (dir, db) = open_db();
+        db.set(b"Apple", b"1").unwrap();
+
+        let mut txn = db.begin_optimistic();
+        txn.set(b"Lime", b"2");
+        assert_eq!(txn.get(b"Lime").unwrap().unwrap(), b"2");
+        txn.delete(b"Apple");
+        assert_eq!(txn.get(b"Apple").unwrap(), None);
+        txn.rollback();
+
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"1");
+        assert_eq!(db.get(b"Lime").unwrap(), None);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_conflict() {
+        let (dir, db) = open_db();
+        db.set(b"Apple", b"1").unwrap();
+
+        let mut txn = db.begin_optimistic();
+        txn.get(b"Apple").unwrap();
+        txn.set(b"Lime", b"2");
+        db.set(b"Apple", b"3").unwrap();
+
+        assert!(matches!(txn.commit(), Err(Error::Conflict)));
+        assert_eq!(db.get(b"Lime").unwrap(), None);
+
+        // Keys created after the transaction started also conflict
+        let mut txn = db.begin_optimistic();
+        assert_eq!(txn.get(b"Orange").unwrap(), None);
+        db.set(b"Orange", b"4").unwrap();
+        txn.set(b"Lime", b"2");
+        assert!(matches!(txn.commit(), Err(Error::Conflict)));
+
+        // Versions of keys flushed to tables are still checked
+        let mut txn = db.begin_optimistic();
+        txn.get(b"Apple").unwrap();
+        db.flush().unwrap();
+        txn.set(b"Apple", b"5");
+        txn.commit().unwrap();
+        let mut txn = db.begin_optimistic();
+        txn.get(b"Apple").unwrap();
+        db.set(b"Apple", b"6").unwrap();
+        db.flush().unwrap();
+        txn.set(b"Lime", b"2");
+        assert!(matches!(txn.commit(), Err(Error::Conflict)));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_conflict() {
+        let (dir, db) = open_db();
+
+        let mut txn = db.begin_optimistic();
+        txn.set(b"Apple", b"1");
+        db.set(b"Apple", b"2").unwrap();
+
+        assert!(matches!(txn.commit(), Err(Error::Conflict)));
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"2");
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unrelated_writes_do_not_conflict() {
+        let (dir, db) = open_db();
+        db.set(b"Apple", b"1").unwrap();
+
+        let mut txn = db.begin_optimistic();
+        txn.get(b"Apple").unwrap();
+        txn.set(b"Apple", b"2");
+        db.set(b"Lime", b"3").unwrap();
+
+        txn.commit().unwrap();
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"2");
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_counter() {
+        let (dir, db) = open_db();
+        let db = Arc::new(db);
+        db.set(b"counter", &0u64.to_le_bytes()).unwrap();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        loop {
+                            let mut txn = db.begin_optimistic();
+                            let value = txn.get(b"counter").unwrap().unwrap();
+                            let counter = u64::from_le_bytes(value.try_into().unwrap());
+                            txn.set(b"counter", &(counter + 1).to_le_bytes());
+                            match txn.commit() {
+                                Ok(()) => break,
+                                Err(Error::Conflict) => continue,
+                                Err(err) => panic!("{}", err),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let value = db.get(b"counter").unwrap().unwrap();
+        assert_eq!(u64::from_le_bytes(value.try_into().unwrap()), 100);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pessimistic_commit() {
+        let (dir, db) = open_db();
+        db.set(b"Apple", b"1").unwrap();
+
+        let mut txn = db.begin_pessimistic();
+        assert_eq!(txn.get_for_update(b"Apple").unwrap().unwrap(), b"1");
+        txn.set(b"Apple", b"2").unwrap();
+        assert_eq!(txn.get(b"Apple").unwrap().unwrap(), b"2");
+        txn.delete(b"Lime").unwrap();
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"1");
+        txn.commit().unwrap();
+
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"2");
+        // Locks were released
+        let mut txn = db.begin_pessimistic();
+        txn.set(b"Apple", b"3").unwrap();
+        txn.rollback().unwrap();
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"2");
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pessimistic_lock_timeout() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        let options = DbOptions {
+            lock_timeout: Duration::from_millis(20),
+            ..DbOptions::default()
+        };
+        let db = Db::open_with_options(&dir, options).unwrap();
+
+        let mut reader = db.begin_pessimistic();
+        reader.get(b"Apple").unwrap();
+        let mut other_reader = db.begin_pessimistic();
+        other_reader.get(b"Apple").unwrap();
+        let mut writer = db.begin_pessimistic();
+        assert!(matches!(
+            writer.set(b"Apple", b"1"),
+            Err(Error::LockTimeout)
+        ));
+
+        drop(reader);
+        drop(other_reader);
+        writer.set(b"Apple", b"1").unwrap();
+        writer.commit().unwrap();
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pessimistic_deadlock() {
+        let (dir, db) = open_db();
+        let barrier = Barrier::new(2);
+
+        // Both transactions lock one key and then wait for the other's key, one
+        // of them is aborted and the other commits
+        let results: Vec<Result<()>> = thread::scope(|s| {
+            let threads: Vec<_> = [
+                (b"Apple".as_slice(), b"Lime".as_slice()),
+                (b"Lime", b"Apple"),
+            ]
+            .into_iter()
+            .map(|(first, second)| {
+                let (db, barrier) = (&db, &barrier);
+                s.spawn(move || {
+                    let mut txn = db.begin_pessimistic();
+                    txn.set(first, b"1").unwrap();
+                    barrier.wait();
+                    txn.set(second, b"2")?;
+                    txn.commit()
+                })
+            })
+            .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results.iter().any(|r| matches!(r, Err(Error::Deadlock))));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pessimistic_concurrent_counter() {
+        let (dir, db) = open_db();
+        db.set(b"counter", &0u64.to_le_bytes()).unwrap();
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..25 {
+                        let mut txn = db.begin_pessimistic();
+                        let value = txn.get_for_update(b"counter").unwrap().unwrap();
+                        let counter = u64::from_le_bytes(value.try_into().unwrap());
+                        txn.set(b"counter", &(counter + 1).to_le_bytes()).unwrap();
+                        txn.commit().unwrap();
+                    }
+                });
+            }
+        });
+
+        let value = db.get(b"counter").unwrap().unwrap();
+        assert_eq!(u64::from_le_bytes(value.try_into().unwrap()), 100);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_two_phase_commit() {
+        let (dir, db) = open_db();
+
+        let mut txn = db.begin_pessimistic();
+        txn.set(b"Apple", b"1").unwrap();
+        txn.prepare("first").unwrap();
+        assert!(matches!(
+            txn.set(b"Lime", b"2"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert_eq!(db.prepared_transactions(), vec!["first"]);
+        assert_eq!(db.get(b"Apple").unwrap(), None);
+        txn.commit().unwrap();
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"1");
+
+        let mut txn = db.begin_pessimistic();
+        txn.set(b"Apple", b"2").unwrap();
+        txn.prepare("second").unwrap();
+        txn.rollback().unwrap();
+
+        let mut txn = db.begin_pessimistic();
+        txn.set(b"Lime", b"3").unwrap();
+        txn.prepare("third").unwrap();
+        drop(txn);
+        drop(db);
+
+        // Prepared transactions survive restarts
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(db.get(b"Apple").unwrap().unwrap(), b"1");
+        assert_eq!(db.get(b"Lime").unwrap(), None);
+        assert_eq!(db.prepared_transactions(), vec!["third"]);
+        db.commit_prepared("third").unwrap();
+        assert_eq!(db.get(b"Lime").unwrap().unwrap(), b"3");
+        assert!(db.prepared_transactions().is_empty());
+        assert!(matches!(db.rollback_prepared("third"), Err(Error::Io(_))));
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
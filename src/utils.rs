@@ -1,6 +1,8 @@
 use std::{
-    fs::read_dir,
+    fs::{read_dir, File},
+    io,
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 /// Gets the set of files with an extension for a given directory
@@ -8,10 +10,55 @@ pub fn files_with_ext(dir: &Path, ext: &str) -> Vec<PathBuf> {
     let mut files = Vec::new();
     for file in read_dir(dir).unwrap() {
         let path = file.unwrap().path();
-        if path.extension().unwrap() == ext {
+        if path.extension().is_some_and(|e| e == ext) {
             files.push(path);
         }
     }
 
     files
 }
+
+/// Current time in microseconds since the UNIX epoch
+pub fn now_micros() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros()
+}
+
+/// Reserves `len` bytes of disk space for a file starting at `offset`, without
+/// changing the size of the file
+///
+/// Filesystems that don't support preallocation are silently ignored, since
+/// preallocation is only a performance optimization
+#[cfg(target_os = "linux")]
+pub fn fallocate(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(()),
+        _ => Err(err),
+    }
+}
+
+/// Reserves `len` bytes of disk space for a file starting at `offset`, without
+/// changing the size of the file
+///
+/// Preallocation is only implemented on Linux, elsewhere this does nothing
+#[cfg(not(target_os = "linux"))]
+pub fn fallocate(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
@@ -1,465 +1,3536 @@
 //! A Write Ahead Log storage for persisting operations performed to
 //! the Database.
-//! An entry in the Log has the following structure:
+//!
+//! The WAL is split into segments (files) inside a directory. Once the active
+//! segment grows over the configured size it gets closed and a new one is
+//! started. Each closed segment is tagged with the highest sequence number it
+//! contains, so it can be deleted as soon as all its operations have been
+//! persisted in SSTables.
+//!
+//! When archiving is enabled, obsolete segments are moved to the `archive`
+//! directory instead, and kept there according to a retention policy, so
+//! consumers of [`Wal::get_updates_since`] that fall behind can catch up.
+//!
+//! Every segment has a log number, which is also its file name, and starts
+//! with a file header:
+//!
+//! +------------+--------------+-----------------+-----------------+----------+
+//! | Magic (8B) | Version (4B) | Log Number (8B) | Created At (8B) | CRC (4B) |
+//! +------------+--------------+-----------------+-----------------+----------+
+//! Magic = Identifies the file as an IronDB WAL segment
+//! Version = Version of the format of the segment
+//! Log Number = Log number of the segment
+//! Created At = Time the segment was created in microseconds
+//! CRC = CRC-32C of the previous fields of the header
+//!
+//! Segments written before the header was introduced start straight with the
+//! first record. They are still readable, and are considered version 0.
+//! Segments of older versions can be rewritten in the current version with
+//! [`Wal::upgrade_dir`].
+//!
+//! The segments of the first version of the WAL have no records either, only
+//! one entry after the other:
 //!
 //! +---------------+---------------+-----------------+-...-+--...--+-----------------+
 //! | Key Size (8B) | Tombstone(1B) | Value Size (8B) | Key | Value | Timestamp (16B) |
 //! +---------------+---------------+-----------------+-...-+--...--+-----------------+
+//! Value Size = Length of the Value data, missing for Tombstones
+//!
+//! They are also readable as version 0. Their entries have no sequence number,
+//! so they are numbered after the operations of the segments before them.
+//!
+//! Segments are preallocated on disk and, when configured, obsolete segments
+//! are recycled: they are renamed to the new log number and overwritten from
+//! the start. To tell new records apart from the stale ones left over in a
+//! recycled file, each record is prefixed with a header:
+//!
+//! +----------+-----------------+-------------+-----------+---...---+
+//! | CRC (4B) | Log Number (8B) | Length (4B) | Type (1B) | Payload |
+//! +----------+-----------------+-------------+-----------+---...---+
+//! CRC = CRC-32C of the Log Number, Length, Type and Payload
+//! Log Number = Log number of the segment the record was written to
+//! Length = Length of the Payload
+//! Type = Type of the record
+//! Payload = Data of the record
+//!
+//! When compression is enabled, every segment starts with a record advertising
+//! the compression algorithm, whose payload is a single byte. The payloads of
+//! all the entry records that follow are compressed independently with it.
+//!
+//! The (uncompressed) payload of an entry record has the following structure,
+//! where (V) fields are varints:
+//!
+//! +--------------+-----------+--------------+----------------+-...-+--...--+----------------+
+//! | Sequence (V) | Kind (1B) | Key Size (V) | Value Size (V) | Key | Value | Timestamp (8B) |
+//! +--------------+-----------+--------------+----------------+-...-+--...--+----------------+
+//! Sequence = Sequence number of the operation, increasing across segments
+//! Kind = Kind of operation: 0 for a value, 1 for a Tombstone, 2 for a
+//!        merge operand, 3 for a range Tombstone, whose Key is the start of
+//!        the range and Value its (exclusive) end, and 4 for a value with a
+//!        TTL. Versions before 5 only have values and Tombstones, version 5
+//!        has no range Tombstones and version 6 has no values with a TTL.
+//!        The high bit is set for operations on a column family other than
+//!        the default one, and the Kind is then followed by the id of the
+//!        column family as a varint
 //! Key Size = Length of the Key data
-//! Tombstone = If this record was deleted and has a value
-//! Value Size = Length of the Value data
+//! Value Size = Length of the Value data, missing for Tombstones
 //! Key = Key data
-//! Value = Value data
+//! Value = Value data, or the merge operand. Values with a TTL are followed
+//!         by the TTL in microseconds as a varint
 //! Timestamp = Timestamp of the operation in microseconds
+//!
+//! The operations of a write batch are written together in a single batch
+//! record, so they are recovered all or none. Its payload has the following
+//! structure:
+//!
+//! +--------------------+-----------+----------------+--------------+-----+--------------+
+//! | First Sequence (V) | Count (V) | Timestamp (8B) | Operation #1 | ... | Operation #N |
+//! +--------------------+-----------+----------------+--------------+-----+--------------+
+//! First Sequence = Sequence number of the first operation, the rest follow it
+//! Count = Number of operations in the batch
+//! Timestamp = Timestamp of all the operations in microseconds
+//!
+//! where every operation has the following structure:
+//!
+//! +-----------+--------------+----------------+-...-+--...--+
+//! | Kind (1B) | Key Size (V) | Value Size (V) | Key | Value |
+//! +-----------+--------------+----------------+-...-+--...--+
+//!
+//! Transactions committed in two phases first write a prepare record holding
+//! their operations, which are not applied yet. They are applied by a later
+//! commit record, or dropped by a rollback record. Prepared transactions that
+//! were neither committed nor rolled back are recovered when loading the WAL,
+//! and the segments holding them are kept until they are. The payloads of these
+//! records have the following structures:
+//!
+//! +---------------+--...---+-----------+--------------+-----+--------------+
+//! | Name Size (V) |  Name  | Count (V) | Operation #1 | ... | Operation #N |
+//! +---------------+--...---+-----------+--------------+-----+--------------+
+//! Prepare record
+//!
+//! +---------------+--...---+---------------------------+
+//! | Name Size (V) |  Name  | Payload of a batch record |
+//! +---------------+--...---+---------------------------+
+//! Commit record, holding the operations again with their sequence numbers
+//!
+//! +---------------+--...---+
+//! | Name Size (V) |  Name  |
+//! +---------------+--...---+
+//! Rollback record
+//!
+//! All fixed width fields are little endian, so segments are the same no matter
+//! the platform they were written on.
 
 #![allow(dead_code)]
 
 use std::{
-    fs::{remove_file, File, OpenOptions},
-    io::{self, BufReader, BufWriter, Read, Write},
+    collections::{BTreeMap, VecDeque},
+    fs::{copy, create_dir_all, remove_file, rename, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    checksum,
+    column_family::{ColumnFamilyId, DEFAULT_COLUMN_FAMILY},
+    comparator::{BytewiseComparator, Comparator},
+    compression::CompressionType,
+    encoding::{get_bytes, get_fixed_u64, get_varint, put_varint, MAX_VARINT_LEN},
+    memtable::MemTable,
+    utils::{fallocate, files_with_ext, now_micros},
+    write_batch::{WriteBatch, WriteOp},
 };
 
-use crate::{memtable::MemTable, utils::files_with_ext};
+/// Default size at which the active WAL segment is rotated (64 MiB)
+pub const DEFAULT_MAX_SEGMENT_SIZE: usize = 64 * 1024 * 1024;
+
+/// Name of the directory obsolete segments are archived to
+const ARCHIVE_DIR: &str = "archive";
+
+/// Default amount of disk space reserved at a time for the active segment (1 MiB)
+pub const DEFAULT_PREALLOCATION_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Magic bytes every WAL segment starts with
+const WAL_MAGIC: &[u8; 8] = b"IRONWAL\0";
+
+/// Version of the format of the segments written by this code
+///
+/// 1: Fixed width entries
+/// 2: Varint sizes and 8 bytes timestamps in entries
+/// 3: Batch records
+/// 4: Two-phase commit records
+/// 5: Merge operands
+/// 6: Range Tombstones
+/// 7: Values with a TTL
+/// 8: Column families
+pub const WAL_FORMAT_VERSION: u32 = 8;
+
+/// Size of the header at the start of every segment
+const WAL_HEADER_SIZE: usize = 8 + 4 + 8 + 8 + 4;
+
+/// Size of the header in front of every record
+const RECORD_HEADER_SIZE: usize = 4 + 8 + 4 + 1;
+
+/// Header at the start of a WAL segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalHeader {
+    /// Version of the format of the segment
+    pub version: u32,
+    /// Log number of the segment
+    pub log_number: u64,
+    /// Time the segment was created in microseconds
+    pub created_at: u64,
+}
+
+impl WalHeader {
+    fn encode(&self) -> [u8; WAL_HEADER_SIZE] {
+        let mut buffer = [0; WAL_HEADER_SIZE];
+        buffer[..8].copy_from_slice(WAL_MAGIC);
+        buffer[8..12].copy_from_slice(&self.version.to_le_bytes());
+        buffer[12..20].copy_from_slice(&self.log_number.to_le_bytes());
+        buffer[20..28].copy_from_slice(&self.created_at.to_le_bytes());
+        let crc = checksum::crc32c(&buffer[..28]);
+        buffer[28..].copy_from_slice(&crc.to_le_bytes());
+        buffer
+    }
+
+    /// Decodes the header at the start of a segment
+    ///
+    /// Returns `None` if the data doesn't start with the magic bytes, and an
+    /// error if it does but the header is corrupted or of an unknown version
+    fn decode(buffer: &[u8]) -> io::Result<Option<Self>> {
+        if buffer.len() < WAL_HEADER_SIZE || &buffer[..8] != WAL_MAGIC {
+            return Ok(None);
+        }
+
+        let crc = u32::from_le_bytes(buffer[28..32].try_into().unwrap());
+        if checksum::crc32c(&buffer[..28]) != crc {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupted WAL segment header",
+            ));
+        }
+
+        let version = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
+        if version == 0 || version > WAL_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported WAL format version {}", version),
+            ));
+        }
+
+        Ok(Some(Self {
+            version,
+            log_number: u64::from_le_bytes(buffer[12..20].try_into().unwrap()),
+            created_at: u64::from_le_bytes(buffer[20..28].try_into().unwrap()),
+        }))
+    }
+}
+
+/// Type of a record in the WAL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordType {
+    /// A single set or delete operation
+    Entry = 1,
+    /// Compression algorithm used for the entries of the segment
+    SetCompression = 2,
+    /// The operations of a write batch
+    Batch = 3,
+    /// The operations of a transaction that is prepared but not committed
+    Prepare = 4,
+    /// Commit of a prepared transaction
+    Commit = 5,
+    /// Rollback of a prepared transaction
+    Rollback = 6,
+}
+
+impl RecordType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(RecordType::Entry),
+            2 => Some(RecordType::SetCompression),
+            3 => Some(RecordType::Batch),
+            4 => Some(RecordType::Prepare),
+            5 => Some(RecordType::Commit),
+            6 => Some(RecordType::Rollback),
+            _ => None,
+        }
+    }
+}
 
 pub struct WalEntry {
+    pub seq: u64,
+    /// Column family the operation writes to
+    pub column_family: ColumnFamilyId,
     pub key: Vec<u8>,
+    /// Value of the entry, or the operand for merges. `None` for Tombstones
     pub value: Option<Vec<u8>>,
     pub timestamp: u128,
     pub deleted: bool,
+    /// Merge operand mark
+    pub merge: bool,
+    /// End of the range deleted by a range Tombstone, which starts at `key`
+    pub range_end: Option<Vec<u8>>,
+    /// Time after the write the value expires, `None` if it never does
+    pub ttl: Option<Duration>,
+}
+
+impl WalEntry {
+    fn from_op(seq: u64, column_family: ColumnFamilyId, op: WriteOp, timestamp: u128) -> Self {
+        let mut ttl = None;
+        let (key, value, deleted, merge, range_end) = match op {
+            WriteOp::Set { key, value } => (key, Some(value), false, false, None),
+            WriteOp::Delete { key } => (key, None, true, false, None),
+            WriteOp::Merge { key, operand } => (key, Some(operand), false, true, None),
+            WriteOp::SetWithTtl {
+                key,
+                value,
+                ttl: op_ttl,
+            } => {
+                ttl = Some(op_ttl);
+                (key, Some(value), false, false, None)
+            }
+            WriteOp::DeleteRange { start, end } => (start, None, true, false, Some(end)),
+        };
+        Self {
+            seq,
+            column_family,
+            key,
+            value,
+            timestamp,
+            deleted,
+            merge,
+            range_end,
+            ttl,
+        }
+    }
+
+    /// The operation this entry records
+    pub fn to_op(&self) -> WriteOp {
+        let key = self.key.clone();
+        match &self.value {
+            _ if self.range_end.is_some() => WriteOp::DeleteRange {
+                start: key,
+                end: self.range_end.clone().unwrap(),
+            },
+            _ if self.deleted => WriteOp::Delete { key },
+            Some(operand) if self.merge => WriteOp::Merge {
+                key,
+                operand: operand.clone(),
+            },
+            Some(value) if self.ttl.is_some() => WriteOp::SetWithTtl {
+                key,
+                value: value.clone(),
+                ttl: self.ttl.unwrap(),
+            },
+            value => WriteOp::Set {
+                key,
+                value: value.clone().unwrap_or_default(),
+            },
+        }
+    }
+}
+
+/// Kind byte of an operation in an entry
+const KIND_VALUE: u8 = 0;
+const KIND_TOMBSTONE: u8 = 1;
+const KIND_MERGE: u8 = 2;
+const KIND_RANGE_TOMBSTONE: u8 = 3;
+const KIND_VALUE_WITH_TTL: u8 = 4;
+
+/// Bit set on the kind of operations on a column family other than the
+/// default one
+const KIND_COLUMN_FAMILY_FLAG: u8 = 0x80;
+
+/// Configuration of a [`Wal`]
+#[derive(Debug, Clone)]
+pub struct WalOptions {
+    /// Size in bytes after which the active segment is closed and a new one
+    /// is started
+    pub max_segment_size: usize,
+    /// Bytes reserved on disk each time the active segment runs out of
+    /// preallocated space. `0` disables preallocation
+    pub preallocation_block_size: usize,
+    /// Number of obsolete segments kept around to be reused by new segments
+    /// instead of being deleted. `0` disables recycling. Ignored when
+    /// archiving is enabled
+    pub recycle_log_file_num: usize,
+    /// Compression applied to each entry written to the WAL
+    pub compression: CompressionType,
+    /// Time archived segments are kept for since they were last written to
+    ///
+    /// Archiving is enabled when either this or `archive_size_limit` are set
+    pub archive_ttl: Option<Duration>,
+    /// Maximum total size in bytes of the archived segments, the oldest
+    /// segments are deleted when going over it
+    ///
+    /// Archiving is enabled when either this or `archive_ttl` are set
+    pub archive_size_limit: Option<u64>,
+}
+
+impl WalOptions {
+    /// Whether obsolete segments are archived instead of deleted
+    pub fn archive_enabled(&self) -> bool {
+        self.archive_ttl.is_some() || self.archive_size_limit.is_some()
+    }
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        Self {
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            preallocation_block_size: DEFAULT_PREALLOCATION_BLOCK_SIZE,
+            recycle_log_file_num: 0,
+            compression: CompressionType::None,
+            archive_ttl: None,
+            archive_size_limit: None,
+        }
+    }
+}
+
+/// Statistics about the data written to a [`Wal`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalStats {
+    /// Bytes of entries before compression
+    pub bytes_before_compression: u64,
+    /// Bytes of entries after compression, as written to disk
+    pub bytes_after_compression: u64,
+}
+
+impl WalStats {
+    /// Ratio between the size of the entries before and after compression
+    ///
+    /// A ratio of 2.0 means the entries take half the space on disk
+    pub fn compression_ratio(&self) -> f64 {
+        if self.bytes_after_compression == 0 {
+            return 1.0;
+        }
+        self.bytes_before_compression as f64 / self.bytes_after_compression as f64
+    }
+}
+
+/// Point in time up to which the WAL is replayed when loading it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryTarget {
+    /// Replay the operations up to the first one that happened after this
+    /// timestamp in microseconds
+    Timestamp(u128),
+    /// Replay the operations up to this sequence number (inclusive)
+    Sequence(u64),
+}
+
+impl RecoveryTarget {
+    /// Whether an operation happened before the target
+    fn includes(&self, entry: &WalEntry) -> bool {
+        self.includes_operation(entry.seq, entry.timestamp)
+    }
+
+    /// Whether the operation with the given sequence number and timestamp
+    /// happened before the target
+    pub fn includes_operation(&self, seq: u64, timestamp: u128) -> bool {
+        match *self {
+            RecoveryTarget::Timestamp(target) => timestamp <= target,
+            RecoveryTarget::Sequence(target) => seq <= target,
+        }
+    }
+}
+
+/// A record of a segment holding operations or transaction markers
+enum WalRecord {
+    /// Operations applied to the database, all the ones of a batch together
+    Entries(Vec<WalEntry>),
+    Prepare {
+        name: String,
+        batch: WriteBatch,
+    },
+    Commit {
+        name: String,
+        entries: Vec<WalEntry>,
+    },
+    Rollback {
+        name: String,
+    },
+}
+
+/// A transaction that is prepared but not committed nor rolled back
+struct PreparedTransaction {
+    batch: WriteBatch,
+    /// Log number of the segment holding the prepare record
+    log_number: u64,
+}
+
+/// A closed WAL segment
+struct WalSegment {
+    path: PathBuf,
+    log_number: u64,
+    /// Highest sequence number written to this segment
+    max_sequence: u64,
 }
 
 /// Write Ahead Log (WAL)
 ///
-/// An append-only file that holds the operations performed on the MemTable.
+/// An append-only log that holds the operations performed on the MemTable.
 /// The WAL is intended for recovery of the MemTable when the server is shutdown
 pub struct Wal {
+    dir: PathBuf,
+    options: WalOptions,
+    /// Log number of the active segment
+    log_number: u64,
+    /// Path of the active segment
     path: PathBuf,
     file: BufWriter<File>,
+    /// Bytes written to the active segment
+    size: usize,
+    /// Bytes reserved on disk for the active segment
+    allocated: usize,
+    /// Sequence number of the last operation appended to the WAL
+    last_sequence: u64,
+    /// Highest sequence number whose data is already persisted in SSTables
+    persisted_sequence: u64,
+    /// Closed segments, oldest first
+    segments: Vec<WalSegment>,
+    /// Obsolete segment files waiting to be reused
+    recycled: Vec<PathBuf>,
+    /// Prepared transactions by name
+    prepared: BTreeMap<String, PreparedTransaction>,
+    stats: WalStats,
 }
 
 impl IntoIterator for Wal {
     type Item = WalEntry;
 
-    type IntoIter = WalIterator;
+    type IntoIter = std::iter::Flatten<std::vec::IntoIter<WalIterator>>;
 
-    fn into_iter(self) -> Self::IntoIter {
-        WalIterator::new(self.path).expect("the iterator to work")
+    fn into_iter(mut self) -> Self::IntoIter {
+        self.flush().expect("the WAL to flush");
+        self.segment_paths()
+            .into_iter()
+            .map(|path| WalIterator::new(path).expect("the iterator to work"))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flatten()
     }
 }
 
 impl Wal {
     pub fn new(dir: &Path) -> io::Result<Self> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_micros();
-
-        let path = Path::new(dir).join(format!("{}.wal", timestamp));
-        let file = OpenOptions::new().append(true).create(true).open(&path)?;
-        let file = BufWriter::new(file);
-        Ok(Self { path, file })
+        Self::with_options(dir, WalOptions::default())
     }
 
-    pub fn from_path(path: &Path) -> io::Result<Self> {
-        let file = OpenOptions::new().append(true).create(true).open(path)?;
-        let file = BufWriter::new(file);
+    /// Creates a WAL appending to a new segment in `dir`
+    ///
+    /// Segments already in the directory are not replayed, but the sequence
+    /// numbers of the new operations continue after the highest one they
+    /// hold, so they never go back.
+    pub fn with_options(dir: &Path, options: WalOptions) -> io::Result<Self> {
+        let last_sequence = last_sequence_in(dir)?;
+        let mut wal = Self::create(dir, options, 0, Vec::new())?;
+        wal.last_sequence = last_sequence;
+        Ok(wal)
+    }
 
-        Ok(Self {
-            path: path.to_owned(),
+    /// Creates a WAL with a fresh active segment, reusing one of the `recycled`
+    /// files for it if possible
+    fn create(
+        dir: &Path,
+        options: WalOptions,
+        min_log_number: u64,
+        mut recycled: Vec<PathBuf>,
+    ) -> io::Result<Self> {
+        let log_number = new_log_number(dir, min_log_number);
+        let (path, file, allocated) = open_segment(dir, log_number, recycled.pop())?;
+        let mut wal = Self {
+            dir: dir.to_owned(),
+            options,
+            log_number,
+            path,
             file,
-        })
+            size: 0,
+            allocated,
+            last_sequence: 0,
+            persisted_sequence: 0,
+            segments: Vec::new(),
+            recycled,
+            prepared: BTreeMap::new(),
+            stats: WalStats::default(),
+        };
+        wal.preallocate(WAL_HEADER_SIZE)?;
+        wal.write_segment_header()?;
+
+        Ok(wal)
+    }
+
+    /// Writes the header and the records every segment starts with
+    ///
+    /// The header is flushed right away, so the segment can be identified even
+    /// if nothing else is ever written to it
+    fn write_segment_header(&mut self) -> io::Result<()> {
+        let header = WalHeader {
+            version: WAL_FORMAT_VERSION,
+            log_number: self.log_number,
+            created_at: now_micros() as u64,
+        };
+        self.file.write_all(&header.encode())?;
+        self.size += WAL_HEADER_SIZE;
+
+        let compression = self.options.compression;
+        if compression != CompressionType::None {
+            self.write_record(RecordType::SetCompression, &[compression as u8])?;
+        }
+
+        self.flush()
     }
 
     /// Sets a Key-Value pair and the operation is appended to the WAL
-    pub fn set(&mut self, key: &[u8], value: &[u8], timestamp: u128) -> io::Result<()> {
-        self.file.write_all(&key.len().to_le_bytes())?;
-        self.file.write_all(&(false as u8).to_le_bytes())?;
-        self.file.write_all(&value.len().to_le_bytes())?;
-        self.file.write_all(key)?;
-        self.file.write_all(value)?;
-        self.file.write_all(&timestamp.to_le_bytes())?;
+    ///
+    /// Returns the sequence number assigned to the operation
+    pub fn set(&mut self, key: &[u8], value: &[u8], timestamp: u128) -> io::Result<u64> {
+        let seq = self.last_sequence + 1;
+        let op = WriteOp::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+        let payload = encode_entry(seq, &op, timestamp)?;
+        self.write_payload(RecordType::Entry, &payload)?;
+        self.last_sequence = seq;
+        self.maybe_rotate()?;
+
+        Ok(seq)
+    }
+
+    /// Deletes a Key-Value pair and the operation is appended to the WAL
+    ///
+    /// This is achieved using Tombstones. Returns the sequence number assigned
+    /// to the operation
+    pub fn delete(&mut self, key: &[u8], timestamp: u128) -> io::Result<u64> {
+        let seq = self.last_sequence + 1;
+        let op = WriteOp::Delete {
+            key: key.to_owned(),
+        };
+        let payload = encode_entry(seq, &op, timestamp)?;
+        self.write_payload(RecordType::Entry, &payload)?;
+        self.last_sequence = seq;
+        self.maybe_rotate()?;
+
+        Ok(seq)
+    }
+
+    /// Appends all the operations of a batch to the WAL in a single record, so
+    /// they are recovered all or none
+    ///
+    /// Returns the sequence number assigned to the last operation of the batch
+    pub fn write_batch(&mut self, batch: &WriteBatch, timestamp: u128) -> io::Result<u64> {
+        if batch.is_empty() {
+            return Ok(self.last_sequence);
+        }
+
+        let first_seq = self.last_sequence + 1;
+        let payload = encode_batch(first_seq, timestamp, batch.iter_cf())?;
+        self.write_payload(RecordType::Batch, &payload)?;
+        self.last_sequence += batch.len() as u64;
+        self.maybe_rotate()?;
+
+        Ok(self.last_sequence)
+    }
+
+    /// Appends the operations of a transaction to the WAL without applying
+    /// them, as the first phase of a two-phase commit
+    ///
+    /// The transaction is then committed or rolled back by name, even after
+    /// reloading the WAL. Fails with [`io::ErrorKind::AlreadyExists`] if there
+    /// is already a prepared transaction with the same name.
+    pub fn prepare(&mut self, name: &str, batch: &WriteBatch) -> io::Result<()> {
+        if self.prepared.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("transaction {} is already prepared", name),
+            ));
+        }
+
+        let mut payload = encode_name(name);
+        put_varint(&mut payload, batch.len() as u64);
+        put_ops(&mut payload, batch.iter_cf());
+        self.write_payload(RecordType::Prepare, &payload)?;
+        self.prepared.insert(
+            name.to_owned(),
+            PreparedTransaction {
+                batch: batch.clone(),
+                log_number: self.log_number,
+            },
+        );
+        self.maybe_rotate()
+    }
+
+    /// Commits a prepared transaction, assigning sequence numbers to its
+    /// operations
+    ///
+    /// Returns the operations of the transaction, so they can be applied, and
+    /// the sequence number assigned to the last one. Fails with
+    /// [`io::ErrorKind::NotFound`] if the transaction is not prepared.
+    pub fn commit_prepared(
+        &mut self,
+        name: &str,
+        timestamp: u128,
+    ) -> io::Result<(WriteBatch, u64)> {
+        let batch = match self.prepared.get(name) {
+            Some(prepared) => prepared.batch.clone(),
+            None => return Err(not_prepared(name)),
+        };
+
+        let mut payload = encode_name(name);
+        payload.extend(encode_batch(
+            self.last_sequence + 1,
+            timestamp,
+            batch.iter_cf(),
+        )?);
+        self.write_payload(RecordType::Commit, &payload)?;
+        self.prepared.remove(name);
+        self.last_sequence += batch.len() as u64;
+        self.maybe_rotate()?;
+
+        Ok((batch, self.last_sequence))
+    }
+
+    /// Discards the operations of a prepared transaction. Fails with
+    /// [`io::ErrorKind::NotFound`] if the transaction is not prepared
+    pub fn rollback_prepared(&mut self, name: &str) -> io::Result<()> {
+        if !self.prepared.contains_key(name) {
+            return Err(not_prepared(name));
+        }
+
+        self.write_payload(RecordType::Rollback, &encode_name(name))?;
+        self.prepared.remove(name);
+        self.maybe_rotate()
+    }
+
+    /// Names of the transactions that are prepared but not committed nor
+    /// rolled back yet, in order
+    pub fn prepared_transactions(&self) -> Vec<String> {
+        self.prepared.keys().cloned().collect()
+    }
+
+    /// Appends a record holding operations to the active segment, compressing
+    /// it if configured
+    fn write_payload(&mut self, record_type: RecordType, payload: &[u8]) -> io::Result<()> {
+        let compressed = self.options.compression.compress(payload);
+        self.write_record(record_type, &compressed)?;
+
+        self.stats.bytes_before_compression += payload.len() as u64;
+        self.stats.bytes_after_compression += compressed.len() as u64;
 
         Ok(())
     }
 
-    /// Deletes a Key-Value pair and the operation is appended to the WAL
+    /// Appends a record with its header to the active segment
+    fn write_record(&mut self, record_type: RecordType, payload: &[u8]) -> io::Result<()> {
+        self.preallocate(RECORD_HEADER_SIZE + payload.len())?;
+
+        let header = record_header(self.log_number, record_type, payload);
+        self.file.write_all(&header)?;
+        self.file.write_all(payload)?;
+        self.size += RECORD_HEADER_SIZE + payload.len();
+
+        Ok(())
+    }
+
+    /// Makes sure there's at least `len` bytes of disk space reserved after
+    /// the end of the active segment, reserving a new block if there isn't
+    fn preallocate(&mut self, len: usize) -> io::Result<()> {
+        let block_size = self.options.preallocation_block_size;
+        if block_size == 0 || (self.allocated > 0 && self.size + len <= self.allocated) {
+            return Ok(());
+        }
+
+        let allocated = (self.size + len).max(self.allocated) + block_size;
+        fallocate(
+            self.file.get_ref(),
+            self.allocated as u64,
+            (allocated - self.allocated) as u64,
+        )?;
+        self.allocated = allocated;
+
+        Ok(())
+    }
+
+    /// Flushes the WAL to disk
+    ///
+    /// This is useful for applying bulk operations and flushing the final result
+    /// to disk. Waiting to flush after the bulk operations have been performed will
+    /// improve the write performance substantially
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    /// Sequence number of the last operation appended to the WAL
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Statistics about the data written since the WAL was opened
+    pub fn stats(&self) -> WalStats {
+        self.stats
+    }
+
+    /// Number of segments in the WAL, including the active one
+    pub fn num_segments(&self) -> usize {
+        self.segments.len() + 1
+    }
+
+    /// Verifies the checksums of the records of every segment, returning the
+    /// paths of the segments that are corrupted
+    pub fn verify_checksums(&mut self) -> io::Result<Vec<PathBuf>> {
+        self.flush()?;
+        let mut corrupted = Vec::new();
+        for path in self.segment_paths() {
+            if !verify_segment(&path)? {
+                corrupted.push(path);
+            }
+        }
+        Ok(corrupted)
+    }
+
+    /// Paths of all the segments in the WAL, oldest first
+    fn segment_paths(&self) -> Vec<PathBuf> {
+        self.segments
+            .iter()
+            .map(|s| s.path.clone())
+            .chain(std::iter::once(self.path.clone()))
+            .collect()
+    }
+
+    /// Closes the active segment and starts a new one if it has grown over the
+    /// configured size
+    fn maybe_rotate(&mut self) -> io::Result<()> {
+        if self.size < self.options.max_segment_size {
+            return Ok(());
+        }
+
+        self.flush()?;
+        let log_number = new_log_number(&self.dir, self.log_number + 1);
+        let (path, file, allocated) = open_segment(&self.dir, log_number, self.recycled.pop())?;
+        let old_path = std::mem::replace(&mut self.path, path);
+        let old_log_number = std::mem::replace(&mut self.log_number, log_number);
+        self.file = file;
+        self.size = 0;
+        self.allocated = allocated;
+        self.segments.push(WalSegment {
+            path: old_path,
+            log_number: old_log_number,
+            max_sequence: self.last_sequence,
+        });
+        self.preallocate(WAL_HEADER_SIZE)?;
+        self.write_segment_header()?;
+
+        self.delete_obsolete_segments()
+    }
+
+    /// Marks every operation up to `seq` (inclusive) as persisted in SSTables
+    ///
+    /// Closed segments that only contain persisted operations are not needed
+    /// for recovery anymore, so they are deleted. The active segment is kept
+    /// and will be deleted once it gets rotated.
+    pub fn mark_persisted(&mut self, seq: u64) -> io::Result<()> {
+        self.persisted_sequence = self.persisted_sequence.max(seq);
+        self.delete_obsolete_segments()
+    }
+
+    /// Deletes (archives or recycles) the closed segments whose operations are
+    /// all persisted
+    ///
+    /// Segments holding transactions that are still prepared are needed to
+    /// recover them, so they are kept, as well as all the later ones.
+    fn delete_obsolete_segments(&mut self) -> io::Result<()> {
+        let min_prepared = self.prepared.values().map(|p| p.log_number).min();
+        let persisted = self
+            .segments
+            .iter()
+            .take_while(|s| s.max_sequence <= self.persisted_sequence)
+            .take_while(|s| min_prepared.is_none_or(|min| s.log_number < min))
+            .count();
+        if persisted == 0 {
+            return Ok(());
+        }
+
+        let obsolete: Vec<WalSegment> = self.segments.drain(..persisted).collect();
+        for segment in obsolete {
+            if self.options.archive_enabled() {
+                let archive_dir = self.dir.join(ARCHIVE_DIR);
+                create_dir_all(&archive_dir)?;
+                rename(
+                    &segment.path,
+                    archive_dir.join(segment.path.file_name().unwrap()),
+                )?;
+            } else if self.recycled.len() < self.options.recycle_log_file_num {
+                self.recycled.push(recycle_segment(segment.path)?);
+            } else {
+                remove_file(segment.path)?;
+            }
+        }
+
+        self.purge_archive()
+    }
+
+    /// Deletes the archived segments that fall out of the retention policy
+    fn purge_archive(&mut self) -> io::Result<()> {
+        let archive_dir = self.dir.join(ARCHIVE_DIR);
+        if !archive_dir.exists() {
+            return Ok(());
+        }
+
+        let mut archived = files_with_ext(&archive_dir, "wal");
+        archived.sort();
+
+        let now = SystemTime::now();
+        let mut total_size = 0;
+        let mut kept = Vec::new();
+        for path in archived {
+            let metadata = path.metadata()?;
+            let age = now
+                .duration_since(metadata.modified()?)
+                .unwrap_or(Duration::ZERO);
+            if self.options.archive_ttl.is_some_and(|ttl| age >= ttl) {
+                remove_file(path)?;
+            } else {
+                total_size += metadata.len();
+                kept.push((path, metadata.len()));
+            }
+        }
+
+        // Delete the oldest segments until the archive is within its limit
+        if let Some(size_limit) = self.options.archive_size_limit {
+            for (path, len) in kept {
+                if total_size <= size_limit {
+                    break;
+                }
+                remove_file(path)?;
+                total_size -= len;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an iterator over every operation with a sequence number greater
+    /// or equal than `seq`, in order
+    ///
+    /// Operations are read from the archived segments (if archiving is enabled)
+    /// and the live ones. Operations appended after the iterator is created are
+    /// not returned. Fails with [`io::ErrorKind::NotFound`] if some of the
+    /// requested operations are not available anymore.
+    ///
+    /// Segments are only opened once the iterator reaches them. Live segments
+    /// archived in the meantime are read from the archive, and the iteration
+    /// stops early if a segment was deleted, see [`UpdatesIterator::status`]
+    pub fn get_updates_since(&mut self, seq: u64) -> io::Result<UpdatesIterator> {
+        self.flush()?;
+
+        let mut paths = VecDeque::new();
+        let archive_dir = self.dir.join(ARCHIVE_DIR);
+        if archive_dir.exists() {
+            let mut archived = files_with_ext(&archive_dir, "wal");
+            archived.sort();
+            paths.extend(archived);
+        }
+        paths.extend(self.segment_paths());
+
+        let mut updates = UpdatesIterator {
+            paths,
+            archive_dir,
+            segment: None,
+            first: None,
+            seq: seq.max(1),
+            last_seq: self.last_sequence,
+            error: None,
+        };
+        updates.first = updates.next_entry();
+        if let Some(err) = updates.error.take() {
+            return Err(err);
+        }
+
+        let missing = match &updates.first {
+            Some(first) => first.seq > updates.seq,
+            None => updates.seq <= self.last_sequence,
+        };
+        if missing {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "updates since sequence {} are not available anymore",
+                    updates.seq
+                ),
+            ));
+        }
+
+        Ok(updates)
+    }
+
+    /// Rewrites the segments within a directory that were written with an older
+    /// version of the format in the current one, returning how many were
+    /// upgraded
+    ///
+    /// Old segments are readable as they are, so this is only needed to get rid
+    /// of the old formats on disk. It must not be called while a WAL is open on
+    /// the directory. The operations of baseline segments are numbered after
+    /// the ones of the segments before them
+    pub fn upgrade_dir(dir: &Path) -> io::Result<usize> {
+        let mut wal_files = files_with_ext(dir, "wal");
+        wal_files.sort();
+
+        let mut upgraded = 0;
+        let mut last_sequence = 0;
+        for wal_file in wal_files {
+            let (was_upgraded, max_sequence) = upgrade_segment(&wal_file, last_sequence + 1)?;
+            if was_upgraded {
+                upgraded += 1;
+            }
+            last_sequence = last_sequence.max(max_sequence);
+        }
+
+        Ok(upgraded)
+    }
+
+    /// Loads the WAL(s) within a directory, returning a new WAL and the recovered MemTable.
+    ///
+    /// If multiple WAL exist in a directory, they are replayed by file date.
+    pub fn load_from_dir(dir: &Path) -> io::Result<(Wal, MemTable)> {
+        Self::load_from_dir_with_options(dir, WalOptions::default())
+    }
+
+    /// Loads the WAL(s) within a directory using the given options
+    ///
+    /// The existing segments are kept as closed segments of the new WAL, since
+    /// their operations are not persisted anywhere else yet. New operations are
+    /// appended to a fresh segment, continuing the sequence numbers. Baseline
+    /// segments are upgraded to the current format first, so the sequence
+    /// numbers given to their operations are recorded.
+    pub fn load_from_dir_with_options(
+        dir: &Path,
+        options: WalOptions,
+    ) -> io::Result<(Wal, MemTable)> {
+        Self::load(dir, options, None, Arc::new(BytewiseComparator), 0).map(with_default_memtable)
+    }
+
+    /// Loads the WAL(s) within a directory using the given options, returning
+    /// the recovered MemTable of every column family with operations in them,
+    /// with their keys ordered by `comparator`
     ///
-    /// This is achieved using Tombstones
-    pub fn delete(&mut self, key: &[u8], timestamp: u128) -> io::Result<()> {
-        self.file.write_all(&key.len().to_le_bytes())?;
-        self.file.write_all(&(true as u8).to_le_bytes())?;
-        self.file.write_all(key)?;
-        self.file.write_all(&timestamp.to_le_bytes())?;
+    /// Operations up to `flushed_sequence` (inclusive) are already persisted
+    /// in SSTables, so they are not replayed, and the segments only holding
+    /// such operations are deleted.
+    ///
+    /// With a `target`, the operations are only replayed up to it, like
+    /// [`Wal::load_from_dir_until`] does.
+    pub fn load_from_dir_with_column_families(
+        dir: &Path,
+        options: WalOptions,
+        target: Option<RecoveryTarget>,
+        comparator: Arc<dyn Comparator>,
+        flushed_sequence: u64,
+    ) -> io::Result<(Wal, BTreeMap<ColumnFamilyId, MemTable>)> {
+        Self::load(dir, options, target, comparator, flushed_sequence)
+    }
+
+    /// Loads the WAL(s) within a directory, replaying the operations only up
+    /// to the given target, to recover the MemTable as it was at that moment
+    ///
+    /// Operations are replayed in order, stopping at the first one past the
+    /// target. That operation and all the later ones are removed from the WAL,
+    /// so they are not replayed again, but a copy of the segments that held
+    /// them is kept in a `discarded-<timestamp>` directory.
+    pub fn load_from_dir_until(
+        dir: &Path,
+        options: WalOptions,
+        target: RecoveryTarget,
+    ) -> io::Result<(Wal, MemTable)> {
+        Self::load(dir, options, Some(target), Arc::new(BytewiseComparator), 0)
+            .map(with_default_memtable)
+    }
+
+    fn load(
+        dir: &Path,
+        options: WalOptions,
+        target: Option<RecoveryTarget>,
+        comparator: Arc<dyn Comparator>,
+        flushed_sequence: u64,
+    ) -> io::Result<(Wal, BTreeMap<ColumnFamilyId, MemTable>)> {
+        let mut wal_files = files_with_ext(dir, "wal");
+        wal_files.sort();
+
+        let mut memtables = BTreeMap::new();
+        let mut segments = Vec::new();
+        let mut recycled = files_with_ext(dir, "recycle");
+        let mut prepared = BTreeMap::new();
+        let mut last_sequence = 0;
+        let mut last_log_number = 0;
+
+        let mut wal_files = wal_files.into_iter();
+        while let Some(wal_file) = wal_files.next() {
+            let log_number = log_number_from_path(&wal_file)?;
+            last_log_number = last_log_number.max(log_number);
+
+            let mut is_empty = true;
+            let mut iter = WalIterator::new(wal_file.clone())?;
+            if iter.is_baseline() {
+                upgrade_segment(&wal_file, last_sequence + 1)?;
+                iter = WalIterator::new(wal_file.clone())?;
+            }
+            loop {
+                let offset = iter.offset();
+                let Some(record) = iter.next_record() else {
+                    break;
+                };
+                let entries = match record {
+                    WalRecord::Entries(entries) => entries,
+                    WalRecord::Prepare { name, batch } => {
+                        let transaction = PreparedTransaction { batch, log_number };
+                        prepared.insert(name, transaction);
+                        is_empty = false;
+                        continue;
+                    }
+                    WalRecord::Commit { name, entries } => {
+                        // The commit is discarded with its operations if they
+                        // are past the target, keeping the transaction prepared
+                        if !target.is_some_and(|t| entries.iter().any(|e| !t.includes(e))) {
+                            prepared.remove(&name);
+                        }
+                        entries
+                    }
+                    WalRecord::Rollback { name } => {
+                        prepared.remove(&name);
+                        is_empty = false;
+                        continue;
+                    }
+                };
+
+                // Batches are discarded as a whole if any operation is past
+                // the target
+                if target.is_some_and(|t| entries.iter().any(|e| !t.includes(e))) {
+                    let later: Vec<PathBuf> = wal_files.by_ref().collect();
+                    discard_after(dir, &wal_file, offset, &later)?;
+                    break;
+                }
+
+                for entry in entries {
+                    last_sequence = last_sequence.max(entry.seq);
+                    is_empty = false;
+                    if entry.seq <= flushed_sequence {
+                        continue;
+                    }
+
+                    let new_memtable = memtables
+                        .entry(entry.column_family)
+                        .or_insert_with(|| MemTable::with_comparator(comparator.clone()));
+                    if let Some(end) = &entry.range_end {
+                        new_memtable.delete_range(&entry.key, end, entry.timestamp, entry.seq);
+                    } else if entry.deleted {
+                        new_memtable.delete(entry.key.as_slice(), entry.timestamp, entry.seq);
+                    } else if entry.merge {
+                        new_memtable.merge(
+                            entry.key.as_slice(),
+                            entry
+                                .value
+                                .as_ref()
+                                .expect("an operand to exist")
+                                .as_slice(),
+                            entry.timestamp,
+                            entry.seq,
+                        );
+                    } else if let Some(ttl) = entry.ttl {
+                        // The expiry is relative to the original write, so
+                        // values that expired while the database was closed
+                        // stay expired
+                        new_memtable.set_with_ttl(
+                            entry.key.as_slice(),
+                            entry.value.as_ref().expect("a value to exist").as_slice(),
+                            entry.timestamp,
+                            ttl,
+                            entry.seq,
+                        );
+                    } else {
+                        new_memtable.set(
+                            entry.key.as_slice(),
+                            entry.value.as_ref().expect("a value to exist").as_slice(),
+                            entry.timestamp,
+                            entry.seq,
+                        );
+                    }
+                }
+            }
+
+            if is_empty {
+                // Nothing to recover from an empty segment
+                recycled.push(recycle_segment(wal_file)?);
+            } else {
+                segments.push(WalSegment {
+                    path: wal_file,
+                    log_number,
+                    max_sequence: last_sequence,
+                });
+            }
+        }
+
+        // Only keep as many files for reuse as configured
+        recycled.sort();
+        while recycled.len() > options.recycle_log_file_num {
+            remove_file(recycled.remove(0))?;
+        }
+
+        let mut new_wal = Wal::create(dir, options, last_log_number + 1, recycled)?;
+        new_wal.last_sequence = last_sequence.max(flushed_sequence);
+        new_wal.segments = segments;
+        new_wal.prepared = prepared;
+        new_wal.mark_persisted(flushed_sequence)?;
+        new_wal.purge_archive()?;
+        Ok((new_wal, memtables))
+    }
+}
+
+/// Keeps only the MemTable of the default column family out of the ones
+/// recovered from a WAL
+fn with_default_memtable(
+    (wal, mut memtables): (Wal, BTreeMap<ColumnFamilyId, MemTable>),
+) -> (Wal, MemTable) {
+    let memtable = memtables
+        .remove(&DEFAULT_COLUMN_FAMILY)
+        .unwrap_or_else(MemTable::new);
+    (wal, memtable)
+}
+
+/// Removes every operation from the segments starting at `offset` of
+/// `segment`, including all the `later` segments
+///
+/// A copy of the affected segments is kept in a new directory, in case the
+/// operations need to be recovered later on
+fn discard_after(dir: &Path, segment: &Path, offset: u64, later: &[PathBuf]) -> io::Result<()> {
+    let backup_dir = dir.join(format!("discarded-{}", now_micros()));
+    create_dir_all(&backup_dir)?;
+
+    copy(segment, backup_dir.join(segment.file_name().unwrap()))?;
+    OpenOptions::new()
+        .write(true)
+        .open(segment)?
+        .set_len(offset)?;
+
+    for path in later {
+        rename(path, backup_dir.join(path.file_name().unwrap()))?;
+    }
+
+    Ok(())
+}
+
+/// Checks the header and records of a segment, returning whether they are
+/// all valid
+///
+/// Reading a segment stops at the first record that is not valid, which is
+/// expected at its end: a torn write left by a crash, or stale data of a
+/// recycled file. Such a record is only reported as corrupted when a valid
+/// record of the segment follows it, since the operations after it would be
+/// lost. A corrupted last record can't be told apart from a torn write.
+fn verify_segment(path: &Path) -> io::Result<bool> {
+    let data = std::fs::read(path)?;
+    let (mut offset, log_number) = match WalHeader::decode(&data) {
+        Ok(Some(header)) if is_stale(path, &header) => return Ok(true),
+        Ok(Some(header)) => (WAL_HEADER_SIZE, header.log_number),
+        Ok(None) => (0, log_number_from_path(path)?),
+        Err(_) => return Ok(false),
+    };
+
+    while let Some(len) = valid_record_len(&data[offset..], log_number) {
+        offset += len;
+    }
+    let Some(header) = data.get(offset..offset + RECORD_HEADER_SIZE) else {
+        return Ok(true);
+    };
+    let len = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+    let next = offset + RECORD_HEADER_SIZE + len;
+    Ok(data
+        .get(next..)
+        .is_none_or(|rest| valid_record_len(rest, log_number).is_none()))
+}
+
+/// Length of the record at the start of `data`, if it is a valid record of
+/// the segment with the given log number
+fn valid_record_len(data: &[u8], log_number: u64) -> Option<usize> {
+    let header = data.get(..RECORD_HEADER_SIZE)?;
+    let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let record_log_number = u64::from_le_bytes(header[4..12].try_into().unwrap());
+    let len = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+    let payload = data.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len)?;
+
+    let valid = record_log_number == log_number
+        && checksum::extend(checksum::crc32c(&header[4..]), payload) == crc;
+    valid.then_some(RECORD_HEADER_SIZE + len)
+}
+
+/// Whether a segment still has the header of the segment its file was
+/// recycled from
+///
+/// A recycled file is renamed before its new header is written, so a crash in
+/// between leaves the old header in place, with the old records after it that
+/// are consistent with it. Such a segment holds nothing.
+fn is_stale(path: &Path, header: &WalHeader) -> bool {
+    log_number_from_path(path).is_ok_and(|log_number| log_number != header.log_number)
+}
+
+/// Path of the segment file for a log number
+fn segment_path(dir: &Path, log_number: u64) -> PathBuf {
+    dir.join(format!("{}.wal", log_number))
+}
+
+/// Parses the log number of a segment from its file name
+fn log_number_from_path(path: &Path) -> io::Result<u64> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid WAL segment name: {}", path.display()),
+            )
+        })
+}
+
+/// Highest sequence number of the operations in the segments of `dir`,
+/// including the archived ones
+///
+/// Sequence numbers grow with the log numbers of the segments, so only the
+/// newest segment holding any operation is read.
+fn last_sequence_in(dir: &Path) -> io::Result<u64> {
+    let mut paths = files_with_ext(dir, "wal");
+    let archive_dir = dir.join(ARCHIVE_DIR);
+    if archive_dir.exists() {
+        paths.extend(files_with_ext(&archive_dir, "wal"));
+    }
+    let mut segments = paths
+        .into_iter()
+        .map(|path| Ok((log_number_from_path(&path)?, path)))
+        .collect::<io::Result<Vec<_>>>()?;
+    segments.sort();
+
+    for (_, path) in segments.into_iter().rev() {
+        if let Some(last_sequence) = WalIterator::new(path)?.map(|entry| entry.seq).max() {
+            return Ok(last_sequence);
+        }
+    }
+    Ok(0)
+}
+
+/// Picks the log number for a new segment
+///
+/// Log numbers are the creation timestamp of the segment, bumped when needed
+/// to be at least `min_log_number` and to not collide with an existing file
+fn new_log_number(dir: &Path, min_log_number: u64) -> u64 {
+    let mut log_number = (now_micros() as u64).max(min_log_number);
+    while segment_path(dir, log_number).exists() {
+        log_number += 1;
+    }
+    log_number
+}
+
+/// Opens the file of a new segment, returning its path, the writer and the
+/// bytes already allocated on disk for it
+///
+/// If a `recycled` file is given, it gets renamed and overwritten from the
+/// start instead of creating a new file.
+fn open_segment(
+    dir: &Path,
+    log_number: u64,
+    recycled: Option<PathBuf>,
+) -> io::Result<(PathBuf, BufWriter<File>, usize)> {
+    let path = segment_path(dir, log_number);
+    let (file, allocated) = match recycled {
+        Some(recycled) => {
+            rename(recycled, &path)?;
+            let file = OpenOptions::new().write(true).open(&path)?;
+            let allocated = file.metadata()?.len() as usize;
+            (file, allocated)
+        }
+        None => {
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            (file, 0)
+        }
+    };
+
+    Ok((path, BufWriter::new(file), allocated))
+}
+
+/// Renames an obsolete segment so it's not replayed, keeping it for reuse
+fn recycle_segment(path: PathBuf) -> io::Result<PathBuf> {
+    let recycled = path.with_extension("recycle");
+    rename(path, &recycled)?;
+    Ok(recycled)
+}
+
+/// Encodes the payload of an entry record
+///
+/// Fails if the timestamp doesn't fit in the 8 bytes of the format
+fn encode_entry(seq: u64, op: &WriteOp, timestamp: u128) -> io::Result<Vec<u8>> {
+    let timestamp = encode_timestamp(timestamp)?;
+
+    let mut payload = Vec::with_capacity(MAX_VARINT_LEN * 3 + 1 + op_len(op) + 8);
+    put_varint(&mut payload, seq);
+    put_op(&mut payload, DEFAULT_COLUMN_FAMILY, op);
+    payload.extend_from_slice(&timestamp.to_le_bytes());
+    Ok(payload)
+}
+
+/// Converts a timestamp to the 8 bytes it takes in the WAL
+fn encode_timestamp(timestamp: u128) -> io::Result<u64> {
+    u64::try_from(timestamp).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("timestamp {} does not fit in a WAL entry", timestamp),
+        )
+    })
+}
+
+/// Encodes the payload of a batch record from its operations
+fn encode_batch<'a>(
+    first_seq: u64,
+    timestamp: u128,
+    ops: impl ExactSizeIterator<Item = (ColumnFamilyId, &'a WriteOp)>,
+) -> io::Result<Vec<u8>> {
+    let timestamp = encode_timestamp(timestamp)?;
+
+    let mut payload = Vec::new();
+    put_varint(&mut payload, first_seq);
+    put_varint(&mut payload, ops.len() as u64);
+    payload.extend_from_slice(&timestamp.to_le_bytes());
+    put_ops(&mut payload, ops);
+    Ok(payload)
+}
+
+/// Appends the encoding of the operations of a batch to `payload`
+fn put_ops<'a>(payload: &mut Vec<u8>, ops: impl Iterator<Item = (ColumnFamilyId, &'a WriteOp)>) {
+    for (cf, op) in ops {
+        put_op(payload, cf, op);
+    }
+}
+
+/// Appends the encoding of an operation on a column family to `payload`: its
+/// kind, column family, key and value
+fn put_op(payload: &mut Vec<u8>, cf: ColumnFamilyId, op: &WriteOp) {
+    let (kind, value) = match op {
+        WriteOp::Set { value, .. } => (KIND_VALUE, Some(value)),
+        WriteOp::Delete { .. } => (KIND_TOMBSTONE, None),
+        WriteOp::Merge { operand, .. } => (KIND_MERGE, Some(operand)),
+        WriteOp::SetWithTtl { value, .. } => (KIND_VALUE_WITH_TTL, Some(value)),
+        WriteOp::DeleteRange { end, .. } => (KIND_RANGE_TOMBSTONE, Some(end)),
+    };
+    let key = op.key();
+
+    if cf == DEFAULT_COLUMN_FAMILY {
+        payload.push(kind);
+    } else {
+        payload.push(kind | KIND_COLUMN_FAMILY_FLAG);
+        put_varint(payload, cf as u64);
+    }
+    put_varint(payload, key.len() as u64);
+    if let Some(value) = value {
+        put_varint(payload, value.len() as u64);
+    }
+    payload.extend_from_slice(key);
+    if let Some(value) = value {
+        payload.extend_from_slice(value);
+    }
+    if let WriteOp::SetWithTtl { ttl, .. } = op {
+        put_varint(payload, ttl.as_micros() as u64);
+    }
+}
+
+/// Bytes of data of an operation, its key and value
+fn op_len(op: &WriteOp) -> usize {
+    match op {
+        WriteOp::Set { key, value } | WriteOp::SetWithTtl { key, value, .. } => {
+            key.len() + value.len()
+        }
+        WriteOp::Delete { key } => key.len(),
+        WriteOp::Merge { key, operand } => key.len() + operand.len(),
+        WriteOp::DeleteRange { start, end } => start.len() + end.len(),
+    }
+}
+
+/// Decodes the next operation of an entry or batch, together with the column
+/// family it writes to
+fn get_op(payload: &mut &[u8]) -> Option<(ColumnFamilyId, WriteOp)> {
+    let mut kind = *get_bytes(payload, 1)?.first()?;
+    let mut cf = DEFAULT_COLUMN_FAMILY;
+    if kind & KIND_COLUMN_FAMILY_FLAG != 0 {
+        kind &= !KIND_COLUMN_FAMILY_FLAG;
+        cf = ColumnFamilyId::try_from(get_varint(payload)?).ok()?;
+    }
+
+    let key_len = get_varint(payload)? as usize;
+    if kind == KIND_TOMBSTONE {
+        let key = get_bytes(payload, key_len)?.to_vec();
+        return Some((cf, WriteOp::Delete { key }));
+    }
+
+    let value_len = get_varint(payload)? as usize;
+    let key = get_bytes(payload, key_len)?.to_vec();
+    let value = get_bytes(payload, value_len)?.to_vec();
+    let op = match kind {
+        KIND_VALUE => Some(WriteOp::Set { key, value }),
+        KIND_MERGE => Some(WriteOp::Merge {
+            key,
+            operand: value,
+        }),
+        KIND_RANGE_TOMBSTONE => Some(WriteOp::DeleteRange {
+            start: key,
+            end: value,
+        }),
+        KIND_VALUE_WITH_TTL => Some(WriteOp::SetWithTtl {
+            key,
+            value,
+            ttl: Duration::from_micros(get_varint(payload)?),
+        }),
+        _ => None,
+    }?;
+    Some((cf, op))
+}
+
+/// Decodes the payload of a batch record into one entry per operation
+fn decode_batch(mut payload: &[u8]) -> Option<Vec<WalEntry>> {
+    let first_seq = get_varint(&mut payload)?;
+    let count = get_varint(&mut payload)?;
+    let timestamp = get_fixed_u64(&mut payload)? as u128;
+    if count == 0 {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    for seq in first_seq..first_seq + count {
+        let (cf, op) = get_op(&mut payload)?;
+        entries.push(WalEntry::from_op(seq, cf, op, timestamp));
+    }
+
+    Some(entries)
+}
+
+/// Encodes the name of a prepared transaction, which all the two-phase
+/// commit records start with
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut payload = Vec::new();
+    put_varint(&mut payload, name.len() as u64);
+    payload.extend_from_slice(name.as_bytes());
+    payload
+}
+
+/// Decodes the name of a prepared transaction
+fn get_name(payload: &mut &[u8]) -> Option<String> {
+    let len = get_varint(payload)? as usize;
+    String::from_utf8(get_bytes(payload, len)?.to_vec()).ok()
+}
+
+/// Decodes the payload of a prepare record
+fn decode_prepare(mut payload: &[u8]) -> Option<WalRecord> {
+    let name = get_name(&mut payload)?;
+    let count = get_varint(&mut payload)?;
+
+    let mut batch = WriteBatch::new();
+    for _ in 0..count {
+        let (cf, op) = get_op(&mut payload)?;
+        batch.push_cf(cf, op);
+    }
+
+    Some(WalRecord::Prepare { name, batch })
+}
+
+fn not_prepared(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("transaction {} is not prepared", name),
+    )
+}
+
+/// Decodes the payload of an entry record written with a version of the format
+fn decode_entry(version: u32, payload: &[u8]) -> Option<WalEntry> {
+    if version < 2 {
+        return decode_entry_v1(payload);
+    }
+
+    let mut payload = payload;
+    let seq = get_varint(&mut payload)?;
+    let (cf, op) = get_op(&mut payload)?;
+    let timestamp = get_fixed_u64(&mut payload)? as u128;
+
+    Some(WalEntry::from_op(seq, cf, op, timestamp))
+}
+
+/// Decodes the payload of an entry record written with version 0 or 1 of the
+/// format, which used fixed width fields:
+///
+/// +----------------+---------------+---------------+-----------------+-...-+--...--+-----------------+
+/// | Sequence (8B)  | Key Size (8B) | Tombstone(1B) | Value Size (8B) | Key | Value | Timestamp (16B) |
+/// +----------------+---------------+---------------+-----------------+-...-+--...--+-----------------+
+///
+/// Sizes were written as 64-bit `usize`, so they are read as `u64` to be able
+/// to read them back on any platform
+fn decode_entry_v1(mut payload: &[u8]) -> Option<WalEntry> {
+    // Read the sequence field
+    let mut seq_buffer = [0; 8];
+    payload.read_exact(&mut seq_buffer).ok()?;
+    let seq = u64::from_le_bytes(seq_buffer);
+
+    // Read the key size field
+    let mut len_buffer = [0; 8];
+    payload.read_exact(&mut len_buffer).ok()?;
+    let key_len = usize::try_from(u64::from_le_bytes(len_buffer)).ok()?;
+    if key_len > payload.len() {
+        return None;
+    }
+
+    // Read the tombstone field
+    let mut bool_buffer = [0; 1];
+    payload.read_exact(&mut bool_buffer).ok()?;
+    let deleted = bool_buffer[0] != 0;
+
+    // Read the key and value
+    let mut key = vec![0; key_len];
+    let mut value = None;
+
+    if deleted {
+        payload.read_exact(&mut key).ok()?;
+    } else {
+        payload.read_exact(&mut len_buffer).ok()?;
+        let value_len = usize::try_from(u64::from_le_bytes(len_buffer)).ok()?;
+        if value_len > payload.len() {
+            return None;
+        }
+        payload.read_exact(&mut key).ok()?;
+
+        let mut value_buf = vec![0; value_len];
+        payload.read_exact(&mut value_buf).ok()?;
+        value = Some(value_buf);
+    }
+
+    // Read the timestamp
+    let mut timestamp_buffer = [0; 16];
+    payload.read_exact(&mut timestamp_buffer).ok()?;
+    let timestamp = u128::from_le_bytes(timestamp_buffer);
+
+    Some(WalEntry {
+        seq,
+        column_family: DEFAULT_COLUMN_FAMILY,
+        key,
+        value,
+        timestamp,
+        deleted,
+        merge: false,
+        range_end: None,
+        ttl: None,
+    })
+}
+
+/// Encodes a record holding operations or transaction markers, returning its
+/// type and payload
+fn encode_record(record: &WalRecord) -> io::Result<(RecordType, Vec<u8>)> {
+    let encode_entries = |entries: &[WalEntry]| {
+        let ops: Vec<(ColumnFamilyId, WriteOp)> = entries
+            .iter()
+            .map(|e| (e.column_family, e.to_op()))
+            .collect();
+        let ops = ops.iter().map(|(cf, op)| (*cf, op));
+        encode_batch(entries[0].seq, entries[0].timestamp, ops)
+    };
+
+    Ok(match record {
+        WalRecord::Entries(entries) => match entries.as_slice() {
+            [entry] if entry.column_family == DEFAULT_COLUMN_FAMILY => (
+                RecordType::Entry,
+                encode_entry(entry.seq, &entry.to_op(), entry.timestamp)?,
+            ),
+            entries => (RecordType::Batch, encode_entries(entries)?),
+        },
+        WalRecord::Prepare { name, batch } => {
+            let mut payload = encode_name(name);
+            put_varint(&mut payload, batch.len() as u64);
+            put_ops(&mut payload, batch.iter_cf());
+            (RecordType::Prepare, payload)
+        }
+        WalRecord::Commit { name, entries } => {
+            let mut payload = encode_name(name);
+            payload.extend(encode_entries(entries)?);
+            (RecordType::Commit, payload)
+        }
+        WalRecord::Rollback { name } => (RecordType::Rollback, encode_name(name)),
+    })
+}
+
+/// Rewrites a segment written with an older version of the format in the
+/// current one, keeping its log number and compression
+///
+/// The operations of a baseline segment are numbered from `next_sequence`.
+/// Returns whether the segment was upgraded, `false` if it was already in the
+/// current version, and the highest sequence number in it. Fails with
+/// [`io::ErrorKind::InvalidData`] if a record can't be read before the end of
+/// the segment, rather than dropping the records from there
+fn upgrade_segment(path: &Path, next_sequence: u64) -> io::Result<(bool, u64)> {
+    let mut iter = WalIterator::new(path.to_owned())?;
+    iter.number_from(next_sequence);
+    let mut records = Vec::new();
+    while let Some(record) = iter.next_record() {
+        records.push(record);
+    }
+    let max_sequence = records
+        .iter()
+        .filter_map(|record| match record {
+            WalRecord::Entries(entries) | WalRecord::Commit { entries, .. } => entries.last(),
+            WalRecord::Prepare { .. } | WalRecord::Rollback { .. } => None,
+        })
+        .map(|entry| entry.seq)
+        .max()
+        .unwrap_or(0);
+    if iter.version() == WAL_FORMAT_VERSION {
+        return Ok((false, max_sequence));
+    }
+
+    // Reading stops at the end of the segment, which is the end of the file, a
+    // torn write or stale data from a recycled file. Baseline entries have no
+    // checksums, so only a torn write stops them early
+    if !iter.is_baseline() {
+        let data = std::fs::read(path)?;
+        let offset = iter.record_offset as usize;
+        let undecodable = valid_record_len(&data[offset..], iter.log_number).is_some();
+        if undecodable || !verify_segment(path)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "can't read the record at offset {} of {}",
+                    offset,
+                    path.display()
+                ),
+            ));
+        }
+    }
+
+    let log_number = iter.log_number;
+    // Segments without a header were created at the time of their log number
+    let created_at = iter.header().map_or(log_number, |h| h.created_at);
+    let compression = iter.compression;
+
+    let header = WalHeader {
+        version: WAL_FORMAT_VERSION,
+        log_number,
+        created_at,
+    };
+    let mut data = header.encode().to_vec();
+    if compression != CompressionType::None {
+        append_record(
+            &mut data,
+            log_number,
+            RecordType::SetCompression,
+            &[compression as u8],
+        );
+    }
+    for record in records {
+        let (record_type, payload) = encode_record(&record)?;
+        append_record(
+            &mut data,
+            log_number,
+            record_type,
+            &compression.compress(&payload),
+        );
+    }
+
+    // Replace the segment atomically, so it's never left half written
+    let tmp_path = path.with_extension("upgrade");
+    std::fs::write(&tmp_path, data)?;
+    rename(tmp_path, path)?;
+
+    Ok((true, max_sequence))
+}
+
+/// Builds the header of a record
+fn record_header(
+    log_number: u64,
+    record_type: RecordType,
+    payload: &[u8],
+) -> [u8; RECORD_HEADER_SIZE] {
+    let mut header = [0; RECORD_HEADER_SIZE];
+    header[4..12].copy_from_slice(&log_number.to_le_bytes());
+    header[12..16].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    header[16] = record_type as u8;
+    let crc = checksum::extend(checksum::crc32c(&header[4..]), payload);
+    header[..4].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+/// Appends a record with its header to `buffer`
+fn append_record(buffer: &mut Vec<u8>, log_number: u64, record_type: RecordType, payload: &[u8]) {
+    buffer.extend_from_slice(&record_header(log_number, record_type, payload));
+    buffer.extend_from_slice(payload);
+}
+
+/// An iterator over the operations appended to a WAL since a sequence number
+///
+/// Created by [`Wal::get_updates_since`]
+pub struct UpdatesIterator {
+    /// Segments not opened yet, oldest first
+    paths: VecDeque<PathBuf>,
+    /// Where live segments are looked for once archived
+    archive_dir: PathBuf,
+    /// Segment being read
+    segment: Option<WalIterator>,
+    /// First entry, read when the iterator was created
+    first: Option<WalEntry>,
+    /// Operations with a lower sequence number are skipped
+    seq: u64,
+    /// Operations with a greater sequence number were appended after the
+    /// iterator was created
+    last_seq: u64,
+    /// Error that stopped the iteration
+    error: Option<io::Error>,
+}
+
+impl UpdatesIterator {
+    /// Error that stopped the iteration early, if any. The iteration stops
+    /// when a segment it didn't reach yet can't be opened anymore
+    pub fn status(&self) -> io::Result<()> {
+        match &self.error {
+            Some(err) => Err(io::Error::new(err.kind(), err.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the next entry of the segments, opening them as they are
+    /// reached
+    fn next_entry(&mut self) -> Option<WalEntry> {
+        loop {
+            if let Some(entry) = self.segment.as_mut().and_then(|s| s.next()) {
+                return Some(entry);
+            }
+            let path = self.paths.pop_front()?;
+            match self.open_segment(path) {
+                Ok(segment) => self.segment = Some(segment),
+                Err(err) => {
+                    self.paths.clear();
+                    self.segment = None;
+                    self.error = Some(err);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Opens a segment, looking for it in the archive if it was archived
+    /// since the iterator was created
+    fn open_segment(&self, path: PathBuf) -> io::Result<WalIterator> {
+        match WalIterator::new(path.clone()) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => match path.file_name() {
+                Some(name) if !path.starts_with(&self.archive_dir) => {
+                    WalIterator::new(self.archive_dir.join(name))
+                }
+                _ => Err(err),
+            },
+            result => result,
+        }
+    }
+}
+
+impl Iterator for UpdatesIterator {
+    type Item = WalEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.first.take() {
+                Some(entry) => entry,
+                None => self.next_entry()?,
+            };
+            if entry.seq > self.last_seq {
+                self.paths.clear();
+                self.segment = None;
+                return None;
+            }
+            if entry.seq >= self.seq {
+                return Some(entry);
+            }
+        }
+    }
+}
+
+/// An iterator over all the entries in a WAL file
+pub struct WalIterator {
+    reader: BufReader<File>,
+    /// Header of the segment, `None` for segments written before headers
+    header: Option<WalHeader>,
+    /// Log number of the segment, records with a different one are stale
+    log_number: u64,
+    /// Size of the file
+    len: u64,
+    /// Bytes left to read in the file
+    remaining: u64,
+    /// Offset in the file of the last record read
+    record_offset: u64,
+    /// Compression of the entries, as advertised by the segment
+    compression: CompressionType,
+    /// Entries of the last batch read that were not returned yet
+    pending: VecDeque<WalEntry>,
+    /// Whether the segment has the layout of the first version of the WAL,
+    /// entries without records nor sequence numbers
+    baseline: bool,
+    /// Sequence number given to the next entry of a baseline segment
+    next_sequence: u64,
+}
+
+impl WalIterator {
+    /// Opens a WAL segment for reading, validating its header
+    ///
+    /// Fails if the file is not a WAL segment, or if it was written with an
+    /// unsupported version of the format
+    pub fn new(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        let mut buffer = Vec::with_capacity(WAL_HEADER_SIZE);
+        (&mut reader)
+            .take(WAL_HEADER_SIZE as u64)
+            .read_to_end(&mut buffer)?;
+
+        if let Some(header) = WalHeader::decode(&buffer)? {
+            let remaining = match is_stale(&path, &header) {
+                true => 0,
+                false => len - WAL_HEADER_SIZE as u64,
+            };
+            return Ok(WalIterator {
+                reader,
+                header: Some(header),
+                log_number: header.log_number,
+                len,
+                remaining,
+                record_offset: len - remaining,
+                compression: CompressionType::None,
+                pending: VecDeque::new(),
+                baseline: false,
+                next_sequence: 1,
+            });
+        }
+
+        // Segments without a header start straight with the first record, or
+        // the first entry for baseline segments, which must be valid for the
+        // file to be one of ours
+        reader.seek(SeekFrom::Start(0))?;
+        let mut iter = WalIterator {
+            reader,
+            header: None,
+            log_number: log_number_from_path(&path)?,
+            len,
+            remaining: len,
+            record_offset: 0,
+            compression: CompressionType::None,
+            pending: VecDeque::new(),
+            baseline: false,
+            next_sequence: 1,
+        };
+        if len > 0 && iter.read_raw_record().is_none() {
+            iter.reader.seek(SeekFrom::Start(0))?;
+            iter.remaining = len;
+            iter.baseline = true;
+            if iter.read_baseline_entry().is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("not a WAL segment: {}", path.display()),
+                ));
+            }
+            iter.next_sequence = 1;
+        }
+        iter.reader.seek(SeekFrom::Start(0))?;
+        iter.remaining = len;
+
+        Ok(iter)
+    }
+
+    /// Sets the sequence number of the first entry of a baseline segment,
+    /// which doesn't record them. Other segments are not affected
+    fn number_from(&mut self, seq: u64) {
+        self.next_sequence = seq;
+    }
+
+    /// Whether the segment has the layout of the first version of the WAL
+    pub fn is_baseline(&self) -> bool {
+        self.baseline
+    }
+
+    /// Header of the segment, `None` for segments written before headers
+    /// were introduced
+    pub fn header(&self) -> Option<&WalHeader> {
+        self.header.as_ref()
+    }
+
+    /// Version of the format of the segment
+    pub fn version(&self) -> u32 {
+        self.header.map_or(0, |h| h.version)
+    }
+
+    /// Position in the file of the next record
+    fn offset(&self) -> u64 {
+        self.len - self.remaining
+    }
+
+    /// Reads the entries of the next record holding operations applied to the
+    /// database. All the entries of a batch are returned together
+    ///
+    /// Prepared transactions are skipped until they are committed
+    fn next_entries(&mut self) -> Option<Vec<WalEntry>> {
+        loop {
+            match self.next_record()? {
+                WalRecord::Entries(entries) | WalRecord::Commit { entries, .. } => {
+                    return Some(entries)
+                }
+                WalRecord::Prepare { .. } | WalRecord::Rollback { .. } => {}
+            }
+        }
+    }
+
+    /// Reads the next record holding operations or transaction markers
+    fn next_record(&mut self) -> Option<WalRecord> {
+        if self.baseline {
+            let entry = self.read_baseline_entry()?;
+            return Some(WalRecord::Entries(vec![entry]));
+        }
+
+        loop {
+            let (record_type, payload) = self.read_record()?;
+            if record_type == RecordType::SetCompression {
+                self.compression = CompressionType::from_u8(*payload.first()?)?;
+                continue;
+            }
+
+            let payload = self.compression.decompress(&payload)?;
+            let mut payload = payload.as_slice();
+            return match record_type {
+                RecordType::Entry => Some(WalRecord::Entries(vec![decode_entry(
+                    self.version(),
+                    payload,
+                )?])),
+                RecordType::Batch => Some(WalRecord::Entries(decode_batch(payload)?)),
+                RecordType::Prepare => decode_prepare(payload),
+                RecordType::Commit => Some(WalRecord::Commit {
+                    name: get_name(&mut payload)?,
+                    entries: decode_batch(payload)?,
+                }),
+                RecordType::Rollback => Some(WalRecord::Rollback {
+                    name: get_name(&mut payload)?,
+                }),
+                RecordType::SetCompression => unreachable!(),
+            };
+        }
+    }
+
+    /// Reads the next record of the segment
+    ///
+    /// Returns `None` at the end of the segment, which is either the end of the
+    /// file or the first record that was not written to this segment: stale
+    /// data from a recycled file, or a torn write
+    fn read_record(&mut self) -> Option<(RecordType, Vec<u8>)> {
+        let (header, payload) = self.read_raw_record()?;
+        let log_number = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        if log_number != self.log_number {
+            return None;
+        }
+
+        Some((RecordType::from_u8(header[16])?, payload))
+    }
+
+    /// Reads the header and payload of the next record, checking that they
+    /// are not corrupted
+    fn read_raw_record(&mut self) -> Option<([u8; RECORD_HEADER_SIZE], Vec<u8>)> {
+        self.record_offset = self.len - self.remaining;
+        if self.remaining < RECORD_HEADER_SIZE as u64 {
+            return None;
+        }
+
+        let mut header = [0; RECORD_HEADER_SIZE];
+        self.reader.read_exact(&mut header).ok()?;
+        let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let len = u32::from_le_bytes(header[12..16].try_into().unwrap()) as u64;
+
+        self.remaining -= RECORD_HEADER_SIZE as u64;
+        if len > self.remaining {
+            return None;
+        }
+
+        let mut payload = vec![0; len as usize];
+        self.reader.read_exact(&mut payload).ok()?;
+        self.remaining -= len;
+        if checksum::extend(checksum::crc32c(&header[4..]), &payload) != crc {
+            return None;
+        }
+
+        Some((header, payload))
+    }
+
+    /// Reads the next entry of a baseline segment, numbering it with the next
+    /// sequence number
+    ///
+    /// Returns `None` at the end of the segment, or at an entry torn by a crash
+    fn read_baseline_entry(&mut self) -> Option<WalEntry> {
+        let key_len = u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap());
+        let deleted = match self.read_bytes(1)?[0] {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        let value_len = match deleted {
+            true => None,
+            false => Some(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap())),
+        };
+        let key = self.read_bytes(key_len)?;
+        let value = match value_len {
+            Some(value_len) => Some(self.read_bytes(value_len)?),
+            None => None,
+        };
+        let timestamp = u128::from_le_bytes(self.read_bytes(16)?.try_into().unwrap());
+
+        let seq = self.next_sequence;
+        self.next_sequence += 1;
+        Some(WalEntry {
+            seq,
+            column_family: DEFAULT_COLUMN_FAMILY,
+            key,
+            value,
+            timestamp,
+            deleted,
+            merge: false,
+            range_end: None,
+            ttl: None,
+        })
+    }
+
+    /// Reads the next `len` bytes of the segment, or `None` if it ends before
+    fn read_bytes(&mut self, len: u64) -> Option<Vec<u8>> {
+        if len > self.remaining {
+            return None;
+        }
+        let mut buffer = vec![0; len as usize];
+        self.reader.read_exact(&mut buffer).ok()?;
+        self.remaining -= len;
+        Some(buffer)
+    }
+}
+
+impl Iterator for WalIterator {
+    type Item = WalEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pending.is_empty() {
+            let entries = self.next_entries()?;
+            self.pending.extend(entries);
+        }
+        self.pending.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    use std::fs::{create_dir, read_dir, remove_dir_all};
+    use std::fs::{metadata, File, OpenOptions};
+    use std::io::BufReader;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// Opens a segment for reading, positioned after its header
+    fn segment_reader(path: &Path) -> BufReader<File> {
+        let file = OpenOptions::new().read(true).open(path).unwrap();
+        let mut reader = BufReader::new(file);
+
+        let mut header = [0; WAL_HEADER_SIZE];
+        reader.read_exact(&mut header).unwrap();
+        let header = WalHeader::decode(&header).unwrap().unwrap();
+        assert_eq!(header.version, WAL_FORMAT_VERSION);
+        assert_eq!(log_number_from_path(path).unwrap(), header.log_number);
+
+        reader
+    }
+
+    fn check_entry(
+        reader: &mut BufReader<File>,
+        seq: u64,
+        key: &[u8],
+        value: Option<&[u8]>,
+        timestamp: u128,
+        deleted: bool,
+    ) {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        reader.read_exact(&mut header).unwrap();
+        assert_eq!(header[16], RecordType::Entry as u8);
+        let len = u32::from_le_bytes(header[12..16].try_into().unwrap());
+
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload).unwrap();
+        let mut payload = payload.as_slice();
+
+        let file_seq = get_varint(&mut payload).unwrap();
+        assert_eq!(file_seq, seq);
+
+        let file_deleted = get_bytes(&mut payload, 1).unwrap()[0] != 0;
+        assert_eq!(file_deleted, deleted);
+
+        let file_key_len = get_varint(&mut payload).unwrap() as usize;
+        assert_eq!(file_key_len, key.len());
+
+        if deleted {
+            let file_key = get_bytes(&mut payload, file_key_len).unwrap();
+            assert_eq!(file_key, key);
+        } else {
+            let file_value_len = get_varint(&mut payload).unwrap() as usize;
+            assert_eq!(file_value_len, value.unwrap().len());
+            let file_key = get_bytes(&mut payload, file_key_len).unwrap();
+            assert_eq!(file_key, key);
+            let file_value = get_bytes(&mut payload, file_value_len).unwrap();
+            assert_eq!(file_value, value.unwrap());
+        }
+
+        let file_timestamp = get_fixed_u64(&mut payload).unwrap() as u128;
+        assert_eq!(file_timestamp, timestamp);
+        assert!(payload.is_empty());
+    }
+
+    #[test]
+    fn test_write_one() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros();
+
+        let mut wal = Wal::new(&dir).unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", timestamp).unwrap();
+        wal.flush().unwrap();
+
+        let mut reader = segment_reader(&wal.path);
+
+        check_entry(
+            &mut reader,
+            1,
+            b"Lime",
+            Some(b"Lime Smoothie"),
+            timestamp,
+            false,
+        );
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_many() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros();
+
+        let entries: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"Apple", Some(b"Apple Smoothie")),
+            (b"Lime", Some(b"Lime Smoothie")),
+            (b"Orange", Some(b"Orange Smoothie")),
+        ];
+
+        let mut wal = Wal::new(&dir).unwrap();
+
+        for e in entries.iter() {
+            wal.set(e.0, e.1.unwrap(), timestamp).unwrap();
+        }
+        wal.flush().unwrap();
+
+        let mut reader = segment_reader(&wal.path);
+
+        for (i, e) in entries.iter().enumerate() {
+            check_entry(&mut reader, i as u64 + 1, e.0, e.1, timestamp, false);
+        }
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_delete() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros();
+
+        let entries: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"Apple", Some(b"Apple Smoothie")),
+            (b"Lime", Some(b"Lime Smoothie")),
+            (b"Orange", Some(b"Orange Smoothie")),
+        ];
+
+        let mut wal = Wal::new(&dir).unwrap();
+
+        for e in entries.iter() {
+            wal.set(e.0, e.1.unwrap(), timestamp).unwrap();
+        }
+        for e in entries.iter() {
+            wal.delete(e.0, timestamp).unwrap();
+        }
+
+        wal.flush().unwrap();
+
+        let mut reader = segment_reader(&wal.path);
+
+        for (i, e) in entries.iter().enumerate() {
+            check_entry(&mut reader, i as u64 + 1, e.0, e.1, timestamp, false);
+        }
+        for (i, e) in entries.iter().enumerate() {
+            check_entry(&mut reader, i as u64 + 4, e.0, None, timestamp, true);
+        }
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_wal_none() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_mem_table.len(), 0);
+
+        let m = metadata(new_wal.path).unwrap();
+        assert_eq!(m.len(), WAL_HEADER_SIZE as u64);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_wal_one() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let entries: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"Apple", Some(b"Apple Smoothie")),
+            (b"Lime", Some(b"Lime Smoothie")),
+            (b"Orange", Some(b"Orange Smoothie")),
+        ];
+
+        let mut wal = Wal::new(&dir).unwrap();
+
+        for (i, e) in entries.iter().enumerate() {
+            wal.set(e.0, e.1.unwrap(), i as u128).unwrap();
+        }
+        wal.flush().unwrap();
+
+        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_wal.last_sequence(), 3);
+        assert_eq!(new_wal.num_segments(), 2);
+
+        // The old segment is kept untouched
+        let mut reader = segment_reader(&wal.path);
+
+        for (i, e) in entries.iter().enumerate() {
+            check_entry(&mut reader, i as u64 + 1, e.0, e.1, i as u128, false);
+
+            let mem_e = new_mem_table.get(e.0).unwrap();
+            assert_eq!(mem_e.key, e.0);
+            assert_eq!(mem_e.value.as_ref().unwrap().as_slice(), e.1.unwrap());
+            assert_eq!(mem_e.timestamp, i as u128);
+        }
+
+        // New operations go to a fresh segment
+        let m = metadata(&new_wal.path).unwrap();
+        assert_eq!(m.len(), WAL_HEADER_SIZE as u64);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_wal_multiple() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let entries_1: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"Apple", Some(b"Apple Smoothie")),
+            (b"Lime", Some(b"Lime Smoothie")),
+            (b"Orange", Some(b"Orange Smoothie")),
+        ];
+        let mut wal_1 = Wal::new(&dir).unwrap();
+        for (i, e) in entries_1.iter().enumerate() {
+            wal_1.set(e.0, e.1.unwrap(), i as u128).unwrap();
+        }
+        wal_1.flush().unwrap();
+
+        let entries_2: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"Strawberry", Some(b"Strawberry Smoothie")),
+            (b"Blueberry", Some(b"Blueberry Smoothie")),
+            (b"Orange", Some(b"Orange Milkshake")),
+        ];
+        let mut wal_2 = Wal::new(&dir).unwrap();
+        for (i, e) in entries_2.iter().enumerate() {
+            wal_2.set(e.0, e.1.unwrap(), (i + 3) as u128).unwrap();
+        }
+        wal_2.flush().unwrap();
+
+        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_wal.last_sequence(), 6);
+        assert_eq!(new_wal.num_segments(), 3);
+
+        for (i, e) in entries_1.iter().enumerate() {
+            let mem_e = new_mem_table.get(e.0).unwrap();
+            if i != 2 {
+                assert_eq!(mem_e.key, e.0);
+                assert_eq!(mem_e.value.as_ref().unwrap().as_slice(), e.1.unwrap());
+                assert_eq!(mem_e.timestamp, i as u128);
+            } else {
+                assert_eq!(mem_e.key, e.0);
+                assert_ne!(mem_e.value.as_ref().unwrap().as_slice(), e.1.unwrap());
+                assert_ne!(mem_e.timestamp, i as u128);
+            }
+        }
+        for (i, e) in entries_2.iter().enumerate() {
+            let mem_e = new_mem_table.get(e.0).unwrap();
+            assert_eq!(mem_e.key, e.0);
+            assert_eq!(mem_e.value.as_ref().unwrap().as_slice(), e.1.unwrap());
+            assert_eq!(mem_e.timestamp, (i + 3) as u128);
+        }
+
+        // The recovered WAL iterates over every segment in order
+        let seqs: Vec<u64> = new_wal.into_iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5, 6]);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_segments() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        // Every small entry is 29B of overhead plus the key and value, and
+        // segments have a header of 32B
+        let options = WalOptions {
+            max_segment_size: 120,
+            ..Default::default()
+        };
+        let mut wal = Wal::with_options(&dir, options).unwrap();
+        for i in 0..10u128 {
+            wal.set(b"Apple", b"Apple Smoothie", i).unwrap();
+        }
+        wal.flush().unwrap();
+
+        // Segments rotate after every second entry
+        assert_eq!(wal.num_segments(), 6);
+        assert_eq!(files_with_ext(&dir, "wal").len(), 6);
+        let max_sequences: Vec<u64> = wal.segments.iter().map(|s| s.max_sequence).collect();
+        assert_eq!(max_sequences, vec![2, 4, 6, 8, 10]);
+
+        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_wal.last_sequence(), 10);
+        assert_eq!(new_mem_table.get(b"Apple").unwrap().timestamp, 9);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mark_persisted() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let options = WalOptions {
+            max_segment_size: 120,
+            ..Default::default()
+        };
+        let mut wal = Wal::with_options(&dir, options).unwrap();
+        for i in 0..5u128 {
+            wal.set(b"Apple", b"Apple Smoothie", i).unwrap();
+        }
+        assert_eq!(wal.num_segments(), 3);
+
+        // Only segments where every entry is persisted are deleted
+        wal.mark_persisted(3).unwrap();
+        assert_eq!(wal.num_segments(), 2);
+        assert_eq!(files_with_ext(&dir, "wal").len(), 2);
+
+        // The active segment is kept until it gets rotated
+        wal.mark_persisted(5).unwrap();
+        assert_eq!(wal.num_segments(), 1);
+        wal.set(b"Apple", b"Apple Smoothie", 5).unwrap();
+        assert_eq!(wal.num_segments(), 2);
+        wal.mark_persisted(6).unwrap();
+        assert_eq!(wal.num_segments(), 1);
+        assert_eq!(files_with_ext(&dir, "wal").len(), 1);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recycle_segments() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let options = WalOptions {
+            max_segment_size: 120,
+            recycle_log_file_num: 1,
+            ..Default::default()
+        };
+        let mut wal = Wal::with_options(&dir, options.clone()).unwrap();
+        for i in 0..4u128 {
+            wal.set(b"Apple", b"Apple Smoothie", i).unwrap();
+        }
+        assert_eq!(wal.num_segments(), 3);
+
+        // The oldest obsolete segment is kept for reuse, the other one deleted
+        wal.mark_persisted(4).unwrap();
+        assert_eq!(wal.num_segments(), 1);
+        assert_eq!(files_with_ext(&dir, "recycle").len(), 1);
+        assert_eq!(files_with_ext(&dir, "wal").len(), 1);
+
+        // Once the active segment rotates, the next one reuses the recycled
+        // file, overwriting it with shorter entries that leave stale data behind
+        for i in 4..7u128 {
+            wal.set(b"Lime", b"Lime Smoothie", i).unwrap();
+        }
+        wal.flush().unwrap();
+        assert_eq!(files_with_ext(&dir, "recycle").len(), 0);
+        assert_eq!(files_with_ext(&dir, "wal").len(), 2);
+        assert!(metadata(&wal.path).unwrap().len() > wal.size as u64);
+
+        // Only the new entries are read back from the recycled segment
+        let entries: Vec<WalEntry> = wal.into_iter().collect();
+        let seqs: Vec<u64> = entries.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![5, 6, 7]);
+        assert!(entries.iter().all(|e| e.key == b"Lime"));
+
+        let (new_wal, new_mem_table) = Wal::load_from_dir_with_options(&dir, options).unwrap();
+        assert_eq!(new_wal.last_sequence(), 7);
+        assert_eq!(new_mem_table.len(), 1);
+        assert_eq!(new_mem_table.get(b"Lime").unwrap().timestamp, 6);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recycled_segment_with_stale_header() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let options = WalOptions {
+            max_segment_size: 120,
+            ..Default::default()
+        };
+        let mut wal = Wal::with_options(&dir, options.clone()).unwrap();
+        wal.set(b"Apple", b"Old", 0).unwrap();
+        wal.set(b"Kiwi", b"Kiwi", 1).unwrap();
+        wal.set(b"Lime", b"Lime", 2).unwrap();
+        wal.set(b"Apple", b"New", 3).unwrap();
+        wal.flush().unwrap();
+        let newest_log_number = wal.log_number;
+        drop(wal);
+
+        // A crash right after the oldest segment was recycled as the newest
+        // one, before its header got rewritten
+        let mut paths = files_with_ext(&dir, "wal");
+        paths.sort_by_key(|path| log_number_from_path(path).unwrap());
+        let path = segment_path(&dir, newest_log_number + 1);
+        rename(&paths[0], &path).unwrap();
+        assert_eq!(WalIterator::new(path.clone()).unwrap().count(), 0);
+        assert!(verify_segment(&path).unwrap());
+
+        // The old entries of the recycled file are not replayed
+        let (new_wal, new_mem_table) = Wal::load_from_dir_with_options(&dir, options).unwrap();
+        assert_eq!(new_mem_table.len(), 1);
+        assert_eq!(
+            new_mem_table.get(b"Apple").unwrap().value.as_deref(),
+            Some(b"New".as_slice())
+        );
+        assert_eq!(new_wal.last_sequence(), 4);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_stops_at_corruption() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let mut wal = Wal::new(&dir).unwrap();
+        wal.set(b"Apple", b"Apple Smoothie", 0).unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", 1).unwrap();
+        wal.flush().unwrap();
+
+        // Flip a byte in the value of the second entry
+        let mut data = std::fs::read(&wal.path).unwrap();
+        let len = data.len();
+        data[len - 20] ^= 0xFF;
+        std::fs::write(&wal.path, data).unwrap();
+
+        let entries: Vec<WalEntry> = WalIterator::new(wal.path.clone()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, b"Apple");
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let mut wal = Wal::new(&dir).unwrap();
+        wal.set(b"Apple", b"Apple Smoothie", 0).unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", 1).unwrap();
+        assert!(wal.verify_checksums().unwrap().is_empty());
+
+        // A torn write at the end of the segment is not a corruption
+        let data = std::fs::read(&wal.path).unwrap();
+        let len = data.len();
+        std::fs::write(&wal.path, &data[..len - 5]).unwrap();
+        assert!(wal.verify_checksums().unwrap().is_empty());
+
+        // A flipped byte in the value of the first entry hides the second one
+        let mut data = data;
+        data[WAL_HEADER_SIZE + RECORD_HEADER_SIZE + 10] ^= 0xFF;
+        std::fs::write(&wal.path, data).unwrap();
+        assert_eq!(wal.verify_checksums().unwrap(), vec![wal.path.clone()]);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_entries() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let options = WalOptions {
+            max_segment_size: 256,
+            compression: CompressionType::Lz4,
+            ..Default::default()
+        };
+        let value = br#"{"fruit": "Lime", "smoothie": true, "sizes": [1, 1, 1, 1]}"#.repeat(8);
+        let mut wal = Wal::with_options(&dir, options.clone()).unwrap();
+        for i in 0..10u128 {
+            wal.set(b"Lime", &value, i).unwrap();
+        }
+        wal.delete(b"Lime", 10).unwrap();
+        wal.flush().unwrap();
+
+        // The entries are written to disk compressed across several segments
+        let stats = wal.stats();
+        assert!(wal.num_segments() > 1);
+        assert!(stats.bytes_after_compression < stats.bytes_before_compression);
+        assert!(stats.compression_ratio() > 2.0);
+
+        let (new_wal, new_mem_table) = Wal::load_from_dir_with_options(&dir, options).unwrap();
+        assert_eq!(new_wal.last_sequence(), 11);
+        assert!(new_mem_table.get(b"Lime").unwrap().deleted);
+
+        let entries: Vec<WalEntry> = new_wal.into_iter().collect();
+        assert_eq!(entries.len(), 11);
+        assert!(entries[..10]
+            .iter()
+            .all(|e| e.value.as_deref() == Some(value.as_slice())));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_header() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let timestamp = now_micros() as u64;
+        let mut wal = Wal::new(&dir).unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", 0).unwrap();
+        wal.flush().unwrap();
+
+        let iter = WalIterator::new(wal.path.clone()).unwrap();
+        let header = *iter.header().unwrap();
+        assert_eq!(header.version, WAL_FORMAT_VERSION);
+        assert_eq!(header.log_number, wal.log_number);
+        assert!(header.created_at >= timestamp);
+        assert_eq!(iter.count(), 1);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    /// Encodes an entry the way the first version of the WAL wrote it, before
+    /// segments had records or sequence numbers
+    fn encode_baseline_entry(key: &[u8], value: Option<&[u8]>, timestamp: u128) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&key.len().to_le_bytes());
+        data.push(value.is_none() as u8);
+        if let Some(value) = value {
+            data.extend_from_slice(&value.len().to_le_bytes());
+        }
+        data.extend_from_slice(key);
+        if let Some(value) = value {
+            data.extend_from_slice(value);
+        }
+        data.extend_from_slice(&timestamp.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_read_baseline_segment() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        // A segment written by the first version of the WAL, ending with a
+        // torn entry
+        let mut data = Vec::new();
+        data.extend(encode_baseline_entry(b"Apple", Some(b"Apple Smoothie"), 1));
+        data.extend(encode_baseline_entry(b"Lime", Some(b"Lime Smoothie"), 2));
+        data.extend(encode_baseline_entry(b"Apple", None, 3));
+        data.extend(&encode_baseline_entry(b"Orange", Some(b"Orange Smoothie"), 4)[..20]);
+        let path = dir.join("1600000000000000.wal");
+        std::fs::write(&path, &data).unwrap();
+
+        let iter = WalIterator::new(path.clone()).unwrap();
+        assert!(iter.header().is_none());
+        assert!(iter.is_baseline());
+        assert_eq!(iter.version(), 0);
+        let entries: Vec<WalEntry> = iter.collect();
+        let seqs: Vec<u64> = entries.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+        assert!(entries[2].deleted);
+        assert_eq!(entries[1].timestamp, 2);
+
+        let (mut new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_wal.last_sequence(), 3);
+        assert!(new_mem_table.get(b"Apple").unwrap().deleted);
+        assert_eq!(
+            new_mem_table.get(b"Lime").unwrap().value.as_deref(),
+            Some(b"Lime Smoothie".as_slice())
+        );
+        // The segment is upgraded, recording the sequence numbers given to
+        // its operations
+        let iter = WalIterator::new(path).unwrap();
+        assert_eq!(iter.version(), WAL_FORMAT_VERSION);
+        assert_eq!(iter.map(|e| e.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        // New operations are numbered after the ones of the baseline segment
+        assert_eq!(new_wal.set(b"Orange", b"Orange Smoothie", 4).unwrap(), 4);
+        new_wal.flush().unwrap();
+        drop(new_wal);
+        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_wal.last_sequence(), 4);
+        assert_eq!(new_mem_table.get(b"Orange").unwrap().seq, 4);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_invalid_header() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        // A file that is not a WAL segment
+        let path = dir.join("1.wal");
+        std::fs::write(&path, b"Lime Smoothie, Orange Smoothie, Apple Smoothie").unwrap();
+        let err = WalIterator::new(path.clone()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(Wal::load_from_dir(&dir).is_err());
+
+        // A segment from a newer version of the format
+        let header = WalHeader {
+            version: WAL_FORMAT_VERSION + 1,
+            log_number: 1,
+            created_at: 0,
+        };
+        std::fs::write(&path, header.encode()).unwrap();
+        let err = WalIterator::new(path.clone()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A corrupted header
+        let mut data = WalHeader {
+            version: WAL_FORMAT_VERSION,
+            ..header
+        }
+        .encode();
+        data[14] ^= 0xFF;
+        std::fs::write(&path, data).unwrap();
+        let err = WalIterator::new(path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    /// Encodes an entry the way version 1 of the format did
+    fn encode_entry_v1(seq: u64, key: &[u8], value: Option<&[u8]>, timestamp: u128) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&seq.to_le_bytes());
+        payload.extend_from_slice(&(key.len() as u64).to_le_bytes());
+        payload.push(value.is_none() as u8);
+        if let Some(value) = value {
+            payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        }
+        payload.extend_from_slice(key);
+        if let Some(value) = value {
+            payload.extend_from_slice(value);
+        }
+        payload.extend_from_slice(&timestamp.to_le_bytes());
+        payload
+    }
+
+    #[test]
+    fn test_entry_encoding_size() {
+        let op = WriteOp::Set {
+            key: b"Lime".to_vec(),
+            value: b"Lime Smoothie".to_vec(),
+        };
+        let payload = encode_entry(1, &op, 10).unwrap();
+        let payload_v1 = encode_entry_v1(1, b"Lime", Some(b"Lime Smoothie"), 10);
+        assert_eq!(payload.len(), 1 + 1 + 1 + 1 + 4 + 13 + 8);
+        assert_eq!(payload_v1.len(), 8 + 8 + 1 + 8 + 4 + 13 + 16);
+
+        let entry = decode_entry(WAL_FORMAT_VERSION, &payload).unwrap();
+        assert_eq!(entry.seq, 1);
+        assert_eq!(entry.key, b"Lime");
+        assert_eq!(entry.value.unwrap(), b"Lime Smoothie");
+        assert_eq!(entry.timestamp, 10);
+        assert!(!entry.deleted);
+        assert!(!entry.merge);
+
+        let op = WriteOp::Merge {
+            key: b"Lime".to_vec(),
+            operand: b"Lime Smoothie".to_vec(),
+        };
+        let payload = encode_entry(2, &op, 20).unwrap();
+        let entry = decode_entry(WAL_FORMAT_VERSION, &payload).unwrap();
+        assert!(entry.merge);
+        assert_eq!(entry.value.as_ref().unwrap(), b"Lime Smoothie");
+        assert_eq!(entry.to_op(), op);
+
+        let err = encode_entry(1, &op, u64::MAX as u128 + 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_upgrade_v1_segment() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        // A segment written with version 1 of the format
+        let log_number = 1_000;
+        let header = WalHeader {
+            version: 1,
+            log_number,
+            created_at: 42,
+        };
+        let mut data = header.encode().to_vec();
+        append_record(
+            &mut data,
+            log_number,
+            RecordType::Entry,
+            &encode_entry_v1(1, b"Apple", Some(b"Apple Smoothie"), 0),
+        );
+        append_record(
+            &mut data,
+            log_number,
+            RecordType::Entry,
+            &encode_entry_v1(2, b"Apple", None, 1),
+        );
+        append_record(
+            &mut data,
+            log_number,
+            RecordType::Entry,
+            &encode_entry_v1(3, b"Lime", Some(b"Lime Smoothie"), 2),
+        );
+        let path = segment_path(&dir, log_number);
+        std::fs::write(&path, &data).unwrap();
+
+        // Old segments are readable as they are
+        let iter = WalIterator::new(path.clone()).unwrap();
+        assert_eq!(iter.version(), 1);
+        let entries: Vec<WalEntry> = iter.collect();
+        assert_eq!(entries.len(), 3);
+
+        assert_eq!(Wal::upgrade_dir(&dir).unwrap(), 1);
+        assert_eq!(Wal::upgrade_dir(&dir).unwrap(), 0);
+
+        // The upgraded segment holds the same entries in less space
+        assert!(metadata(&path).unwrap().len() < data.len() as u64);
+        let iter = WalIterator::new(path.clone()).unwrap();
+        assert_eq!(iter.version(), WAL_FORMAT_VERSION);
+        assert_eq!(iter.header().unwrap().created_at, 42);
+        for (entry, upgraded) in entries.iter().zip(iter) {
+            assert_eq!(entry.seq, upgraded.seq);
+            assert_eq!(entry.key, upgraded.key);
+            assert_eq!(entry.value, upgraded.value);
+            assert_eq!(entry.timestamp, upgraded.timestamp);
+            assert_eq!(entry.deleted, upgraded.deleted);
+        }
+
+        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_wal.last_sequence(), 3);
+        assert!(new_mem_table.get(b"Apple").unwrap().deleted);
+        assert_eq!(new_mem_table.get(b"Lime").unwrap().timestamp, 2);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_damaged_segment() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        let log_number = 1_000;
+        let header = WalHeader {
+            version: 1,
+            log_number,
+            created_at: 42,
+        };
+        let mut data = header.encode().to_vec();
+        append_record(
+            &mut data,
+            log_number,
+            RecordType::Entry,
+            &encode_entry_v1(1, b"Apple", Some(b"Apple Smoothie"), 0),
+        );
+        let offset = data.len();
+        let last = encode_entry_v1(2, b"Lime", Some(b"Lime Smoothie"), 1);
+        let path = segment_path(&dir, log_number);
+
+        // A torn write is the end of the segment
+        let mut torn = data.clone();
+        append_record(&mut torn, log_number, RecordType::Entry, &last);
+        torn.truncate(torn.len() - 4);
+        std::fs::write(&path, &torn).unwrap();
+        assert_eq!(Wal::upgrade_dir(&dir).unwrap(), 1);
+        assert_eq!(WalIterator::new(path.clone()).unwrap().count(), 1);
+
+        // A record that can't be decoded is reported instead of dropped, with
+        // the records after it
+        let mut damaged = data.clone();
+        append_record(&mut damaged, log_number, RecordType::Entry, &[0xff]);
+        append_record(&mut damaged, log_number, RecordType::Entry, &last);
+        std::fs::write(&path, &damaged).unwrap();
+        let err = Wal::upgrade_dir(&dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(&format!("offset {}", offset)));
+
+        // And so is a corrupted record with valid ones after it
+        let mut corrupted = data.clone();
+        append_record(&mut corrupted, log_number, RecordType::Entry, &last);
+        append_record(&mut corrupted, log_number, RecordType::Entry, &last);
+        corrupted[offset + RECORD_HEADER_SIZE] ^= 0xff;
+        std::fs::write(&path, &corrupted).unwrap();
+        let err = Wal::upgrade_dir(&dir).unwrap_err();
+        assert!(err.to_string().contains(&format!("offset {}", offset)));
+
+        // The segment is left as it was
+        assert_eq!(std::fs::read(&path).unwrap(), corrupted);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_baseline_segments() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        // Segments written by the first version of the WAL
+        let mut data = encode_baseline_entry(b"Apple", Some(b"Apple Smoothie"), 1);
+        data.extend(encode_baseline_entry(b"Lime", Some(b"Lime Smoothie"), 2));
+        let path_1 = dir.join("1600000000000000.wal");
+        std::fs::write(&path_1, &data).unwrap();
+        let path_2 = dir.join("1600000000000001.wal");
+        std::fs::write(&path_2, encode_baseline_entry(b"Apple", None, 3)).unwrap();
+
+        assert_eq!(Wal::upgrade_dir(&dir).unwrap(), 2);
+        assert_eq!(Wal::upgrade_dir(&dir).unwrap(), 0);
+
+        // The operations keep numbering across the segments
+        let iter = WalIterator::new(path_1).unwrap();
+        assert_eq!(iter.version(), WAL_FORMAT_VERSION);
+        assert_eq!(iter.header().unwrap().created_at, 1_600_000_000_000_000);
+        let entries: Vec<WalEntry> = iter.collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[1].seq, entries[1].timestamp), (2, 2));
+        assert_eq!(
+            entries[1].value.as_deref(),
+            Some(b"Lime Smoothie".as_slice())
+        );
+        let entries: Vec<WalEntry> = WalIterator::new(path_2).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].seq, 3);
+        assert!(entries[0].deleted);
+
+        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_wal.last_sequence(), 3);
+        assert!(new_mem_table.get(b"Apple").unwrap().deleted);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_headerless_segment() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
+
+        // Segments written before headers start straight with the records
+        let log_number = 1_000;
+        let mut data = Vec::new();
+        append_record(
+            &mut data,
+            log_number,
+            RecordType::SetCompression,
+            &[CompressionType::Lz4 as u8],
+        );
+        for (seq, key, value) in [
+            (1, b"Apple".as_slice(), Some(b"Apple Smoothie".as_slice())),
+            (2, b"Lime", Some(b"Lime Smoothie")),
+            (3, b"Apple", None),
+        ] {
+            let payload = encode_entry_v1(seq, key, value, seq as u128);
+            append_record(
+                &mut data,
+                log_number,
+                RecordType::Entry,
+                &CompressionType::Lz4.compress(&payload),
+            );
+        }
+        let path = segment_path(&dir, log_number);
+        std::fs::write(&path, &data).unwrap();
+
+        let iter = WalIterator::new(path).unwrap();
+        assert!(iter.header().is_none());
+        assert_eq!(iter.version(), 0);
+        let seqs: Vec<u64> = iter.map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+
+        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_wal.last_sequence(), 3);
+        assert!(new_mem_table.get(b"Apple").unwrap().deleted);
+        assert_eq!(
+            new_mem_table.get(b"Lime").unwrap().value.as_ref().unwrap(),
+            b"Lime Smoothie"
+        );
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_updates_since() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
 
-        Ok(())
-    }
+        let options = WalOptions {
+            max_segment_size: 120,
+            archive_size_limit: Some(u64::MAX),
+            ..Default::default()
+        };
+        let mut wal = Wal::with_options(&dir, options.clone()).unwrap();
+        for i in 0..6u128 {
+            wal.set(b"Apple", b"Apple Smoothie", i).unwrap();
+        }
+        wal.delete(b"Apple", 6).unwrap();
 
-    /// Flushes the WAL to disk
-    ///
-    /// This is useful for applying bulk operations and flushing the final result
-    /// to disk. Waiting to flush after the bulk operations have been performed will
-    /// improve the write performance substantially
-    pub fn flush(&mut self) -> io::Result<()> {
-        self.file.flush()
-    }
+        // Persisted segments are archived instead of deleted
+        wal.mark_persisted(4).unwrap();
+        assert_eq!(wal.num_segments(), 2);
+        assert_eq!(files_with_ext(&dir.join(ARCHIVE_DIR), "wal").len(), 2);
 
-    /// Loads the WAL(s) within a directory, returning a new WAL and the recovered MemTable.
-    ///
-    /// If multiple WAL exist in a directory, they are merged by file date.
-    pub fn load_from_dir(dir: &Path) -> io::Result<(Wal, MemTable)> {
-        let mut wal_files = files_with_ext(dir, "wal");
-        wal_files.sort();
+        let seqs: Vec<u64> = wal.get_updates_since(0).unwrap().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5, 6, 7]);
 
-        let mut new_memtable = MemTable::new();
-        let mut new_wal = Wal::new(dir)?;
+        let entries: Vec<WalEntry> = wal.get_updates_since(3).unwrap().collect();
+        let seqs: Vec<u64> = entries.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![3, 4, 5, 6, 7]);
+        assert!(entries[4].deleted);
 
-        for wal_file in wal_files.iter() {
-            if let Ok(wal) = Wal::from_path(wal_file) {
-                for entry in wal.into_iter() {
-                    if entry.deleted {
-                        new_memtable.delete(entry.key.as_slice(), entry.timestamp);
-                        new_wal.delete(entry.key.as_slice(), entry.timestamp)?;
-                    } else {
-                        new_memtable.set(
-                            entry.key.as_slice(),
-                            entry.value.as_ref().expect("a value to exist").as_slice(),
-                            entry.timestamp,
-                        );
-                        new_wal.set(
-                            entry.key.as_slice(),
-                            entry.value.as_ref().expect("a value to exist").as_slice(),
-                            entry.timestamp,
-                        )?;
-                    }
-                }
-            }
-        }
-        new_wal.flush()?;
-        wal_files.into_iter().try_for_each(remove_file)?;
-        Ok((new_wal, new_memtable))
-    }
-}
+        assert_eq!(wal.get_updates_since(8).unwrap().count(), 0);
 
-/// An iterator over all the entries in a WAL file
-pub struct WalIterator {
-    reader: BufReader<File>,
-}
+        // Updates are still available after a restart
+        drop(wal);
+        let (mut new_wal, _) = Wal::load_from_dir_with_options(&dir, options).unwrap();
+        new_wal.set(b"Lime", b"Lime Smoothie", 7).unwrap();
+        let seqs: Vec<u64> = new_wal
+            .get_updates_since(6)
+            .unwrap()
+            .map(|e| e.seq)
+            .collect();
+        assert_eq!(seqs, vec![6, 7, 8]);
 
-impl WalIterator {
-    pub fn new(path: PathBuf) -> io::Result<Self> {
-        let file = OpenOptions::new().read(true).open(path)?;
-        let reader = BufReader::new(file);
-        Ok(WalIterator { reader })
+        remove_dir_all(&dir).unwrap();
     }
-}
 
-impl Iterator for WalIterator {
-    type Item = WalEntry;
+    #[test]
+    fn test_get_updates_since_opens_segments_lazily() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
 
-    fn next(&mut self) -> Option<Self::Item> {
-        // Read the key size field
-        let mut len_buffer = [0; 8];
-        self.reader.read_exact(&mut len_buffer).ok()?;
-        let key_len = usize::from_le_bytes(len_buffer);
+        // Each segment has a header and two entries of 48B
+        let options = WalOptions {
+            max_segment_size: 120,
+            archive_size_limit: Some(u64::MAX),
+            ..Default::default()
+        };
+        let mut wal = Wal::with_options(&dir, options).unwrap();
+        for i in 0..4u128 {
+            wal.set(b"Apple", b"Apple Smoothie", i).unwrap();
+        }
 
-        // Read the tombstone field
-        let mut bool_buffer = [0; 1];
-        self.reader.read_exact(&mut bool_buffer).ok()?;
-        let deleted = bool_buffer[0] != 0;
+        // Segments archived before the iterator reaches them are read from the
+        // archive, and operations appended later are not returned
+        let updates = wal.get_updates_since(1).unwrap();
+        wal.mark_persisted(4).unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", 4).unwrap();
+        assert_eq!(files_with_ext(&dir.join(ARCHIVE_DIR), "wal").len(), 2);
+        let seqs: Vec<u64> = updates.map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4]);
+
+        // Segments deleted before they are reached stop the iteration
+        let mut updates = wal.get_updates_since(1).unwrap();
+        let archived = files_with_ext(&dir.join(ARCHIVE_DIR), "wal");
+        remove_file(archived.iter().max().unwrap()).unwrap();
+        let seqs: Vec<u64> = updates.by_ref().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2]);
+        let err = updates.status().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
 
-        // Read the key and value
-        let mut key = vec![0; key_len];
-        let mut value = None;
+        remove_dir_all(&dir).unwrap();
+    }
 
-        if deleted {
-            self.reader.read_exact(&mut key).ok()?;
-        } else {
-            self.reader.read_exact(&mut len_buffer).ok()?;
-            let value_len = usize::from_le_bytes(len_buffer);
-            self.reader.read_exact(&mut key).ok()?;
+    #[test]
+    fn test_archive_retention() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
 
-            let mut value_buf = vec![0; value_len];
-            self.reader.read_exact(&mut value_buf).ok()?;
-            value = Some(value_buf);
+        // Each segment has a header and two entries of 48B
+        let options = WalOptions {
+            max_segment_size: 120,
+            archive_size_limit: Some(2 * 128),
+            ..Default::default()
+        };
+        let mut wal = Wal::with_options(&dir, options).unwrap();
+        for i in 0..8u128 {
+            wal.set(b"Apple", b"Apple Smoothie", i).unwrap();
         }
+        wal.mark_persisted(8).unwrap();
 
-        // Read the timestamp
-        let mut timestamp_buffer = [0; 16];
-        self.reader.read_exact(&mut timestamp_buffer).ok();
-        let timestamp = u128::from_le_bytes(timestamp_buffer);
+        // Only the two most recent segments fit in the archive
+        assert_eq!(files_with_ext(&dir.join(ARCHIVE_DIR), "wal").len(), 2);
+        let seqs: Vec<u64> = wal.get_updates_since(5).unwrap().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![5, 6, 7, 8]);
 
-        Some(WalEntry {
-            key,
-            value,
-            timestamp,
-            deleted,
-        })
+        // Consumers that fell behind can't catch up anymore
+        let err = wal.get_updates_since(4).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // Expired segments are deleted from the archive
+        wal.options.archive_ttl = Some(Duration::ZERO);
+        wal.purge_archive().unwrap();
+        assert_eq!(files_with_ext(&dir.join(ARCHIVE_DIR), "wal").len(), 0);
+        assert_eq!(wal.get_updates_since(9).unwrap().count(), 0);
+        let err = wal.get_updates_since(8).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        remove_dir_all(&dir).unwrap();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use rand::Rng;
+    #[test]
+    fn test_load_until_timestamp() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
 
-    use super::*;
+        let options = WalOptions {
+            max_segment_size: 120,
+            ..Default::default()
+        };
+        let mut wal = Wal::with_options(&dir, options.clone()).unwrap();
+        wal.set(b"Apple", b"Apple Smoothie", 10).unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", 20).unwrap();
+        wal.set(b"Orange", b"Orange Smoothie", 30).unwrap();
+        // A bad deploy starts writing garbage
+        wal.set(b"Apple", b"Garbage", 40).unwrap();
+        wal.delete(b"Lime", 50).unwrap();
+        wal.set(b"Orange", b"Garbage", 60).unwrap();
+        wal.flush().unwrap();
+        drop(wal);
+
+        let target = RecoveryTarget::Timestamp(35);
+        let (mut new_wal, new_mem_table) =
+            Wal::load_from_dir_until(&dir, options.clone(), target).unwrap();
+        assert_eq!(new_wal.last_sequence(), 3);
+        assert_eq!(new_mem_table.len(), 3);
+        for (key, value) in [
+            (b"Apple".as_slice(), b"Apple Smoothie".as_slice()),
+            (b"Lime", b"Lime Smoothie"),
+            (b"Orange", b"Orange Smoothie"),
+        ] {
+            let entry = new_mem_table.get(key).unwrap();
+            assert_eq!(entry.value.as_ref().unwrap(), value);
+        }
 
-    use std::fs::{create_dir, remove_dir_all};
-    use std::fs::{metadata, File, OpenOptions};
-    use std::io::BufReader;
-    use std::path::PathBuf;
-    use std::time::{SystemTime, UNIX_EPOCH};
+        // The discarded segments are kept aside
+        let backup_dir = read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.is_dir())
+            .unwrap();
+        assert!(backup_dir
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("discarded-"));
+        assert_eq!(files_with_ext(&backup_dir, "wal").len(), 2);
+
+        // New operations continue after the recovered ones
+        assert_eq!(new_wal.set(b"Lime", b"Lime Milkshake", 70).unwrap(), 4);
+        new_wal.flush().unwrap();
+        drop(new_wal);
+
+        // The discarded operations are not replayed again
+        let (new_wal, new_mem_table) = Wal::load_from_dir_with_options(&dir, options).unwrap();
+        assert_eq!(new_wal.last_sequence(), 4);
+        let seqs: Vec<u64> = new_wal.into_iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4]);
+        assert_eq!(
+            new_mem_table.get(b"Apple").unwrap().value.as_ref().unwrap(),
+            b"Apple Smoothie"
+        );
+        assert_eq!(
+            new_mem_table.get(b"Lime").unwrap().value.as_ref().unwrap(),
+            b"Lime Milkshake"
+        );
 
-    fn check_entry(
-        reader: &mut BufReader<File>,
-        key: &[u8],
-        value: Option<&[u8]>,
-        timestamp: u128,
-        deleted: bool,
-    ) {
-        let mut len_buffer = [0; 8];
-        reader.read_exact(&mut len_buffer).unwrap();
-        let file_key_len = usize::from_le_bytes(len_buffer);
-        assert_eq!(file_key_len, key.len());
+        remove_dir_all(&dir).unwrap();
+    }
 
-        let mut bool_buffer = [0; 1];
-        reader.read_exact(&mut bool_buffer).unwrap();
-        let file_deleted = bool_buffer[0] != 0;
-        assert_eq!(file_deleted, deleted);
+    #[test]
+    fn test_load_until_sequence() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
 
-        if deleted {
-            let mut file_key = vec![0; file_key_len];
-            reader.read_exact(&mut file_key).unwrap();
-            assert_eq!(file_key, key);
-        } else {
-            reader.read_exact(&mut len_buffer).unwrap();
-            let file_value_len = usize::from_le_bytes(len_buffer);
-            assert_eq!(file_value_len, value.unwrap().len());
-            let mut file_key = vec![0; file_key_len];
-            reader.read_exact(&mut file_key).unwrap();
-            assert_eq!(file_key, key);
-            let mut file_value = vec![0; file_value_len];
-            reader.read_exact(&mut file_value).unwrap();
-            assert_eq!(file_value, value.unwrap());
+        let mut wal = Wal::new(&dir).unwrap();
+        for i in 0..5u128 {
+            wal.set(b"Apple", format!("Apple Smoothie {}", i).as_bytes(), i)
+                .unwrap();
         }
+        wal.flush().unwrap();
+        drop(wal);
+
+        let target = RecoveryTarget::Sequence(2);
+        let (new_wal, new_mem_table) =
+            Wal::load_from_dir_until(&dir, WalOptions::default(), target).unwrap();
+        assert_eq!(new_wal.last_sequence(), 2);
+        assert_eq!(
+            new_mem_table.get(b"Apple").unwrap().value.as_ref().unwrap(),
+            b"Apple Smoothie 1"
+        );
 
-        let mut timestamp_buffer = [0; 16];
-        reader.read_exact(&mut timestamp_buffer).unwrap();
-        let file_timestamp = u128::from_le_bytes(timestamp_buffer);
-        assert_eq!(file_timestamp, timestamp);
+        // Targets past the end of the WAL replay everything
+        drop(new_wal);
+        let target = RecoveryTarget::Sequence(10);
+        let (new_wal, _) = Wal::load_from_dir_until(&dir, WalOptions::default(), target).unwrap();
+        assert_eq!(new_wal.last_sequence(), 2);
+
+        remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_write_one() {
+    fn test_write_batch() {
         let mut rng = rand::thread_rng();
         let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
         create_dir(&dir).unwrap();
 
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_micros();
+        let mut batch = WriteBatch::new();
+        batch.set(b"Apple", b"Apple Smoothie");
+        batch.set(b"Lime", b"Lime Smoothie");
+        batch.delete(b"Apple");
 
         let mut wal = Wal::new(&dir).unwrap();
-        wal.set(b"Lime", b"Lime Smoothie", timestamp).unwrap();
+        wal.set(b"Orange", b"Orange Smoothie", 0).unwrap();
+        assert_eq!(wal.write_batch(&batch, 10).unwrap(), 4);
+        assert_eq!(wal.write_batch(&WriteBatch::new(), 20).unwrap(), 4);
+        assert_eq!(wal.delete(b"Orange", 30).unwrap(), 5);
         wal.flush().unwrap();
 
-        let file = OpenOptions::new().read(true).open(&wal.path).unwrap();
-        let mut reader = BufReader::new(file);
+        let entries: Vec<WalEntry> = WalIterator::new(wal.path.clone()).unwrap().collect();
+        let seqs: Vec<u64> = entries.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5]);
+        assert_eq!(entries[1].key, b"Apple");
+        assert_eq!(entries[1].value.as_ref().unwrap(), b"Apple Smoothie");
+        assert_eq!(entries[2].timestamp, 10);
+        assert!(entries[3].deleted);
 
-        check_entry(
-            &mut reader,
-            b"Lime",
-            Some(b"Lime Smoothie"),
-            timestamp,
-            false,
-        );
+        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_wal.last_sequence(), 5);
+        assert!(new_mem_table.get(b"Apple").unwrap().deleted);
+        assert_eq!(new_mem_table.get(b"Apple").unwrap().seq, 4);
+        assert_eq!(new_mem_table.get(b"Lime").unwrap().seq, 3);
 
         remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_write_many() {
+    fn test_load_until_mid_batch() {
         let mut rng = rand::thread_rng();
         let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
         create_dir(&dir).unwrap();
 
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_micros();
-
-        let entries: Vec<(&[u8], Option<&[u8]>)> = vec![
-            (b"Apple", Some(b"Apple Smoothie")),
-            (b"Lime", Some(b"Lime Smoothie")),
-            (b"Orange", Some(b"Orange Smoothie")),
-        ];
+        let mut batch = WriteBatch::new();
+        batch.set(b"Apple", b"Apple Smoothie");
+        batch.set(b"Lime", b"Lime Smoothie");
 
         let mut wal = Wal::new(&dir).unwrap();
-
-        for e in entries.iter() {
-            wal.set(e.0, e.1.unwrap(), timestamp).unwrap();
-        }
+        wal.set(b"Orange", b"Orange Smoothie", 0).unwrap();
+        wal.write_batch(&batch, 10).unwrap();
         wal.flush().unwrap();
+        drop(wal);
 
-        let file = OpenOptions::new().read(true).open(&wal.path).unwrap();
-        let mut reader = BufReader::new(file);
-
-        for e in entries.iter() {
-            check_entry(&mut reader, e.0, e.1, timestamp, false);
-        }
+        // Batches are recovered all or none
+        let target = RecoveryTarget::Sequence(2);
+        let (new_wal, new_mem_table) =
+            Wal::load_from_dir_until(&dir, WalOptions::default(), target).unwrap();
+        assert_eq!(new_wal.last_sequence(), 1);
+        assert_eq!(new_mem_table.len(), 1);
+        assert!(new_mem_table.get(b"Apple").is_none());
 
         remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_write_delete() {
+    fn test_prepared_transactions() {
         let mut rng = rand::thread_rng();
         let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
         create_dir(&dir).unwrap();
 
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_micros();
-
-        let entries: Vec<(&[u8], Option<&[u8]>)> = vec![
-            (b"Apple", Some(b"Apple Smoothie")),
-            (b"Lime", Some(b"Lime Smoothie")),
-            (b"Orange", Some(b"Orange Smoothie")),
-        ];
+        let mut batch = WriteBatch::new();
+        batch.set(b"Apple", b"Apple Smoothie");
+        batch.delete(b"Lime");
 
         let mut wal = Wal::new(&dir).unwrap();
-
-        for e in entries.iter() {
-            wal.set(e.0, e.1.unwrap(), timestamp).unwrap();
-        }
-        for e in entries.iter() {
-            wal.delete(e.0, timestamp).unwrap();
-        }
-
+        wal.set(b"Lime", b"Lime Smoothie", 0).unwrap();
+        wal.prepare("first", &batch).unwrap();
+        wal.prepare("second", &batch).unwrap();
+        wal.prepare("third", &batch).unwrap();
+        assert_eq!(
+            wal.prepare("first", &batch).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(wal.last_sequence(), 1);
+
+        let (committed, seq) = wal.commit_prepared("first", 10).unwrap();
+        assert_eq!(committed, batch);
+        assert_eq!(seq, 3);
+        wal.rollback_prepared("third").unwrap();
+        assert_eq!(
+            wal.commit_prepared("third", 20).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(wal.prepared_transactions(), vec!["second"]);
         wal.flush().unwrap();
 
-        let file = OpenOptions::new().read(true).open(&wal.path).unwrap();
-        let mut reader = BufReader::new(file);
+        // Prepared operations are not updates until they are committed
+        let seqs: Vec<u64> = wal.get_updates_since(0).unwrap().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+        drop(wal);
 
-        for e in entries.iter() {
-            check_entry(&mut reader, e.0, e.1, timestamp, false);
-        }
-        for e in entries.iter() {
-            check_entry(&mut reader, e.0, None, timestamp, true);
-        }
+        let (mut new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_wal.last_sequence(), 3);
+        assert_eq!(new_wal.prepared_transactions(), vec!["second"]);
+        assert!(new_mem_table.get(b"Lime").unwrap().deleted);
+        assert_eq!(new_mem_table.get(b"Apple").unwrap().seq, 2);
+
+        let (committed, seq) = new_wal.commit_prepared("second", 30).unwrap();
+        assert_eq!(committed, batch);
+        assert_eq!(seq, 5);
 
         remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_read_wal_none() {
+    fn test_prepared_transactions_keep_segments() {
         let mut rng = rand::thread_rng();
         let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
         create_dir(&dir).unwrap();
 
-        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
-        assert_eq!(new_mem_table.len(), 0);
+        let options = WalOptions {
+            max_segment_size: 120,
+            ..WalOptions::default()
+        };
+        let mut batch = WriteBatch::new();
+        batch.set(b"Apple", b"Apple Smoothie");
+
+        let mut wal = Wal::with_options(&dir, options.clone()).unwrap();
+        wal.prepare("first", &batch).unwrap();
+        for _ in 0..4 {
+            wal.set(b"Lime", b"Lime Smoothie", 0).unwrap();
+        }
+        assert_eq!(wal.num_segments(), 3);
 
-        let m = metadata(new_wal.path).unwrap();
-        assert_eq!(m.len(), 0);
+        // The segment with the prepare record is kept until it is resolved
+        wal.mark_persisted(4).unwrap();
+        assert_eq!(wal.num_segments(), 3);
+        drop(wal);
+
+        let (mut new_wal, _) = Wal::load_from_dir_with_options(&dir, options).unwrap();
+        assert_eq!(new_wal.prepared_transactions(), vec!["first"]);
+        assert_eq!(new_wal.num_segments(), 4);
+        new_wal.mark_persisted(4).unwrap();
+        assert_eq!(new_wal.num_segments(), 4);
+
+        new_wal.rollback_prepared("first").unwrap();
+        new_wal.mark_persisted(4).unwrap();
+        assert_eq!(new_wal.num_segments(), 1);
 
         remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_read_wal_one() {
+    fn test_range_tombstones() {
         let mut rng = rand::thread_rng();
         let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
         create_dir(&dir).unwrap();
 
-        let entries: Vec<(&[u8], Option<&[u8]>)> = vec![
-            (b"Apple", Some(b"Apple Smoothie")),
-            (b"Lime", Some(b"Lime Smoothie")),
-            (b"Orange", Some(b"Orange Smoothie")),
-        ];
+        let mut batch = WriteBatch::new();
+        batch.delete_range(b"Apple", b"Orange");
+        batch.set(b"Lime", b"Lime Pie");
 
         let mut wal = Wal::new(&dir).unwrap();
-
-        for (i, e) in entries.iter().enumerate() {
-            wal.set(e.0, e.1.unwrap(), i as u128).unwrap();
-        }
+        wal.set(b"Apple", b"Apple Smoothie", 0).unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", 0).unwrap();
+        wal.set(b"Orange", b"Orange Smoothie", 0).unwrap();
+        assert_eq!(wal.write_batch(&batch, 10).unwrap(), 5);
         wal.flush().unwrap();
 
-        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
-
-        let file = OpenOptions::new().read(true).open(&new_wal.path).unwrap();
-        let mut reader = BufReader::new(file);
-
-        for (i, e) in entries.iter().enumerate() {
-            check_entry(&mut reader, e.0, e.1, i as u128, false);
+        let entries: Vec<WalEntry> = WalIterator::new(wal.path.clone()).unwrap().collect();
+        assert_eq!(entries[3].key, b"Apple");
+        assert_eq!(entries[3].range_end.as_deref(), Some(b"Orange".as_slice()));
+        assert!(entries[3].deleted);
+        assert_eq!(entries[3].to_op(), batch.iter().next().unwrap().clone());
 
-            let mem_e = new_mem_table.get(e.0).unwrap();
-            assert_eq!(mem_e.key, e.0);
-            assert_eq!(mem_e.value.as_ref().unwrap().as_slice(), e.1.unwrap());
-            assert_eq!(mem_e.timestamp, i as u128);
-        }
+        let (_, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert!(new_mem_table.get(b"Apple").is_none());
+        assert_eq!(new_mem_table.get(b"Lime").unwrap().seq, 5);
+        assert!(new_mem_table.get(b"Orange").is_some());
+        assert_eq!(new_mem_table.range_tombstones()[0].seq, 4);
 
         remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn test_read_wal_multiple() {
+    fn test_values_with_ttl() {
         let mut rng = rand::thread_rng();
         let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
         create_dir(&dir).unwrap();
 
-        let entries_1: Vec<(&[u8], Option<&[u8]>)> = vec![
-            (b"Apple", Some(b"Apple Smoothie")),
-            (b"Lime", Some(b"Lime Smoothie")),
-            (b"Orange", Some(b"Orange Smoothie")),
-        ];
-        let mut wal_1 = Wal::new(&dir).unwrap();
-        for (i, e) in entries_1.iter().enumerate() {
-            wal_1.set(e.0, e.1.unwrap(), i as u128).unwrap();
-        }
-        wal_1.flush().unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set_with_ttl(b"Apple", b"Apple Smoothie", Duration::from_micros(300));
 
-        let entries_2: Vec<(&[u8], Option<&[u8]>)> = vec![
-            (b"Strawberry", Some(b"Strawberry Smoothie")),
-            (b"Blueberry", Some(b"Blueberry Smoothie")),
-            (b"Orange", Some(b"Orange Milkshake")),
-        ];
-        let mut wal_2 = Wal::new(&dir).unwrap();
-        for (i, e) in entries_2.iter().enumerate() {
-            wal_2.set(e.0, e.1.unwrap(), (i + 3) as u128).unwrap();
-        }
-        wal_2.flush().unwrap();
+        let mut wal = Wal::new(&dir).unwrap();
+        wal.write_batch(&batch, 100).unwrap();
+        wal.set(b"Lime", b"Lime Smoothie", 200).unwrap();
+        wal.flush().unwrap();
 
-        let (new_wal, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        let entries: Vec<WalEntry> = WalIterator::new(wal.path.clone()).unwrap().collect();
+        assert_eq!(entries[0].ttl, Some(Duration::from_micros(300)));
+        assert_eq!(entries[0].to_op(), batch.iter().next().unwrap().clone());
+        assert_eq!(entries[1].ttl, None);
 
-        let file = OpenOptions::new().read(true).open(&new_wal.path).unwrap();
-        let mut reader = BufReader::new(file);
+        // The expiry is relative to the original write
+        let (_, new_mem_table) = Wal::load_from_dir(&dir).unwrap();
+        assert_eq!(new_mem_table.get(b"Apple").unwrap().expires_at, Some(400));
+        assert_eq!(new_mem_table.get(b"Lime").unwrap().expires_at, None);
 
-        for (i, e) in entries_1.iter().enumerate() {
-            check_entry(&mut reader, e.0, e.1, i as u128, false);
+        remove_dir_all(&dir).unwrap();
+    }
 
-            let mem_e = new_mem_table.get(e.0).unwrap();
-            if i != 2 {
-                assert_eq!(mem_e.key, e.0);
-                assert_eq!(mem_e.value.as_ref().unwrap().as_slice(), e.1.unwrap());
-                assert_eq!(mem_e.timestamp, i as u128);
-            } else {
-                assert_eq!(mem_e.key, e.0);
-                assert_ne!(mem_e.value.as_ref().unwrap().as_slice(), e.1.unwrap());
-                assert_ne!(mem_e.timestamp, i as u128);
-            }
-        }
-        for (i, e) in entries_2.iter().enumerate() {
-            check_entry(&mut reader, e.0, e.1, (i + 3) as u128, false);
+    #[test]
+    fn test_column_families() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        create_dir(&dir).unwrap();
 
-            let mem_e = new_mem_table.get(e.0).unwrap();
-            assert_eq!(mem_e.key, e.0);
-            assert_eq!(mem_e.value.as_ref().unwrap().as_slice(), e.1.unwrap());
-            assert_eq!(mem_e.timestamp, (i + 3) as u128);
-        }
+        let mut batch = WriteBatch::new();
+        batch.set(b"Apple", b"Apple Smoothie");
+        batch.push_cf(
+            300,
+            WriteOp::Set {
+                key: b"Apple".to_vec(),
+                value: b"Apple Pie".to_vec(),
+            },
+        );
+        batch.push_cf(
+            300,
+            WriteOp::Delete {
+                key: b"Lime".to_vec(),
+            },
+        );
+
+        let mut wal = Wal::new(&dir).unwrap();
+        wal.write_batch(&batch, 10).unwrap();
+        wal.prepare("first", &batch).unwrap();
+        wal.flush().unwrap();
+
+        let entries: Vec<WalEntry> = WalIterator::new(wal.path.clone()).unwrap().collect();
+        let families: Vec<ColumnFamilyId> = entries.iter().map(|e| e.column_family).collect();
+        assert_eq!(families, vec![DEFAULT_COLUMN_FAMILY, 300, 300]);
+        drop(wal);
+
+        let (mut new_wal, memtables) = Wal::load_from_dir_with_column_families(
+            &dir,
+            WalOptions::default(),
+            None,
+            Arc::new(BytewiseComparator),
+            0,
+        )
+        .unwrap();
+        assert_eq!(memtables.len(), 2);
+        assert_eq!(
+            memtables[&300].get(b"Apple").unwrap().value.as_deref(),
+            Some(b"Apple Pie".as_slice())
+        );
+        assert!(memtables[&300].get(b"Lime").unwrap().deleted);
+        assert_eq!(new_wal.commit_prepared("first", 20).unwrap().0, batch);
+        new_wal.flush().unwrap();
+        drop(new_wal);
+
+        // Operations already persisted in tables are not replayed
+        let (new_wal, memtables) = Wal::load_from_dir_with_column_families(
+            &dir,
+            WalOptions::default(),
+            None,
+            Arc::new(BytewiseComparator),
+            4,
+        )
+        .unwrap();
+        assert_eq!(memtables.keys().copied().collect::<Vec<_>>(), vec![300]);
+        assert_eq!(new_wal.last_sequence(), 6);
 
         remove_dir_all(&dir).unwrap();
     }
@@ -0,0 +1,492 @@
+//! Batches of write operations applied atomically to the database.
+//!
+//! A [`WriteBatchWithIndex`] also keeps its operations indexed by key, so the
+//! writes staged in it can be read back, on their own or merged with the data
+//! of the database, before the batch is written.
+
+#![allow(dead_code)]
+
+use std::{cmp::Ordering, fmt, iter::Peekable, sync::Arc, time::Duration};
+
+use crate::{
+    column_family::{ColumnFamily, ColumnFamilyId, DEFAULT_COLUMN_FAMILY},
+    comparator::{BytewiseComparator, Comparator},
+    db::{Db, DbIterator},
+    error::Result,
+};
+
+/// A write operation of a [`WriteBatch`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    /// Merge operand combined with the value of the key by the merge operator
+    Merge {
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    },
+    /// Sets the value of a key, which expires `ttl` after it is written
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    /// Deletes every key from `start` (inclusive) to `end` (exclusive)
+    DeleteRange {
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+}
+
+impl WriteOp {
+    /// Key the operation writes to, the start of the range for range deletes
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WriteOp::Set { key, .. } => key,
+            WriteOp::SetWithTtl { key, .. } => key,
+            WriteOp::Delete { key } => key,
+            WriteOp::Merge { key, .. } => key,
+            WriteOp::DeleteRange { start, .. } => start,
+        }
+    }
+}
+
+/// A set of write operations that are applied to the database atomically:
+/// either all of them are applied or none is
+///
+/// Operations are applied in the order they were added to the batch, and can
+/// write to different column families. The ones without a column family write
+/// to the default one
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<(ColumnFamilyId, WriteOp)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds setting a Key-Value pair to the batch
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.push(WriteOp::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        });
+    }
+
+    /// Adds deleting a Key-Value pair to the batch
+    pub fn delete(&mut self, key: &[u8]) {
+        self.push(WriteOp::Delete {
+            key: key.to_owned(),
+        });
+    }
+
+    /// Adds merging an operand into the value of a key to the batch
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.push(WriteOp::Merge {
+            key: key.to_owned(),
+            operand: operand.to_owned(),
+        });
+    }
+
+    /// Adds setting the value of a key that expires `ttl` after the batch is
+    /// written to the batch
+    pub fn set_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) {
+        self.push(WriteOp::SetWithTtl {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            ttl,
+        });
+    }
+
+    /// Adds deleting every key from `start` (inclusive) to `end` (exclusive)
+    /// to the batch
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        self.push(WriteOp::DeleteRange {
+            start: start.to_owned(),
+            end: end.to_owned(),
+        });
+    }
+
+    /// Adds setting a Key-Value pair in a column family to the batch
+    pub fn set_cf(&mut self, cf: &ColumnFamily, key: &[u8], value: &[u8]) {
+        self.push_cf(
+            cf.id(),
+            WriteOp::Set {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            },
+        );
+    }
+
+    /// Adds deleting a Key-Value pair in a column family to the batch
+    pub fn delete_cf(&mut self, cf: &ColumnFamily, key: &[u8]) {
+        self.push_cf(
+            cf.id(),
+            WriteOp::Delete {
+                key: key.to_owned(),
+            },
+        );
+    }
+
+    /// Adds merging an operand into the value of a key in a column family to
+    /// the batch
+    pub fn merge_cf(&mut self, cf: &ColumnFamily, key: &[u8], operand: &[u8]) {
+        self.push_cf(
+            cf.id(),
+            WriteOp::Merge {
+                key: key.to_owned(),
+                operand: operand.to_owned(),
+            },
+        );
+    }
+
+    /// Adds an operation to the batch
+    pub fn push(&mut self, op: WriteOp) {
+        self.push_cf(DEFAULT_COLUMN_FAMILY, op);
+    }
+
+    /// Adds an operation on a column family to the batch
+    pub fn push_cf(&mut self, cf: ColumnFamilyId, op: WriteOp) {
+        self.ops.push((cf, op));
+    }
+
+    /// Operations of the batch, in the order they were added
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &WriteOp> {
+        self.ops.iter().map(|(_, op)| op)
+    }
+
+    /// Operations of the batch with the column family they write to, in the
+    /// order they were added
+    pub fn iter_cf(&self) -> impl ExactSizeIterator<Item = (ColumnFamilyId, &WriteOp)> {
+        self.ops.iter().map(|(cf, op)| (*cf, op))
+    }
+
+    /// Number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Removes all the operations from the batch
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
+
+/// A [`WriteBatch`] indexed by key, to read back the writes staged in it
+///
+/// Only sets and deletes can be staged. Reading merges back would need the
+/// merge operator of the database, range deletes can't be indexed by key and
+/// the expiry of values with a TTL is only known once the batch is written
+#[derive(Clone)]
+pub struct WriteBatchWithIndex {
+    batch: WriteBatch,
+    /// Position in the batch of the last operation of every key, sorted by
+    /// key with the comparator
+    index: Vec<(Vec<u8>, usize)>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl fmt::Debug for WriteBatchWithIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteBatchWithIndex")
+            .field("batch", &self.batch)
+            .field("index", &self.index)
+            .field("comparator", &self.comparator.name())
+            .finish()
+    }
+}
+
+impl Default for WriteBatchWithIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WriteBatchWithIndex {
+    /// Creates a batch indexing keys bytewise
+    pub fn new() -> Self {
+        Self::with_comparator(Arc::new(BytewiseComparator))
+    }
+
+    /// Creates a batch indexing keys with the given comparator, which should
+    /// be the one of the database the batch is read with
+    pub fn with_comparator(comparator: Arc<dyn Comparator>) -> Self {
+        Self {
+            batch: WriteBatch::default(),
+            index: Vec::new(),
+            comparator,
+        }
+    }
+
+    /// Adds setting a Key-Value pair to the batch
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.index_key(key);
+        self.batch.set(key, value);
+    }
+
+    /// Adds deleting a Key-Value pair to the batch
+    pub fn delete(&mut self, key: &[u8]) {
+        self.index_key(key);
+        self.batch.delete(key);
+    }
+
+    /// Points the key at the operation about to be added to the batch
+    fn index_key(&mut self, key: &[u8]) {
+        let idx = self.batch.len();
+        match self.search(key) {
+            Ok(pos) => self.index[pos].1 = idx,
+            Err(pos) => self.index.insert(pos, (key.to_owned(), idx)),
+        }
+    }
+
+    /// Binary searches the index for a key with the comparator
+    fn search(&self, key: &[u8]) -> std::result::Result<usize, usize> {
+        self.index
+            .binary_search_by(|(k, _)| self.comparator.compare(k, key))
+    }
+
+    /// Gets the value a key has in the batch
+    ///
+    /// Returns `None` if the batch doesn't write the key, and `Some(None)` if
+    /// it deletes it
+    pub fn get_from_batch(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        let pos = self.search(key).ok()?;
+        Some(op_value(&self.batch.ops[self.index[pos].1].1))
+    }
+
+    /// Gets the value a key would have in the database if the batch was
+    /// written to it
+    pub fn get_from_batch_and_db(&self, db: &Db, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.get_from_batch(key) {
+            Some(value) => Ok(value.map(|v| v.to_owned())),
+            None => db.get(key),
+        }
+    }
+
+    /// Returns an iterator over the last operation of every key in the batch,
+    /// ordered by key with the comparator of the batch, as Key-Value pairs
+    /// where Tombstones have no value
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.index
+            .iter()
+            .map(|(key, idx)| (key.as_slice(), op_value(&self.batch.ops[*idx].1)))
+    }
+
+    /// Returns an iterator over the Key-Value pairs the database would have if
+    /// the batch was written to it, ordered by key with the comparator of the
+    /// database
+    pub fn iter_with_db(&self, db: &Db) -> Result<BatchWithDbIterator<'_>> {
+        let comparator = db.comparator();
+        let mut index: Vec<(&[u8], usize)> = self
+            .index
+            .iter()
+            .map(|(key, idx)| (key.as_slice(), *idx))
+            .collect();
+        index.sort_by(|(a, _), (b, _)| comparator.compare(a, b));
+        Ok(BatchWithDbIterator {
+            batch: self.batch.ops.as_slice(),
+            index: index.into_iter().peekable(),
+            db: db.iter()?.peekable(),
+            comparator,
+        })
+    }
+
+    /// The batch of operations, to write it to the database
+    pub fn batch(&self) -> &WriteBatch {
+        &self.batch
+    }
+
+    /// Number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.batch.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+
+    /// Removes all the operations from the batch
+    pub fn clear(&mut self) {
+        self.batch.clear();
+        self.index.clear();
+    }
+}
+
+/// Value an operation sets, `None` for deletes
+fn op_value(op: &WriteOp) -> Option<&[u8]> {
+    match op {
+        WriteOp::Set { value, .. } => Some(value),
+        WriteOp::Delete { .. } => None,
+        WriteOp::Merge { .. } | WriteOp::SetWithTtl { .. } | WriteOp::DeleteRange { .. } => {
+            unreachable!("only sets and deletes are indexed")
+        }
+    }
+}
+
+/// An iterator over the data of a database merged with the writes of a batch
+///
+/// Created by [`WriteBatchWithIndex::iter_with_db`]
+pub struct BatchWithDbIterator<'a> {
+    batch: &'a [(ColumnFamilyId, WriteOp)],
+    /// Keys of the batch with the position of their last operation, in the
+    /// order of the database
+    index: Peekable<std::vec::IntoIter<(&'a [u8], usize)>>,
+    db: Peekable<DbIterator>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl Iterator for BatchWithDbIterator<'_> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let order = match (self.index.peek(), self.db.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((batch_key, _)), Some((db_key, _))) => {
+                    self.comparator.compare(batch_key, db_key)
+                }
+            };
+
+            match order {
+                Ordering::Greater => return self.db.next(),
+                // The batch overrides the value of the database
+                Ordering::Equal => {
+                    self.db.next();
+                }
+                Ordering::Less => {}
+            }
+            let (key, idx) = self.index.next()?;
+            if let Some(value) = op_value(&self.batch[idx].1) {
+                return Some((key.to_owned(), value.to_owned()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::{fs::remove_dir_all, path::PathBuf};
+
+    #[test]
+    fn test_write_batch_keeps_order() {
+        let mut batch = WriteBatch::new();
+        assert!(batch.is_empty());
+
+        batch.set(b"Lime", b"Lime Smoothie");
+        batch.delete(b"Apple");
+        batch.set(b"Lime", b"Lime Milkshake");
+
+        assert_eq!(batch.len(), 3);
+        let keys: Vec<&[u8]> = batch.iter().map(|op| op.key()).collect();
+        assert_eq!(keys, vec![b"Lime".as_slice(), b"Apple", b"Lime"]);
+        assert_eq!(
+            batch.iter().last().unwrap(),
+            &WriteOp::Set {
+                key: b"Lime".to_vec(),
+                value: b"Lime Milkshake".to_vec()
+            }
+        );
+
+        batch.clear();
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn test_write_batch_with_index() {
+        let mut rng = rand::thread_rng();
+        let dir = PathBuf::from(format!("./{}/", rng.gen::<u32>()));
+        let db = Db::open(&dir).unwrap();
+        db.set(b"Apple", b"Apple Smoothie").unwrap();
+        db.set(b"Banana", b"Banana Smoothie").unwrap();
+        db.set(b"Orange", b"Orange Smoothie").unwrap();
+
+        let mut batch = WriteBatchWithIndex::new();
+        batch.set(b"Lime", b"Lime Smoothie");
+        batch.set(b"Apple", b"Apple Milkshake");
+        batch.delete(b"Banana");
+        batch.delete(b"Lime");
+        batch.set(b"Lime", b"Lime Milkshake");
+        batch.delete(b"Kiwi");
+
+        assert_eq!(batch.len(), 6);
+        assert_eq!(
+            batch.get_from_batch(b"Lime"),
+            Some(Some(b"Lime Milkshake".as_slice()))
+        );
+        assert_eq!(batch.get_from_batch(b"Banana"), Some(None));
+        assert_eq!(batch.get_from_batch(b"Orange"), None);
+        assert_eq!(
+            batch
+                .get_from_batch_and_db(&db, b"Orange")
+                .unwrap()
+                .unwrap(),
+            b"Orange Smoothie"
+        );
+        assert_eq!(batch.get_from_batch_and_db(&db, b"Banana").unwrap(), None);
+
+        let keys: Vec<&[u8]> = batch.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![b"Apple".as_slice(), b"Banana", b"Kiwi", b"Lime"]);
+
+        let merged: Vec<_> = batch.iter_with_db(&db).unwrap().collect();
+        assert_eq!(
+            merged,
+            vec![
+                (b"Apple".to_vec(), b"Apple Milkshake".to_vec()),
+                (b"Lime".to_vec(), b"Lime Milkshake".to_vec()),
+                (b"Orange".to_vec(), b"Orange Smoothie".to_vec()),
+            ]
+        );
+
+        // Nothing is written until the batch is
+        assert_eq!(db.get(b"Lime").unwrap(), None);
+        db.write(batch.batch()).unwrap();
+        assert_eq!(db.iter().unwrap().collect::<Vec<_>>(), merged);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    /// Orders keys ignoring ASCII case, so "lime" and "LIME" are the same key
+    struct CaseInsensitiveComparator;
+
+    impl Comparator for CaseInsensitiveComparator {
+        fn name(&self) -> &str {
+            "test.CaseInsensitiveComparator"
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }
+    }
+
+    #[test]
+    fn test_write_batch_with_index_comparator() {
+        let mut batch = WriteBatchWithIndex::with_comparator(Arc::new(CaseInsensitiveComparator));
+        batch.set(b"lime", b"Lime Smoothie");
+        batch.set(b"Banana", b"Banana Smoothie");
+        batch.set(b"LIME", b"Lime Milkshake");
+        batch.delete(b"apple");
+
+        assert_eq!(
+            batch.get_from_batch(b"Lime"),
+            Some(Some(b"Lime Milkshake".as_slice()))
+        );
+        assert_eq!(batch.get_from_batch(b"APPLE"), Some(None));
+        let keys: Vec<&[u8]> = batch.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![b"apple".as_slice(), b"Banana", b"lime"]);
+    }
+}